@@ -0,0 +1,126 @@
+#!/usr/bin/env python3
+# Writes the seed corpus in fuzz/seeds/ from the capture in sample/sample.out
+# and the KEXINIT test vector in src/protocol/key_exchange_init.rs.
+#
+#   python3 fuzz/seeds.py
+import ast
+import hashlib
+import os
+import re
+import struct
+import zlib
+
+ROOT = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..")
+SEEDS = os.path.join(ROOT, "fuzz", "seeds")
+
+SSH_MSG_KEXINIT = 20
+SSH_MSG_KEX_ECDH_REPLY = 31
+
+
+def streams(path):
+    """TCP payloads of a pcap file, reassembled per direction."""
+    data = open(path, "rb").read()
+    (link,) = struct.unpack("<I", data[20:24])
+    assert data[:4] == b"\xd4\xc3\xb2\xa1" and link == 1, "expected a little-endian Ethernet pcap"
+    segments = {}
+    offset = 24
+    while offset < len(data):
+        caplen = struct.unpack("<I", data[offset + 8 : offset + 12])[0]
+        ip = data[offset + 16 + 14 : offset + 16 + caplen]
+        offset += 16 + caplen
+        ihl = (ip[0] & 0x0F) * 4
+        total = struct.unpack(">H", ip[2:4])[0]
+        tcp = ip[ihl:total]
+        sport, dport, seq = struct.unpack(">HHI", tcp[:8])
+        payload = tcp[(tcp[12] >> 4) * 4 :]
+        if payload:
+            segments.setdefault((sport, dport), {})[seq] = payload
+    return {
+        ports: b"".join(payload for _, payload in sorted(by_seq.items()))
+        for ports, by_seq in segments.items()
+    }
+
+
+def split(stream):
+    """The identification line and the unencrypted packet payloads after it."""
+    end = stream.index(b"\r\n") + 2
+    version, rest = stream[:end], stream[end:]
+    payloads = []
+    while len(rest) >= 5:
+        packet_length, padding_length = struct.unpack(">IB", rest[:5])
+        payload = rest[5 : 4 + packet_length - padding_length]
+        payloads.append(payload)
+        rest = rest[4 + packet_length :]
+        # SSH_MSG_NEWKEYS, everything after it is encrypted
+        if payload[:1] == b"\x15":
+            break
+    return version, payloads
+
+
+def strings(data, count):
+    values = []
+    for _ in range(count):
+        (length,) = struct.unpack(">I", data[:4])
+        values.append(data[4 : 4 + length])
+        data = data[4 + length :]
+    return values
+
+
+def string(value):
+    return struct.pack(">I", len(value)) + value
+
+
+def kexinit_test_vector():
+    source = open(os.path.join(ROOT, "src", "protocol", "key_exchange_init.rs")).read()
+    literal = re.search(r'SERVER_KEXINIT_PAYLOAD: &\[u8\] = (b".*?");', source, re.S).group(1)
+    return ast.literal_eval(literal)
+
+
+def write(target, seed):
+    directory = os.path.join(SEEDS, target)
+    os.makedirs(directory, exist_ok=True)
+    name = hashlib.sha1(seed).hexdigest()
+    with open(os.path.join(directory, name), "wb") as f:
+        f.write(seed)
+
+
+def main():
+    for (sport, _), stream in streams(os.path.join(ROOT, "sample", "sample.out")).items():
+        version, payloads = split(stream)
+        write("version", version)
+        write("version", b"a line before the identification\r\n" + version)
+        if sport == 22:
+            # everything the server sent, up to where it becomes encrypted
+            write("recv", stream)
+        for payload in payloads:
+            if payload[0] == SSH_MSG_KEXINIT:
+                write("kexinit", payload)
+            if payload[0] == SSH_MSG_KEX_ECDH_REPLY:
+                # string K_S, string Q_S, string signature of H
+                host_key, _, signature = strings(payload[1:], 3)
+                write("public_key", string(host_key) + string(signature) + bytes(32))
+    write("kexinit", kexinit_test_vector())
+
+    # the capture is encrypted past the key exchange, these follow RFC 4254
+    channel_seeds = [
+        b"\x50" + string(b"hostkeys-00@openssh.com") + b"\x00" + string(b"key"),
+        b"\x5b" + struct.pack(">IIII", 0, 1, 0x200000, 0x8000),
+        b"\x5d" + struct.pack(">II", 1, 0x10000),
+        b"\x5e" + struct.pack(">I", 1) + string(b"total 0\n"),
+        b"\x5f" + struct.pack(">II", 1, 1) + string(b"ls: cannot access\n"),
+        b"\x60" + struct.pack(">I", 1),
+        b"\x61" + struct.pack(">I", 1),
+        b"\x62" + struct.pack(">I", 1) + string(b"exit-status") + b"\x00" + struct.pack(">I", 0),
+        b"\x62" + struct.pack(">I", 1) + string(b"exit-signal") + b"\x00" + string(b"TERM") + b"\x00" + string(b"") + string(b""),
+    ]
+    for seed in channel_seeds:
+        write("channel", seed)
+
+    # the same messages compressed as a sender does, one partial flush per packet
+    compress = zlib.compressobj()
+    packets = [compress.compress(seed) + compress.flush(zlib.Z_PARTIAL_FLUSH) for seed in channel_seeds]
+    write("decompress", b"".join(string(packet) for packet in packets))
+
+
+if __name__ == "__main__":
+    main()
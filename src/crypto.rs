@@ -4,16 +4,18 @@ pub mod key_exchange;
 pub mod mac;
 pub mod public_key;
 
-use self::compression::CompressAdapter;
-use self::encryption::EncryptionAdapter;
+use self::compression::{none::NoneCompress, zlib::Zlib, CompressAdapter};
+use self::encryption::aes_ctr::{Aes128Ctr, Aes192Ctr, Aes256Ctr, AesCtr};
+use self::encryption::aes_gcm::AesGcm;
+use self::encryption::{chachapoly::ChaCha20Poly1305, EncryptionAdapter};
 use self::key_exchange::KexMethodAdapter;
+use self::mac::etm::Etm;
+use self::mac::hmac::{HmacSha1, HmacSha2_256, HmacSha2_512};
 use self::mac::MACAdapter;
 use self::public_key::PublicKeyAdapter;
-use crate::protocol::data::{ByteString, DataType, NameList};
-use nom::{
-    error::{Error, ErrorKind, ParseError},
-    Err, IResult,
-};
+use crate::protocol::error::SshError;
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use rsa::signature::Verifier;
 use strum_macros::{AsRefStr, Display, EnumString};
 
 pub struct CryptoAdapter {
@@ -25,12 +27,22 @@ pub struct CryptoAdapter {
 }
 
 /// key exchange algorithm
-#[derive(Copy, Clone, PartialEq, Eq, AsRefStr, EnumString, Display)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, AsRefStr, EnumString, Display)]
 pub enum Kex {
+    #[strum(serialize = "mlkem768x25519-sha256")]
+    MlKem768X25519Sha256,
+    #[strum(serialize = "sntrup761x25519-sha512@openssh.com")]
+    Sntrup761X25519Sha512,
     #[strum(serialize = "curve25519-sha256")]
     Curve25519Sha256,
+    #[strum(serialize = "curve448-sha512")]
+    Curve448Sha512,
     #[strum(serialize = "ecdh-sha2-nistp256")]
-    EcdhSha2Nistrp256,
+    EcdhSha2Nistp256,
+    #[strum(serialize = "ecdh-sha2-nistp384")]
+    EcdhSha2Nistp384,
+    #[strum(serialize = "ecdh-sha2-nistp521")]
+    EcdhSha2Nistp521,
     #[cfg(feature = "deprecated-dh-group1-sha1")]
     #[strum(serialize = "diffie-hellman-group1-sha1")]
     DiffieHellmanGroup1Sha1,
@@ -38,10 +50,16 @@ pub enum Kex {
     DiffieHellmanGroup14Sha1,
     #[strum(serialize = "diffie-hellman-group14-sha256")]
     DiffieHellmanGroup14Sha256,
+    #[strum(serialize = "diffie-hellman-group16-sha512")]
+    DiffieHellmanGroup16Sha512,
+    #[strum(serialize = "diffie-hellman-group18-sha512")]
+    DiffieHellmanGroup18Sha512,
+    #[strum(serialize = "diffie-hellman-group-exchange-sha256")]
+    DiffieHellmanGroupExchangeSha256,
 }
 
 /// pubkey hash algorithm
-#[derive(Copy, Clone, PartialEq, Eq, AsRefStr, EnumString, Display)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, AsRefStr, EnumString, Display)]
 pub enum PubKey {
     #[strum(serialize = "ssh-ed25519")]
     SshEd25519,
@@ -58,7 +76,7 @@ pub enum PubKey {
 }
 
 /// symmetrical encryption algorithm
-#[derive(Copy, Clone, PartialEq, Eq, AsRefStr, EnumString, Display)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, AsRefStr, EnumString, Display)]
 pub enum Enc {
     #[strum(serialize = "chacha20-poly1305@openssh.com")]
     Chacha20Poly1305Openssh,
@@ -68,6 +86,10 @@ pub enum Enc {
     Aes192Ctr,
     #[strum(serialize = "aes256-ctr")]
     Aes256Ctr,
+    #[strum(serialize = "aes128-gcm@openssh.com")]
+    Aes128GcmOpenssh,
+    #[strum(serialize = "aes256-gcm@openssh.com")]
+    Aes256GcmOpenssh,
     #[cfg(feature = "deprecated-aes-cbc")]
     #[strum(serialize = "aes128-cbc")]
     Aes128Cbc,
@@ -83,7 +105,7 @@ pub enum Enc {
 }
 
 /// MAC(message authentication code) algorithm
-#[derive(Copy, Clone, PartialEq, Eq, AsRefStr, EnumString, Display)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, AsRefStr, EnumString, Display)]
 pub enum Mac {
     #[strum(serialize = "hmac-sha1")]
     HmacSha1,
@@ -91,16 +113,194 @@ pub enum Mac {
     HmacSha2_256,
     #[strum(serialize = "hmac-sha2-512")]
     HmacSha2_512,
+    #[strum(serialize = "hmac-sha1-etm@openssh.com")]
+    HmacSha1Etm,
+    #[strum(serialize = "hmac-sha2-256-etm@openssh.com")]
+    HmacSha2_256Etm,
+    #[strum(serialize = "hmac-sha2-512-etm@openssh.com")]
+    HmacSha2_512Etm,
 }
 
 /// compression algorithm
-#[derive(Copy, Clone, PartialEq, Eq, AsRefStr, EnumString, Display)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, AsRefStr, EnumString, Display)]
 pub enum Compress {
     #[strum(serialize = "none")]
     None,
-    #[cfg(feature = "deprecated-zlib")]
     #[strum(serialize = "zlib")]
     Zlib,
     #[strum(serialize = "zlib@openssh.com")]
     ZlibOpenSsh,
 }
+
+impl Enc {
+    // Authenticated ciphers carry their own tag, so no MAC algorithm is negotiated for them.
+    pub fn is_aead(&self) -> bool {
+        matches!(
+            self,
+            Enc::Chacha20Poly1305Openssh | Enc::Aes128GcmOpenssh | Enc::Aes256GcmOpenssh
+        )
+    }
+
+    pub fn new_adapter(&self, key: &[u8], iv: &[u8], etm: bool) -> Box<dyn EncryptionAdapter> {
+        match self {
+            Enc::Chacha20Poly1305Openssh => Box::new(ChaCha20Poly1305::new(key)),
+            Enc::Aes128Ctr => Box::new(AesCtr::<Aes128Ctr>::new(key, iv, etm)),
+            Enc::Aes192Ctr => Box::new(AesCtr::<Aes192Ctr>::new(key, iv, etm)),
+            Enc::Aes256Ctr => Box::new(AesCtr::<Aes256Ctr>::new(key, iv, etm)),
+            Enc::Aes128GcmOpenssh => Box::new(AesGcm::<Aes128Gcm>::new(key, iv)),
+            Enc::Aes256GcmOpenssh => Box::new(AesGcm::<Aes256Gcm>::new(key, iv)),
+        }
+    }
+}
+
+impl Mac {
+    pub fn key_size(&self) -> usize {
+        match self {
+            Mac::HmacSha1 | Mac::HmacSha1Etm => 20,
+            Mac::HmacSha2_256 | Mac::HmacSha2_256Etm => 32,
+            Mac::HmacSha2_512 | Mac::HmacSha2_512Etm => 64,
+        }
+    }
+
+    pub fn is_etm(&self) -> bool {
+        matches!(
+            self,
+            Mac::HmacSha1Etm | Mac::HmacSha2_256Etm | Mac::HmacSha2_512Etm
+        )
+    }
+
+    pub fn new_adapter(&self, key: &[u8]) -> Box<dyn MACAdapter> {
+        let key = key[..self.key_size()].to_vec();
+        match self {
+            Mac::HmacSha1 => Box::new(HmacSha1::new(key)),
+            Mac::HmacSha2_256 => Box::new(HmacSha2_256::new(key)),
+            Mac::HmacSha2_512 => Box::new(HmacSha2_512::new(key)),
+            Mac::HmacSha1Etm => Box::new(Etm::<HmacSha1>::new(key)),
+            Mac::HmacSha2_256Etm => Box::new(Etm::<HmacSha2_256>::new(key)),
+            Mac::HmacSha2_512Etm => Box::new(Etm::<HmacSha2_512>::new(key)),
+        }
+    }
+}
+
+impl Compress {
+    pub fn new_adapter(&self) -> Box<dyn CompressAdapter> {
+        match self {
+            Compress::None => Box::new(NoneCompress {}),
+            Compress::Zlib => Box::new(Zlib::new(false)),
+            Compress::ZlibOpenSsh => Box::new(Zlib::new(true)),
+        }
+    }
+}
+
+impl PubKey {
+    // string    signature format identifier
+    // byte[n]   signature blob in format specific encoding.
+    pub fn verify(
+        &self,
+        host_key: &[u8],
+        signature: &[u8],
+        message: &[u8],
+    ) -> Result<(), SshError> {
+        let host_key =
+            ssh_key::PublicKey::from_bytes(host_key).map_err(|_| SshError::SignatureInvalid)?;
+        let signature =
+            ssh_key::Signature::try_from(signature).map_err(|_| SshError::SignatureInvalid)?;
+        if signature.algorithm().as_str() != self.as_ref() {
+            return Err(SshError::SignatureInvalid);
+        }
+        host_key
+            .key_data()
+            .verify(message, &signature)
+            .map_err(|_| SshError::SignatureInvalid)
+    }
+}
+
+#[test]
+fn verify_ed25519_signature() {
+    use rsa::signature::Signer;
+
+    let private_key =
+        ssh_key::PrivateKey::random(&mut rand_core::OsRng, ssh_key::Algorithm::Ed25519).unwrap();
+    let host_key = private_key.public_key().to_bytes().unwrap();
+    let message = b"exchange hash";
+    let signature: ssh_key::Signature = private_key.key_data().sign(message);
+    let signature: Vec<u8> = signature.try_into().unwrap();
+
+    assert!(PubKey::SshEd25519
+        .verify(&host_key, &signature, message)
+        .is_ok());
+    assert!(matches!(
+        PubKey::SshEd25519.verify(&host_key, &signature, b"tampered hash"),
+        Err(SshError::SignatureInvalid)
+    ));
+    assert!(matches!(
+        PubKey::RsaSha2_256.verify(&host_key, &signature, message),
+        Err(SshError::SignatureInvalid)
+    ));
+}
+
+#[test]
+fn verify_rsa_signature() {
+    use crate::protocol::data::{ByteString, Data};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    // ssh-keygen -t rsa -b 2048, signed with openssl dgst -sha256/-sha512 -sign
+    let host_key = STANDARD
+        .decode(
+            "AAAAB3NzaC1yc2EAAAADAQABAAABAQC9VELqVQ2w3IReZTAUVBiU3qVO3aHWbreU6BdkmO4C+fo5lRor/pZo\
+             Dr70Q2NUo9/3ep0SlZGJ8yVQtPFKIvubCI4G0GHo7eY+RcdKHF8YKdB0Xg+OcPwazb3AyXFOQeQZrHjkzoWn\
+             fI5uBUfuLAB7vKXa93DegdPpaTaw3N/YGmN4We6KUxwLZH2o9zZbTSM/b/zjAbqQJuIczAZb6cDYG+Cujxhq\
+             zIIWQzEG+X1o9VKVCoILvYl0sdPPwsBLo81DYGNHVazeusN9O3quJFFneHu2aCT8cchGOr36V1POqaC+vAhK\
+             m8sb8czI53nAsFqajyHECVDHoK7I2aJHkY/X",
+        )
+        .unwrap();
+    let sha256 = STANDARD
+        .decode(
+            "JZ3Ih9MKTv3ZEjeP52+GO83oWcJdvX1AcVkG/WZU1f1kmjTMlLRJ6MHTF3Y8/9sHEnHjP8wm41iEdQ11bGeH\
+             53c8Suda1MDupN19yepx59yNLwnrBldoY43luBX5a4o9m7NrvjsxoRnq9aLM/LVCxqR2teYOwIFFSNhirmpr\
+             ro97HH+p6OaiNZh0envMgczOeVUAbTGz9QpqKKMQf5eyY3+xfuDe/33lkb8cfA0qpmP9pWYEz2xf7c/mqhrG\
+             kSeS2SVLENraQd1qsxtNppqu3O2JLiHo21MJ9CpWBMFxq3RQdgacHvgETjWsfTSOe7z5STAkQ3f7Zq290nOu\
+             ztVkLA==",
+        )
+        .unwrap();
+    let sha512 = STANDARD
+        .decode(
+            "f8Y9+N08PEAT1vxmBx5jyAZUp14OzUMMIIurY8B4igsaPrxSEbONHsjYaAHauW7bqIooEAXotEjERM+4JlUp\
+             nKwJ5pcPauXpTRblgkIw84KCoKuDWgm/cnPN3NQAO0DHtBlbhKT3v0CjJajkiq7EJ1xIT4tvoItAoRoH3gUk\
+             XsgH7WnjDevczqnnIE3TtQxHg5LxRHRwnvgSW1yuzQqe3T76qSQM6xLNUy7ICv5shoEE5LjxLU1/mENJn+RL\
+             0z/7PgEl+CC76D/Nc+4KbT03K4KptsURoacoyfsx+RSDjCOpXWficCOyJJR9f6sCabRp51/6PPZ3Wz/uoOOw\
+             hDoVVQ==",
+        )
+        .unwrap();
+    let message = b"exchange hash";
+    let blob = |algorithm: &str, signature: &[u8]| {
+        let mut blob = Data::new();
+        blob.put(&algorithm.to_string())
+            .put(&ByteString(signature.to_vec()));
+        blob.into_inner()
+    };
+
+    assert!(PubKey::RsaSha2_256
+        .verify(&host_key, &blob("rsa-sha2-256", &sha256), message)
+        .is_ok());
+    assert!(PubKey::RsaSha2_512
+        .verify(&host_key, &blob("rsa-sha2-512", &sha512), message)
+        .is_ok());
+    // a SHA-512 signature labelled rsa-sha2-256
+    assert!(matches!(
+        PubKey::RsaSha2_256.verify(&host_key, &blob("rsa-sha2-256", &sha512), message),
+        Err(SshError::SignatureInvalid)
+    ));
+    assert!(matches!(
+        PubKey::RsaSha2_512.verify(&host_key, &blob("rsa-sha2-256", &sha256), message),
+        Err(SshError::SignatureInvalid)
+    ));
+    assert!(matches!(
+        PubKey::RsaSha2_256.verify(&host_key, &blob("rsa-sha2-256", &sha256[1..]), message),
+        Err(SshError::SignatureInvalid)
+    ));
+    assert!(matches!(
+        PubKey::RsaSha2_512.verify(&host_key, &blob("rsa-sha2-512", &sha512), b"tampered hash"),
+        Err(SshError::SignatureInvalid)
+    ));
+}
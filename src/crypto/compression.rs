@@ -1,18 +1,16 @@
 pub mod none;
 pub mod zlib;
 
-use strum_macros::{AsRefStr, EnumString};
+use crate::protocol::error::Result;
 
 // none     REQUIRED        no compression
 // zlib     OPTIONAL        ZLIB (LZ77) compression
+// zlib@openssh.com          ZLIB, delayed until the user is authenticated
 pub trait CompressAdapter {
-    fn compress(&self, msg: Vec<u8>) -> anyhow::Result<Vec<u8>>;
-    fn decompress(&self, msg: Vec<u8>) -> anyhow::Result<Vec<u8>>;
-}
-
-/// compression algorithm
-#[derive(Copy, Clone, PartialEq, Eq, AsRefStr, EnumString)]
-pub enum Compress {
-    #[strum(serialize = "none")]
-    None,
+    fn compress(&mut self, msg: Vec<u8>) -> Result<Vec<u8>>;
+    fn decompress(&mut self, msg: Vec<u8>) -> Result<Vec<u8>>;
+    // delayed compression only starts after user authentication
+    fn is_delayed(&self) -> bool {
+        false
+    }
 }
@@ -1,11 +1,12 @@
 use super::CompressAdapter;
+use crate::protocol::error::Result;
 
 pub struct NoneCompress {}
 impl CompressAdapter for NoneCompress {
-    fn compress(&self, msg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    fn compress(&mut self, msg: Vec<u8>) -> Result<Vec<u8>> {
         Ok(msg)
     }
-    fn decompress(&self, msg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    fn decompress(&mut self, msg: Vec<u8>) -> Result<Vec<u8>> {
         Ok(msg)
     }
 }
@@ -1,22 +1,118 @@
 use super::CompressAdapter;
-use flate2::read::ZlibDecoder;
-use flate2::write::ZlibEncoder;
-use flate2::Compression;
-use std::io::prelude::*;
+use crate::protocol::error::{Result, SshError};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+
+// Same bound as on packet_length, a small packet must not inflate without limit.
+const MAX_DECOMPRESSED_LENGTH: usize = 256 * 1024;
+
+// [RFC4253 § 6.2](https://datatracker.ietf.org/doc/html/rfc4253#section-6.2)
+// The compression context is initialized after each key exchange and carried
+// over from one packet to the next, every packet ending on a partial flush.
+//
+// zlib@openssh.com is the same, except that it only starts once the user is
+// authenticated (after SSH_MSG_USERAUTH_SUCCESS).
+pub struct Zlib {
+    compress: Compress,
+    decompress: Decompress,
+    delayed: bool,
+}
+
+impl Zlib {
+    pub fn new(delayed: bool) -> Self {
+        Zlib {
+            compress: Compress::new(Compression::default(), true),
+            decompress: Decompress::new(true),
+            delayed,
+        }
+    }
+}
 
-pub struct Zlib {}
 impl CompressAdapter for Zlib {
-    fn compress(&self, msg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(msg.as_slice())?;
-        let compressed = encoder.finish()?;
-        Ok(compressed)
+    fn compress(&mut self, msg: Vec<u8>) -> Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(msg.len() + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&msg[consumed..], &mut output, FlushCompress::Partial)
+                .map_err(|e| SshError::Protocol(e.to_string()))?;
+            // the flush is complete once all input is consumed and there is room left
+            if self.compress.total_in() - start == msg.len() as u64
+                && output.len() < output.capacity()
+            {
+                return Ok(output);
+            }
+            output.reserve(output.capacity());
+        }
+    }
+
+    fn decompress(&mut self, msg: Vec<u8>) -> Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(msg.len() * 4);
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let produced = output.len();
+            self.decompress
+                .decompress_vec(&msg[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|e| SshError::InvalidFormat(e.to_string()))?;
+            if self.decompress.total_in() - start == msg.len() as u64
+                && output.len() < output.capacity()
+            {
+                return Ok(output);
+            }
+            // input left over that zlib does not take, such as after the end of the stream
+            if self.decompress.total_in() - start == consumed as u64
+                && output.len() == produced
+                && output.len() < output.capacity()
+            {
+                return Err(SshError::InvalidFormat(
+                    "trailing data after zlib stream".to_string(),
+                ));
+            }
+            if output.len() >= MAX_DECOMPRESSED_LENGTH {
+                return Err(SshError::InvalidFormat(format!(
+                    "decompressed payload larger than {} bytes",
+                    MAX_DECOMPRESSED_LENGTH
+                )));
+            }
+            output.reserve(output.capacity().max(1024));
+        }
     }
 
-    fn decompress(&self, msg: Vec<u8>) -> anyhow::Result<Vec<u8>> {
-        let mut decoder = ZlibDecoder::new(msg.as_slice());
-        let mut result = vec![];
-        decoder.read_exact(&mut result)?;
-        Ok(result)
+    fn is_delayed(&self) -> bool {
+        self.delayed
     }
 }
+
+#[test]
+fn zlib_stream_spans_packets() {
+    let mut client = Zlib::new(false);
+    let mut server = Zlib::new(false);
+
+    let messages = [
+        b"SSH_MSG_CHANNEL_DATA hello hello hello hello".to_vec(),
+        vec![],
+        b"SSH_MSG_CHANNEL_DATA hello hello hello hello".to_vec(),
+        (0..100_000).map(|i| (i % 251) as u8).collect(),
+    ];
+    let mut compressed = Vec::new();
+    for message in &messages {
+        compressed.push(client.compress(message.clone()).unwrap());
+    }
+    // the repeated message refers back to the first one
+    assert!(compressed[2].len() < compressed[0].len());
+
+    for (message, compressed) in messages.iter().zip(compressed) {
+        assert_eq!(&server.decompress(compressed).unwrap(), message);
+    }
+
+    // a finished stream with more bytes after it, and a zlib bomb
+    let mut finished = flate2::write::ZlibEncoder::new(Vec::new(), Compression::default());
+    std::io::Write::write_all(&mut finished, b"hello").unwrap();
+    let mut finished = finished.finish().unwrap();
+    finished.extend(b"trailing");
+    assert!(Zlib::new(false).decompress(finished).is_err());
+    let bomb = Zlib::new(false).compress(vec![0; 1 << 20]).unwrap();
+    assert!(Zlib::new(false).decompress(bomb).is_err());
+    assert!(Zlib::new(false).decompress(Vec::new()).unwrap().is_empty());
+}
@@ -3,8 +3,7 @@ pub mod aes_gcm;
 pub mod chachapoly;
 pub mod none;
 
-use crate::protocol::data::Data;
-use strum_macros::{AsRefStr, EnumString};
+use crate::protocol::{data::Data, error::Result};
 
 // 3des-cbc         REQUIRED          three-key 3DES in CBC mode
 // aes256-cbc       OPTIONAL          AES in CBC mode, with a 256-bit key
@@ -18,22 +17,25 @@ pub trait EncryptionAdapter {
     // fn block_size(&self) -> u32;
     fn group_size(&self) -> u32;
     fn packet_length(&mut self, payload_length: u32) -> u32;
+    // number of leading bytes needed to read the packet length
+    fn length_size(&self) -> usize {
+        4
+    }
+    // reads the packet length without advancing the cipher state
+    fn decrypt_length(&self, buffer: &[u8], sequence_number: u32) -> u32;
+    // the part of the packet that has to be a multiple of group_size, all of it
+    // unless the length field is left out of the encryption
+    fn aligned_length(&self, packet_length: u32) -> u32 {
+        packet_length + 4
+    }
+    // size of the authentication tag appended by AEAD ciphers
+    fn tag_size(&self) -> usize {
+        0
+    }
     fn encrypt(&mut self, buffer: &mut Data, sequence_number: u32);
     fn decrypt<'a>(
         &mut self,
         buffer: &'a mut [u8],
         sequence_number: u32,
-    ) -> anyhow::Result<(&'a mut [u8], Vec<u8>, usize)>;
-}
-
-#[derive(Copy, Clone, PartialEq, Eq, AsRefStr, EnumString)]
-pub enum Enc {
-    #[strum(serialize = "chacha20-poly1305@openssh.com")]
-    Chacha20Poly1305Openssh,
-    #[strum(serialize = "aes128-ctr")]
-    Aes128Ctr,
-    #[strum(serialize = "aes192-ctr")]
-    Aes192Ctr,
-    #[strum(serialize = "aes256-ctr")]
-    Aes256Ctr,
+    ) -> Result<(&'a mut [u8], Vec<u8>, usize)>;
 }
@@ -1,51 +1,161 @@
-// use aes::cipher::KeyIvInit;
-// use aes::Aes128Ctr;
-// use ctr::cipher::StreamCipher;
-
-// use crate::utils::hex;
-
-// use super::Encryption;
-
-// type Aes128Ctr64LE = ctr::Ctr64LE<aes::Aes128>;
-
-// pub struct aes128_ctr {
-//     cipher: Aes128Ctr64LE,
-// }
-// impl aes128_ctr {
-//     pub fn new(key: &[u8], nonce: &[u8]) -> Self {
-//         let cipher = Aes128Ctr64LE::new(key[..16].into(), nonce[..16].into());
-//         // cipher.seek(0u32);
-//         aes128_ctr { cipher }
-//     }
-// }
-// impl Encryption for aes128_ctr {
-//     fn encrypt(&mut self, plaintext: &[u8]) -> Option<Vec<u8>> {
-//         let mut plaintext = plaintext.to_vec();
-//         self.cipher.apply_keystream(&mut plaintext);
-//         Some(plaintext.to_vec())
-//     }
-//     fn decrypt(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
-//         let mut ciphertext = ciphertext.to_vec();
-//         self.cipher.apply_keystream(&mut ciphertext);
-//         Some(ciphertext.to_vec())
-//     }
-// }
-// #[test]
-// fn encrypt_decrypt() {
-//     let key = [
-//         76, 221, 190, 22, 88, 43, 120, 83, 238, 242, 103, 43, 102, 166, 2, 140, 2, 69, 26, 90, 97,
-//         71, 171, 14, 75, 109, 150, 117, 175, 54, 125, 201,
-//     ];
-//     let nonce = [
-//         106, 248, 5, 76, 65, 174, 5, 214, 70, 140, 56, 45, 247, 51, 224, 53, 112, 107, 129, 95,
-//         164, 162, 3, 156, 15, 42, 36, 93, 33, 214, 3, 134,
-//     ];
-//     let mut cipher = aes128_ctr::new(key[..16].into(), nonce[..16].into());
-//     let plaintext = "test".as_bytes();
-//     let ciphertext = cipher.encrypt(plaintext).unwrap();
-//     let mut cipher = aes128_ctr::new(key[..16].into(), nonce[..16].into());
-//     let buf = cipher.decrypt(&ciphertext).unwrap();
-//     println!("{}", hex(plaintext));
-//     println!("{}", hex(&buf));
-//     assert_eq!(plaintext, &buf);
-// }
+use super::EncryptionAdapter;
+use crate::protocol::{
+    data::Data,
+    error::{Result, SshError},
+};
+use cipher::{KeyIvInit, StreamCipher};
+
+pub type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+pub type Aes192Ctr = ctr::Ctr128BE<aes::Aes192>;
+pub type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+const BLOCK_SIZE: usize = 16;
+
+// [RFC4344 § 4](https://datatracker.ietf.org/doc/html/rfc4344#section-4)
+// The counter is a 128-bit big-endian integer initialized from the IV and
+// incremented once per block, carried over across packets.
+//
+// With an encrypt-then-MAC algorithm the packet length is left in the clear.
+pub struct AesCtr<C> {
+    cipher: C,
+    etm: bool,
+}
+
+impl<C: KeyIvInit + StreamCipher> AesCtr<C> {
+    pub fn new(key: &[u8], iv: &[u8], etm: bool) -> Self {
+        let cipher = C::new_from_slices(&key[..C::key_size()], &iv[..C::iv_size()]).unwrap();
+        AesCtr { cipher, etm }
+    }
+}
+
+impl<C: KeyIvInit + StreamCipher + Clone> EncryptionAdapter for AesCtr<C> {
+    fn group_size(&self) -> u32 {
+        BLOCK_SIZE as u32
+    }
+
+    // The encrypted part of the packet must be a multiple of the block size,
+    // with at least four bytes of padding.
+    fn packet_length(&mut self, payload_length: u32) -> u32 {
+        let group_size = self.group_size();
+        match self.etm {
+            true => (payload_length + 4).div_ceil(group_size) * group_size,
+            false => (payload_length + 8).div_ceil(group_size) * group_size - 4,
+        }
+    }
+
+    fn length_size(&self) -> usize {
+        match self.etm {
+            true => 4,
+            false => BLOCK_SIZE,
+        }
+    }
+
+    fn aligned_length(&self, packet_length: u32) -> u32 {
+        match self.etm {
+            true => packet_length,
+            false => packet_length + 4,
+        }
+    }
+
+    // Decrypts a copy of the first block with a copy of the counter, so the
+    // keystream is not consumed until the whole packet has arrived.
+    fn decrypt_length(&self, buffer: &[u8], _sequence_number: u32) -> u32 {
+        let mut block: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+        let length = self.length_size();
+        block[..length].copy_from_slice(&buffer[..length]);
+        if !self.etm {
+            self.cipher.clone().apply_keystream(&mut block);
+        }
+        u32::from_be_bytes(block[..4].try_into().unwrap())
+    }
+
+    fn encrypt(&mut self, buffer: &mut Data, _sequence_number: u32) {
+        match self.etm {
+            true => self.cipher.apply_keystream(&mut buffer.0[4..]),
+            false => self.cipher.apply_keystream(&mut buffer.0),
+        }
+    }
+
+    fn decrypt<'a>(
+        &mut self,
+        buffer: &'a mut [u8],
+        _sequence_number: u32,
+    ) -> Result<(&'a mut [u8], Vec<u8>, usize)> {
+        let decrypted = self.length_size();
+        let Some(first) = buffer.get_mut(..decrypted) else {
+            return Err(SshError::BadPacketLength(buffer.len() as u32));
+        };
+        if !self.etm {
+            self.cipher.apply_keystream(first);
+        }
+        let packet_len = u32::from_be_bytes(buffer[..4].try_into().unwrap()) as usize;
+
+        let (packet, buffer) = buffer
+            .split_at_mut_checked(packet_len + 4)
+            .filter(|(packet, _)| packet.len() >= decrypted)
+            .ok_or(SshError::BadPacketLength(packet_len as u32))?;
+        self.cipher.apply_keystream(&mut packet[decrypted..]);
+        Ok((buffer, packet.to_vec(), packet_len + 4))
+    }
+}
+
+#[test]
+fn aes128_ctr_nist_vector() {
+    // NIST SP 800-38A F.5.1 CTR-AES128.Encrypt, first block
+    let key = b"\x2b\x7e\x15\x16\x28\xae\xd2\xa6\xab\xf7\x15\x88\x09\xcf\x4f\x3c";
+    let iv = b"\xf0\xf1\xf2\xf3\xf4\xf5\xf6\xf7\xf8\xf9\xfa\xfb\xfc\xfd\xfe\xff";
+    let mut cipher = AesCtr::<Aes128Ctr>::new(key, iv, false);
+    let mut buffer =
+        Data(b"\x6b\xc1\xbe\xe2\x2e\x40\x9f\x96\xe9\x3d\x7e\x11\x73\x93\x17\x2a".to_vec());
+    cipher.encrypt(&mut buffer, 0);
+    assert_eq!(
+        buffer.into_inner(),
+        b"\x87\x4d\x61\x91\xb6\x20\xe3\x26\x1b\xef\x68\x64\x99\x0d\xb6\xce"
+    );
+
+    // the counter wraps around at 2^128 and the keystream carries over packets
+    let mut client = AesCtr::<Aes128Ctr>::new(key, &[0xff; 16], false);
+    let mut server = AesCtr::<Aes128Ctr>::new(key, &[0xff; 16], false);
+    for payload_length in [5, 27] {
+        let packet_length = client.packet_length(payload_length);
+        assert_eq!((packet_length + 4) % 16, 0);
+        assert!(packet_length - payload_length >= 4);
+
+        let mut packet = Data::new();
+        packet
+            .put(&packet_length)
+            .put(&vec![7; packet_length as usize].as_slice());
+        let plaintext = packet.clone().into_inner();
+        client.encrypt(&mut packet, 0);
+        let mut ciphertext = packet.into_inner();
+        ciphertext.extend([0xaa; 4]);
+
+        let (next, decrypted, length) = server.decrypt(&mut ciphertext, 0).unwrap();
+        assert_eq!(next, &[0xaa; 4]);
+        assert_eq!(decrypted, plaintext);
+        assert_eq!(length, plaintext.len());
+    }
+}
+
+#[test]
+fn aes256_ctr_etm_keeps_length_in_clear() {
+    let mut client = AesCtr::<Aes256Ctr>::new(&[1; 32], &[2; 16], true);
+    let mut server = AesCtr::<Aes256Ctr>::new(&[1; 32], &[2; 16], true);
+
+    let packet_length = client.packet_length(11);
+    assert_eq!(packet_length % 16, 0);
+    assert!(packet_length - 11 >= 4);
+
+    let mut packet = Data::new();
+    packet
+        .put(&packet_length)
+        .put(&vec![7; packet_length as usize].as_slice());
+    let plaintext = packet.clone().into_inner();
+    client.encrypt(&mut packet, 0);
+    let mut ciphertext = packet.into_inner();
+    assert_eq!(ciphertext[..4], plaintext[..4]);
+    assert_ne!(ciphertext[4..], plaintext[4..]);
+
+    let (_, decrypted, _) = server.decrypt(&mut ciphertext, 0).unwrap();
+    assert_eq!(decrypted, plaintext);
+}
@@ -1,50 +1,135 @@
-// use aes_gcm::{
-//     aead::{generic_array::GenericArray, Aead, AeadCore, KeyInit, OsRng},
-//     Aes128Gcm, Aes256Gcm, Key, Nonce,
-// };
-
-// use super::Encryption;
-
-// pub struct aes256_gcm {
-//     cipher: Aes256Gcm,
-//     nonce: Nonce<typenum::U12>,
-// }
-// impl aes256_gcm {
-//     pub fn new(key: &[u8], nonce: &[u8]) -> Self {
-//         let key = Key::<Aes256Gcm>::from_slice(key);
-//         let cipher = Aes256Gcm::new(key);
-//         aes256_gcm {
-//             cipher,
-//             nonce: *GenericArray::from_slice(nonce),
-//         }
-//     }
-// }
-// impl Encryption for aes256_gcm {
-//     fn encrypt(&mut self, plaintext: &[u8]) -> Option<Vec<u8>> {
-//         self.cipher.encrypt(&self.nonce, plaintext).ok()
-//     }
-//     fn decrypt(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
-//         self.cipher.decrypt(&self.nonce, ciphertext).ok()
-//     }
-// }
-
-// pub struct aes128_gcm {
-//     cipher: Aes128Gcm,
-//     nonce: Nonce<typenum::U12>,
-// }
-// impl aes128_gcm {
-//     fn new(key: &[u8]) -> Self {
-//         let key = Key::<Aes128Gcm>::from_slice(key);
-//         let cipher = Aes128Gcm::new(key);
-//         let nonce: Nonce<typenum::U12> = Aes128Gcm::generate_nonce(&mut OsRng);
-//         aes128_gcm { cipher, nonce }
-//     }
-// }
-// impl Encryption for aes128_gcm {
-//     fn encrypt(&mut self, plaintext: &[u8]) -> Option<Vec<u8>> {
-//         self.cipher.encrypt(&self.nonce, plaintext).ok()
-//     }
-//     fn decrypt(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
-//         self.cipher.decrypt(&self.nonce, ciphertext).ok()
-//     }
-// }
+use super::EncryptionAdapter;
+use crate::protocol::{
+    data::Data,
+    error::{Result, SshError},
+};
+use aes_gcm::{
+    aead::{generic_array::GenericArray, AeadCore, AeadInPlace, KeyInit},
+    Tag,
+};
+
+const BLOCK_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+
+// [RFC5647 § 7](https://datatracker.ietf.org/doc/html/rfc5647#section-7)
+// uint32  fixed field; the first four bytes of the derived IV
+// uint64  invocation_counter; incremented after every packet
+//
+// The packet length is sent in the clear and authenticated as additional data.
+pub struct AesGcm<C> {
+    cipher: C,
+    iv: [u8; 12],
+}
+
+impl<C: KeyInit> AesGcm<C> {
+    pub fn new(key: &[u8], iv: &[u8]) -> Self {
+        let cipher = C::new_from_slice(&key[..C::key_size()]).unwrap();
+        AesGcm {
+            cipher,
+            iv: iv[..12].try_into().unwrap(),
+        }
+    }
+
+    fn increment_invocation_counter(&mut self) {
+        let counter = u64::from_be_bytes(self.iv[4..].try_into().unwrap());
+        self.iv[4..].copy_from_slice(&counter.wrapping_add(1).to_be_bytes());
+    }
+}
+
+impl<C: KeyInit + AeadInPlace> EncryptionAdapter for AesGcm<C> {
+    fn group_size(&self) -> u32 {
+        BLOCK_SIZE as u32
+    }
+
+    // The length field is not encrypted, so only the rest is padded to the block size.
+    fn packet_length(&mut self, payload_length: u32) -> u32 {
+        let group_size = self.group_size();
+        (payload_length + 4).div_ceil(group_size) * group_size
+    }
+
+    fn aligned_length(&self, packet_length: u32) -> u32 {
+        packet_length
+    }
+
+    fn decrypt_length(&self, buffer: &[u8], _sequence_number: u32) -> u32 {
+        u32::from_be_bytes(buffer[..4].try_into().unwrap())
+    }
+
+    fn tag_size(&self) -> usize {
+        TAG_SIZE
+    }
+
+    fn encrypt(&mut self, buffer: &mut Data, _sequence_number: u32) {
+        let (packet_length, packet) = buffer.0.split_at_mut(4);
+        let nonce = GenericArray::<u8, <C as AeadCore>::NonceSize>::from_slice(&self.iv);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(nonce, packet_length, packet)
+            .unwrap();
+        buffer.0.extend_from_slice(&tag);
+        self.increment_invocation_counter();
+    }
+
+    fn decrypt<'a>(
+        &mut self,
+        buffer: &'a mut [u8],
+        _sequence_number: u32,
+    ) -> Result<(&'a mut [u8], Vec<u8>, usize)> {
+        let packet_len = self.decrypt_length(buffer, 0) as usize;
+        let (packet, buffer) = buffer
+            .split_at_mut_checked(packet_len + 4)
+            .ok_or(SshError::BadPacketLength(packet_len as u32))?;
+        let (tag, buffer) = buffer
+            .split_at_mut_checked(TAG_SIZE)
+            .ok_or(SshError::BadPacketLength(packet_len as u32))?;
+
+        let (packet_length, ciphertext) = packet.split_at_mut(4);
+        let nonce = GenericArray::<u8, <C as AeadCore>::NonceSize>::from_slice(&self.iv);
+        self.cipher
+            .decrypt_in_place_detached(nonce, packet_length, ciphertext, Tag::from_slice(tag))
+            .map_err(|_| SshError::MacInvalid)?;
+        self.increment_invocation_counter();
+
+        Ok((buffer, packet.to_vec(), packet_len + 4 + TAG_SIZE))
+    }
+}
+
+#[test]
+fn aes_gcm_round_trip() {
+    let key = [0x42; 32];
+    let iv = [0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+    let mut client = AesGcm::<aes_gcm::Aes256Gcm>::new(&key, &iv);
+    let mut server = AesGcm::<aes_gcm::Aes256Gcm>::new(&key, &iv);
+
+    let mut previous = Vec::new();
+    for _ in 0..2 {
+        let packet_length = client.packet_length(13);
+        assert_eq!(packet_length % 16, 0);
+        assert!(packet_length - 13 >= 4);
+
+        let mut packet = Data::new();
+        packet
+            .put(&packet_length)
+            .put(&vec![7; packet_length as usize].as_slice());
+        let plaintext = packet.clone().into_inner();
+        client.encrypt(&mut packet, 0);
+        let mut ciphertext = packet.into_inner();
+        assert_eq!(ciphertext[..4], plaintext[..4]);
+        assert_ne!(ciphertext, previous);
+        previous = ciphertext.clone();
+
+        let (next, decrypted, length) = server.decrypt(&mut ciphertext, 0).unwrap();
+        assert!(next.is_empty());
+        assert_eq!(decrypted, plaintext);
+        assert_eq!(length, plaintext.len() + 16);
+    }
+    // the invocation counter wrapped without touching the fixed field
+    assert_eq!(client.iv, [0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+    let mut packet = Data::new();
+    packet.put(&16u32).put(&[7; 16].as_slice());
+    client.encrypt(&mut packet, 0);
+    let mut ciphertext = packet.into_inner();
+    ciphertext[10] ^= 1;
+    assert!(server.decrypt(&mut ciphertext, 0).is_err());
+}
@@ -1,40 +1,59 @@
 use super::EncryptionAdapter;
-use crate::protocol::{data::Data, error::SshError};
+use crate::protocol::{
+    data::Data,
+    error::{Result, SshError},
+};
 use ring::aead::chacha20_poly1305_openssh::{OpeningKey, SealingKey};
 
 const BSIZE: usize = 64;
+const TAG_SIZE: usize = 16;
 
 pub(crate) struct ChaCha20Poly1305 {
-    client_key: SealingKey,
-    server_key: OpeningKey,
+    sealing_key: SealingKey,
+    opening_key: OpeningKey,
 }
 
 impl ChaCha20Poly1305 {
-    pub fn new(ck: &[u8], sk: &[u8]) -> Self {
-        let mut sealing_key = [0_u8; BSIZE];
-        let mut opening_key = [0_u8; BSIZE];
-        sealing_key.copy_from_slice(ck);
-        opening_key.copy_from_slice(sk);
+    pub fn new(key: &[u8]) -> Self {
+        let mut k = [0_u8; BSIZE];
+        k.copy_from_slice(&key[..BSIZE]);
 
         ChaCha20Poly1305 {
-            client_key: SealingKey::new(&sealing_key),
-            server_key: OpeningKey::new(&opening_key),
+            sealing_key: SealingKey::new(&k),
+            opening_key: OpeningKey::new(&k),
         }
     }
 }
 
 impl EncryptionAdapter for ChaCha20Poly1305 {
     fn group_size(&self) -> u32 {
-        64
+        8
     }
+
+    // The length field is encrypted separately, so only the rest is padded to the block size.
     fn packet_length(&mut self, payload_length: u32) -> u32 {
         let group_size = self.group_size();
-        (payload_length + group_size - 1) / group_size * group_size
+        (payload_length + 4).div_ceil(group_size) * group_size
+    }
+
+    fn aligned_length(&self, packet_length: u32) -> u32 {
+        packet_length
+    }
+
+    fn decrypt_length(&self, buffer: &[u8], sequence_number: u32) -> u32 {
+        let packet_length = self
+            .opening_key
+            .decrypt_packet_length(sequence_number, buffer[..4].try_into().unwrap());
+        u32::from_be_bytes(packet_length)
+    }
+
+    fn tag_size(&self) -> usize {
+        TAG_SIZE
     }
 
     fn encrypt(&mut self, buf: &mut Data, sequence_number: u32) {
         let mut tag = [0_u8; 16];
-        self.client_key
+        self.sealing_key
             .seal_in_place(sequence_number, &mut buf.0, &mut tag);
         buf.0.append(&mut tag.to_vec());
     }
@@ -43,24 +62,27 @@ impl EncryptionAdapter for ChaCha20Poly1305 {
         &mut self,
         buf: &'a mut [u8],
         sequence_number: u32,
-    ) -> anyhow::Result<(&'a mut [u8], Vec<u8>, usize)> {
-        let mut packet_len_slice: [u8; 4] = [0; 4];
-        packet_len_slice.copy_from_slice(&buf[..4]);
-        let packet_len_slice = self
-            .server_key
-            .decrypt_packet_length(sequence_number, packet_len_slice);
-        let packet_len = u32::from_be_bytes(packet_len_slice);
+    ) -> Result<(&'a mut [u8], Vec<u8>, usize)> {
+        let packet_len = self.decrypt_length(buf, sequence_number);
 
-        let (packet, buf) = buf.split_at_mut((packet_len + 4) as usize);
-        let (tag, buf) = buf.split_at_mut(16_usize);
-        let tag: [u8; 16] = tag.try_into().unwrap();
-        match self.server_key.open_in_place(sequence_number, packet, &tag) {
+        let (packet, buf) = (packet_len as usize)
+            .checked_add(4)
+            .and_then(|packet_size| buf.split_at_mut_checked(packet_size))
+            .ok_or(SshError::BadPacketLength(packet_len))?;
+        let (tag, buf) = buf
+            .split_at_mut_checked(TAG_SIZE)
+            .ok_or(SshError::BadPacketLength(packet_len))?;
+        let tag: [u8; TAG_SIZE] = tag.try_into().unwrap();
+        match self
+            .opening_key
+            .open_in_place(sequence_number, packet, &tag)
+        {
             Ok(result) => Ok((
                 buf,
-                [&packet_len_slice[..], result].concat(),
-                (packet_len + 0x14) as usize,
+                [&packet_len.to_be_bytes()[..], result].concat(),
+                packet_len as usize + 4 + TAG_SIZE,
             )),
-            Err(_) => Err(SshError::RecvError("decrypt".to_string()).into()),
+            Err(_) => Err(SshError::MacInvalid),
         }
     }
 }
@@ -1,5 +1,8 @@
 use super::EncryptionAdapter;
-use crate::protocol::data::Data;
+use crate::protocol::{
+    data::Data,
+    error::{Result, SshError},
+};
 
 #[derive(Debug, Clone)]
 pub struct NoneEncryption {}
@@ -15,7 +18,11 @@ impl EncryptionAdapter for NoneEncryption {
     }
     fn packet_length(&mut self, payload_length: u32) -> u32 {
         let group_size = self.group_size();
-        (payload_length + group_size - 1) / group_size * group_size + 4
+        payload_length.div_ceil(group_size) * group_size + 4
+    }
+
+    fn decrypt_length(&self, buffer: &[u8], _sequence_number: u32) -> u32 {
+        u32::from_be_bytes(buffer[..4].try_into().unwrap())
     }
 
     fn encrypt(&mut self, _buffer: &mut Data, _sequence_number: u32) {}
@@ -23,11 +30,17 @@ impl EncryptionAdapter for NoneEncryption {
         &mut self,
         buffer: &'a mut [u8],
         _sequence_number: u32,
-    ) -> anyhow::Result<(&'a mut [u8], Vec<u8>, usize)> {
+    ) -> Result<(&'a mut [u8], Vec<u8>, usize)> {
         let mut packet_len_slice: [u8; 4] = [0; 4];
-        packet_len_slice.copy_from_slice(&buffer[..4]);
+        packet_len_slice.copy_from_slice(
+            buffer
+                .get(..4)
+                .ok_or(SshError::BadPacketLength(buffer.len() as u32))?,
+        );
         let packet_len = u32::from_be_bytes(packet_len_slice);
-        let (packet, buffer) = buffer.split_at_mut((packet_len + 4) as usize);
-        Ok((buffer, packet.to_vec(), (packet_len + 4) as usize))
+        let (packet, buffer) = buffer
+            .split_at_mut_checked(packet_len as usize + 4)
+            .ok_or(SshError::BadPacketLength(packet_len))?;
+        Ok((buffer, packet.to_vec(), packet_len as usize + 4))
     }
 }
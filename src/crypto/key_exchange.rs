@@ -1,33 +1,40 @@
-use strum_macros::{AsRefStr, EnumString};
-
 pub mod curve;
 pub mod dh;
+pub mod ecdh;
+pub mod hybrid;
+pub mod moduli;
+
+use crate::protocol::data::{ByteString, DataType, Mpint};
+use crate::protocol::error::Result;
 
 // diffie-hellman-group1-sha1 REQUIRED
 // diffie-hellman-group14-sha1 REQUIRED
+// diffie-hellman-group14-sha256
+// diffie-hellman-group16-sha512
+// diffie-hellman-group18-sha512
+// diffie-hellman-group-exchange-sha256
+// ecdh-sha2-nistp256
+// ecdh-sha2-nistp384
+// ecdh-sha2-nistp521
 // curve25519-sha256
 // curve448-sha512
+// mlkem768x25519-sha256
+// sntrup761x25519-sha512@openssh.com
 pub trait KexMethodAdapter {
     fn new() -> Self
     where
         Self: Sized;
     fn public_key(&self) -> Vec<u8>;
-    fn shared_secret(&mut self, public_key: &[u8]) -> Vec<u8>;
+    // ECDH sends Q_C and Q_S as string, finite field DH sends e and f as mpint
+    fn encode_public_key(&self, public_key: &[u8], buf: &mut Vec<u8>) {
+        ByteString(public_key.to_vec()).encode(buf)
+    }
+    // Group exchange hashes the requested sizes and the group between K_S and e
+    fn encode_group(&self, _buf: &mut Vec<u8>) {}
+    // K is an mpint, the hybrid post-quantum methods send it as string
+    fn encode_shared_secret(&self, shared_secret: &[u8], buf: &mut Vec<u8>) {
+        Mpint::from_unsigned(shared_secret).encode(buf)
+    }
+    fn shared_secret(&mut self, public_key: &[u8]) -> Result<Vec<u8>>;
     fn hash(&self, seed: &[u8]) -> Vec<u8>;
 }
-
-/// key exchange algorithm
-#[derive(Copy, Clone, PartialEq, Eq, AsRefStr, EnumString)]
-pub enum Kex {
-    #[strum(serialize = "curve25519-sha256")]
-    Curve25519Sha256,
-    #[strum(serialize = "ecdh-sha2-nistp256")]
-    EcdhSha2Nistrp256,
-    #[cfg(feature = "dangerous-dh-group1-sha1")]
-    #[strum(serialize = "diffie-hellman-group1-sha1")]
-    DiffieHellmanGroup1Sha1,
-    #[strum(serialize = "diffie-hellman-group14-sha1")]
-    DiffieHellmanGroup14Sha1,
-    #[strum(serialize = "diffie-hellman-group14-sha256")]
-    DiffieHellmanGroup14Sha256,
-}
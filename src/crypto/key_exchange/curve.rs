@@ -1,4 +1,6 @@
 use super::KexMethodAdapter;
+use crate::protocol::error::{Result, SshError};
+use rand::Rng;
 use rand_core::OsRng;
 use sha2::{Digest, Sha256, Sha512};
 use x25519_dalek::{EphemeralSecret, PublicKey};
@@ -20,12 +22,24 @@ impl KexMethodAdapter for Curve25519Sha256 {
     fn public_key(&self) -> Vec<u8> {
         self.public_key.to_bytes().to_vec()
     }
-    fn shared_secret(&mut self, public_key: &[u8]) -> Vec<u8> {
-        let public_key: [u8; 32] = public_key.try_into().unwrap();
+    fn shared_secret(&mut self, public_key: &[u8]) -> Result<Vec<u8>> {
+        let public_key: [u8; 32] = public_key
+            .try_into()
+            .map_err(|_| SshError::InvalidPublicValue("X25519 public key".to_string()))?;
         let public_key = PublicKey::from(public_key);
-        let private_key = self.private_key.take().unwrap();
+        let private_key = self
+            .private_key
+            .take()
+            .ok_or_else(|| SshError::Protocol("X25519 private key already used".to_string()))?;
         let shared_secret = private_key.diffie_hellman(&public_key);
-        shared_secret.to_bytes().to_vec()
+        // [RFC8731 § 3](https://datatracker.ietf.org/doc/html/rfc8731#section-3)
+        // a low order Q_S gives the all-zero value, which MUST be rejected
+        if shared_secret.as_bytes().iter().all(|b| *b == 0) {
+            return Err(SshError::InvalidPublicValue(
+                "X25519 public key".to_string(),
+            ));
+        }
+        Ok(shared_secret.to_bytes().to_vec())
     }
     fn hash(&self, seed: &[u8]) -> Vec<u8> {
         let mut hasher = Sha256::new();
@@ -34,16 +48,36 @@ impl KexMethodAdapter for Curve25519Sha256 {
     }
 }
 
-pub struct Curve448Sha512 {}
+// [RFC8731](https://datatracker.ietf.org/doc/html/rfc8731)
+// Same as curve25519-sha256 with X448 and SHA-512, Q_C and Q_S are 56 bytes.
+pub struct Curve448Sha512 {
+    private_key: x448::Secret,
+    public_key: [u8; 56],
+}
+
 impl KexMethodAdapter for Curve448Sha512 {
     fn new() -> Self {
-        Curve448Sha512 {}
+        let mut private_key = [0u8; 56];
+        rand::thread_rng().fill(&mut private_key[..]);
+        let private_key = x448::Secret::from(private_key);
+        let public_key = *x448::PublicKey::from(&private_key).as_bytes();
+        Curve448Sha512 {
+            private_key,
+            public_key,
+        }
     }
     fn public_key(&self) -> Vec<u8> {
-        todo!()
+        self.public_key.to_vec()
     }
-    fn shared_secret(&mut self, _public_key: &[u8]) -> Vec<u8> {
-        todo!()
+    fn shared_secret(&mut self, public_key: &[u8]) -> Result<Vec<u8>> {
+        // [RFC8731 § 3](https://datatracker.ietf.org/doc/html/rfc8731#section-3)
+        // Low order points would give an all-zero secret and MUST be rejected.
+        let shared_secret = x448::PublicKey::from_bytes(public_key)
+            .and_then(|public_key| self.private_key.as_diffie_hellman(&public_key))
+            .map(|shared_secret| shared_secret.as_bytes().to_vec())
+            .filter(|shared_secret| shared_secret.iter().any(|b| *b != 0))
+            .ok_or_else(|| SshError::InvalidPublicValue("curve448 public key".to_string()))?;
+        Ok(shared_secret)
     }
     fn hash(&self, seed: &[u8]) -> Vec<u8> {
         let mut hasher = Sha512::new();
@@ -51,3 +85,66 @@ impl KexMethodAdapter for Curve448Sha512 {
         hasher.finalize().as_slice().to_vec()
     }
 }
+
+#[test]
+fn curve25519_agreement() {
+    let mut client = Curve25519Sha256::new();
+    let mut server = Curve25519Sha256::new();
+    let client_secret = client.shared_secret(&server.public_key()).unwrap();
+    let server_secret = server.shared_secret(&client.public_key()).unwrap();
+    assert_eq!(client_secret, server_secret);
+
+    // the ephemeral key is used once
+    assert!(matches!(
+        client.shared_secret(&server.public_key()),
+        Err(SshError::Protocol(_))
+    ));
+    assert!(matches!(
+        Curve25519Sha256::new().shared_secret(&[9; 31]),
+        Err(SshError::InvalidPublicValue(_))
+    ));
+
+    // points of small order: zero, one and a point of order 8
+    let mut order8 = [0u8; 32];
+    order8[..8].copy_from_slice(&[0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae]);
+    order8[8..16].copy_from_slice(&[0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4, 0x6a]);
+    order8[16..24].copy_from_slice(&[0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd]);
+    order8[24..].copy_from_slice(&[0x86, 0x62, 0x05, 0x16, 0x5f, 0x49, 0xb8, 0x00]);
+    let mut one = [0u8; 32];
+    one[0] = 1;
+    for point in [[0u8; 32], one, order8] {
+        assert!(matches!(
+            Curve25519Sha256::new().shared_secret(&point),
+            Err(SshError::InvalidPublicValue(_))
+        ));
+    }
+}
+
+#[test]
+fn curve448_rfc7748_vector() {
+    // [RFC7748 § 6.2](https://datatracker.ietf.org/doc/html/rfc7748#section-6.2)
+    let hex = |s: &str| {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect::<Vec<u8>>()
+    };
+    let alice = hex("9a8f4925d1519f5775cf46b04b5800d4ee9ee8bae8bc5565d498c28dd9c9baf574a9419744897391006382a6f127ab1d9ac2d8c0a598726b");
+    let bob_public = hex("3eb7a829b0cd20f5bcfc0b599b6feccf6da4627107bdb0d4f345b43027d8b972fc3e34fb4232a13ca706dcb57aec3dae07bdc1c67bf33609");
+    let shared = hex("07fff4181ac6cc95ec1c16a94a0f74d12da232ce40a77552281d282bb60c0b56fd2464c335543936521c24403085d59a449a5037514a879d");
+
+    let private_key = x448::Secret::from_bytes(&alice).unwrap();
+    let mut method = Curve448Sha512 {
+        public_key: *x448::PublicKey::from(&private_key).as_bytes(),
+        private_key,
+    };
+    assert_eq!(
+        method.public_key(),
+        hex("9b08f7cc31b7e3e67d22d5aea121074a273bd2b83de09c63faa73d2c22c5d9bbc836647241d953d40c5b12da88120d53177f80e532c41fa0")
+    );
+    assert_eq!(method.shared_secret(&bob_public).unwrap(), shared);
+
+    // the key derivation hash is SHA-512
+    assert_eq!(method.hash(b"").len(), 64);
+    assert!(method.shared_secret(&[0; 56]).is_err());
+}
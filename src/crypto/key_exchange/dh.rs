@@ -1,39 +1,343 @@
-use sha1::{Digest, Sha1};
-
 use super::KexMethodAdapter;
+use crate::protocol::{
+    data::{DataType, Mpint},
+    error::{Result, SshError},
+};
+use rand::Rng;
+use rsa::BigUint;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::marker::PhantomData;
+
+// Size of the private exponent x, twice the strength of the largest derived key (256 bits).
+const PRIVATE_KEY_BITS: usize = 512;
+
+// All MODP groups use 2 as the generator.
+const GENERATOR: u32 = 2;
+
+// Odd numbers below this are tried as factors of a server's group.
+const SIEVE_LIMIT: u32 = 1024;
+
+pub trait Group {
+    const PRIME: &'static [&'static str];
+
+    fn prime() -> BigUint {
+        BigUint::parse_bytes(Self::PRIME.concat().replace(' ', "").as_bytes(), 16).unwrap()
+    }
+}
+
+pub struct Group1;
+pub struct Group14;
+pub struct Group16;
+pub struct Group18;
+
+impl Group for Group1 {
+    const PRIME: &'static [&'static str] = GROUP1_PRIME;
+}
+impl Group for Group14 {
+    const PRIME: &'static [&'static str] = GROUP14_PRIME;
+}
+impl Group for Group16 {
+    const PRIME: &'static [&'static str] = GROUP16_PRIME;
+}
+impl Group for Group18 {
+    const PRIME: &'static [&'static str] = GROUP18_PRIME;
+}
+
+pub type DiffieHellmanGroup1Sha1 = DiffieHellman<Group1, Sha1>;
+pub type DiffieHellmanGroup14Sha1 = DiffieHellman<Group14, Sha1>;
+pub type DiffieHellmanGroup14Sha256 = DiffieHellman<Group14, Sha256>;
+pub type DiffieHellmanGroup16Sha512 = DiffieHellman<Group16, Sha512>;
+pub type DiffieHellmanGroup18Sha512 = DiffieHellman<Group18, Sha512>;
 
-pub struct DiffieHellmanGroup1Sha1 {}
-impl KexMethodAdapter for DiffieHellmanGroup1Sha1 {
+// [RFC4253 § 8](https://datatracker.ietf.org/doc/html/rfc4253#section-8)
+// The client sends e = g^x mod p, the server replies with f = g^y mod p and
+// both compute K = f^x mod p. Unlike ECDH, e and f are sent and hashed as mpint.
+pub struct DiffieHellman<G, D> {
+    prime: BigUint,
+    private_key: BigUint,
+    public_key: BigUint,
+    _group: PhantomData<(G, D)>,
+}
+
+impl<G: Group, D: Digest> KexMethodAdapter for DiffieHellman<G, D> {
     fn new() -> Self {
-        DiffieHellmanGroup1Sha1 {}
+        let prime = G::prime();
+        let (private_key, public_key) = generate_key(&prime, &BigUint::from(GENERATOR));
+        DiffieHellman {
+            prime,
+            private_key,
+            public_key,
+            _group: PhantomData,
+        }
     }
     fn public_key(&self) -> Vec<u8> {
-        Vec::new()
+        self.public_key.to_bytes_be()
+    }
+    fn encode_public_key(&self, public_key: &[u8], buf: &mut Vec<u8>) {
+        Mpint::from_unsigned(public_key).encode(buf)
     }
-    fn shared_secret(&mut self, _public_key: &[u8]) -> Vec<u8> {
-        Vec::new()
+    fn shared_secret(&mut self, public_key: &[u8]) -> Result<Vec<u8>> {
+        compute_shared_secret(&self.prime, &self.private_key, public_key)
     }
     fn hash(&self, seed: &[u8]) -> Vec<u8> {
-        let mut hasher = Sha1::new();
-        hasher.update(seed);
-        hasher.finalize().as_slice().to_vec()
+        D::digest(seed).to_vec()
+    }
+}
+
+pub type DiffieHellmanGroupExchangeSha256 = DiffieHellmanGroupExchange<Sha256>;
+
+// [RFC4419 § 3](https://datatracker.ietf.org/doc/html/rfc4419#section-3)
+// Instead of a fixed group, the client asks for a prime of min <= n <= max bits
+// and the server picks one, usually from its moduli file.
+// Sizes are in bits; the minimum follows [RFC8270](https://datatracker.ietf.org/doc/html/rfc8270).
+pub const GEX_MIN_BITS: u32 = 2048;
+pub const GEX_PREFERRED_BITS: u32 = 3072;
+pub const GEX_MAX_BITS: u32 = 8192;
+
+pub struct DiffieHellmanGroupExchange<D> {
+    min: u32,
+    preferred: u32,
+    max: u32,
+    prime: BigUint,
+    generator: BigUint,
+    private_key: BigUint,
+    public_key: BigUint,
+    _digest: PhantomData<D>,
+}
+
+impl<D> DiffieHellmanGroupExchange<D> {
+    // uint32   min, minimal size in bits of an acceptable group
+    // uint32   n, preferred size in bits of the group the server will send
+    // uint32   max, maximal size in bits of an acceptable group
+    pub fn request(&self) -> (u32, u32, u32) {
+        (self.min, self.preferred, self.max)
+    }
+
+    // Checks the group from SSH_MSG_KEX_DH_GEX_GROUP and generates x and e in it.
+    // p must be a safe prime (p = 2q + 1 with q prime) of the requested size
+    // and g must not generate one of the trivial subgroups.
+    pub fn set_group(&mut self, prime: &[u8], generator: &[u8]) -> Result<()> {
+        let prime = BigUint::from_bytes_be(prime);
+        let generator = BigUint::from_bytes_be(generator);
+
+        let bits = prime.bits();
+        if bits < self.min as usize || bits > self.max as usize {
+            return Err(SshError::DhGexOutOfRange);
+        }
+        let one = BigUint::from(1u32);
+        if generator <= one || generator >= &prime - &one {
+            return Err(SshError::InvalidPublicValue("DH GEX generator".to_string()));
+        }
+        if !is_safe_prime(&prime) {
+            return Err(SshError::InvalidPublicValue(
+                "DH GEX group is not a safe prime".to_string(),
+            ));
+        }
+
+        let (private_key, public_key) = generate_key(&prime, &generator);
+        self.prime = prime;
+        self.generator = generator;
+        self.private_key = private_key;
+        self.public_key = public_key;
+        Ok(())
+    }
+}
+
+// Only a sanity check, like OpenSSH's: proving an 8192-bit p and q prime would
+// take seconds on every connection. p and q = (p - 1) / 2 have to be odd and
+// without small factors, and p has to pass a base 2 Fermat test.
+fn is_safe_prime(prime: &BigUint) -> bool {
+    let zero = BigUint::default();
+    let one = BigUint::from(1u32);
+    let q = (prime - &one) >> 1;
+    if prime.trailing_zeros() != Some(0) || q.trailing_zeros() != Some(0) {
+        return false;
     }
+    let small_factor = (3..SIEVE_LIMIT).step_by(2).any(|factor| {
+        let factor = BigUint::from(factor);
+        prime % &factor == zero || &q % &factor == zero
+    });
+    !small_factor && BigUint::from(2u32).modpow(&(prime - &one), prime) == one
 }
 
-pub struct DiffieHellmanGroup14Sha1 {}
-impl KexMethodAdapter for DiffieHellmanGroup14Sha1 {
+impl<D: Digest> KexMethodAdapter for DiffieHellmanGroupExchange<D> {
     fn new() -> Self {
-        DiffieHellmanGroup14Sha1 {}
+        DiffieHellmanGroupExchange {
+            min: GEX_MIN_BITS,
+            preferred: GEX_PREFERRED_BITS,
+            max: GEX_MAX_BITS,
+            prime: BigUint::default(),
+            generator: BigUint::default(),
+            private_key: BigUint::default(),
+            public_key: BigUint::default(),
+            _digest: PhantomData,
+        }
     }
     fn public_key(&self) -> Vec<u8> {
-        todo!()
+        self.public_key.to_bytes_be()
+    }
+    fn encode_public_key(&self, public_key: &[u8], buf: &mut Vec<u8>) {
+        Mpint::from_unsigned(public_key).encode(buf)
     }
-    fn shared_secret(&mut self, _public_key: &[u8]) -> Vec<u8> {
-        todo!()
+    // uint32   min, n, max as requested
+    // mpint    p, safe prime
+    // mpint    g, generator for subgroup
+    fn encode_group(&self, buf: &mut Vec<u8>) {
+        self.min.encode(buf);
+        self.preferred.encode(buf);
+        self.max.encode(buf);
+        Mpint::from(&self.prime).encode(buf);
+        Mpint::from(&self.generator).encode(buf);
+    }
+    fn shared_secret(&mut self, public_key: &[u8]) -> Result<Vec<u8>> {
+        compute_shared_secret(&self.prime, &self.private_key, public_key)
     }
     fn hash(&self, seed: &[u8]) -> Vec<u8> {
-        let mut hasher = Sha1::new();
-        hasher.update(seed);
-        hasher.finalize().as_slice().to_vec()
+        D::digest(seed).to_vec()
+    }
+}
+
+// x is a random number with its top bit set, e = g^x mod p
+fn generate_key(prime: &BigUint, generator: &BigUint) -> (BigUint, BigUint) {
+    let mut x = [0u8; PRIVATE_KEY_BITS / 8];
+    rand::thread_rng().fill(&mut x[..]);
+    x[0] |= 0x80;
+    let private_key = BigUint::from_bytes_be(&x);
+    let public_key = generator.modpow(&private_key, prime);
+    (private_key, public_key)
+}
+
+// K = f^x mod p
+fn compute_shared_secret(
+    prime: &BigUint,
+    private_key: &BigUint,
+    public_key: &[u8],
+) -> Result<Vec<u8>> {
+    // f must be in the range [1, p-1], excluding the trivial values 1 and p-1
+    let f = BigUint::from_bytes_be(public_key);
+    let one = BigUint::from(1u32);
+    if f <= one || f >= prime - &one {
+        return Err(SshError::InvalidPublicValue("DH public value".to_string()));
     }
+    Ok(f.modpow(private_key, prime).to_bytes_be())
+}
+
+// [RFC2409 § 6.2](https://datatracker.ietf.org/doc/html/rfc2409#section-6.2) Second Oakley Group, 1024 bits
+const GROUP1_PRIME: &[&str] = &[
+    "FFFFFFFF FFFFFFFF C90FDAA2 2168C234 C4C6628B 80DC1CD1",
+    "29024E08 8A67CC74 020BBEA6 3B139B22 514A0879 8E3404DD",
+    "EF9519B3 CD3A431B 302B0A6D F25F1437 4FE1356D 6D51C245",
+    "E485B576 625E7EC6 F44C42E9 A637ED6B 0BFF5CB6 F406B7ED",
+    "EE386BFB 5A899FA5 AE9F2411 7C4B1FE6 49286651 ECE65381",
+    "FFFFFFFF FFFFFFFF",
+];
+
+// [RFC3526 § 3](https://datatracker.ietf.org/doc/html/rfc3526#section-3) 2048-bit MODP Group
+const GROUP14_PRIME: &[&str] = &[
+    "FFFFFFFF FFFFFFFF C90FDAA2 2168C234 C4C6628B 80DC1CD1",
+    "29024E08 8A67CC74 020BBEA6 3B139B22 514A0879 8E3404DD",
+    "EF9519B3 CD3A431B 302B0A6D F25F1437 4FE1356D 6D51C245",
+    "E485B576 625E7EC6 F44C42E9 A637ED6B 0BFF5CB6 F406B7ED",
+    "EE386BFB 5A899FA5 AE9F2411 7C4B1FE6 49286651 ECE45B3D",
+    "C2007CB8 A163BF05 98DA4836 1C55D39A 69163FA8 FD24CF5F",
+    "83655D23 DCA3AD96 1C62F356 208552BB 9ED52907 7096966D",
+    "670C354E 4ABC9804 F1746C08 CA18217C 32905E46 2E36CE3B",
+    "E39E772C 180E8603 9B2783A2 EC07A28F B5C55DF0 6F4C52C9",
+    "DE2BCBF6 95581718 3995497C EA956AE5 15D22618 98FA0510",
+    "15728E5A 8AACAA68 FFFFFFFF FFFFFFFF",
+];
+
+// [RFC3526 § 5](https://datatracker.ietf.org/doc/html/rfc3526#section-5) 4096-bit MODP Group
+const GROUP16_PRIME: &[&str] = &[
+    "FFFFFFFF FFFFFFFF C90FDAA2 2168C234 C4C6628B 80DC1CD1",
+    "29024E08 8A67CC74 020BBEA6 3B139B22 514A0879 8E3404DD",
+    "EF9519B3 CD3A431B 302B0A6D F25F1437 4FE1356D 6D51C245",
+    "E485B576 625E7EC6 F44C42E9 A637ED6B 0BFF5CB6 F406B7ED",
+    "EE386BFB 5A899FA5 AE9F2411 7C4B1FE6 49286651 ECE45B3D",
+    "C2007CB8 A163BF05 98DA4836 1C55D39A 69163FA8 FD24CF5F",
+    "83655D23 DCA3AD96 1C62F356 208552BB 9ED52907 7096966D",
+    "670C354E 4ABC9804 F1746C08 CA18217C 32905E46 2E36CE3B",
+    "E39E772C 180E8603 9B2783A2 EC07A28F B5C55DF0 6F4C52C9",
+    "DE2BCBF6 95581718 3995497C EA956AE5 15D22618 98FA0510",
+    "15728E5A 8AAAC42D AD33170D 04507A33 A85521AB DF1CBA64",
+    "ECFB8504 58DBEF0A 8AEA7157 5D060C7D B3970F85 A6E1E4C7",
+    "ABF5AE8C DB0933D7 1E8C94E0 4A25619D CEE3D226 1AD2EE6B",
+    "F12FFA06 D98A0864 D8760273 3EC86A64 521F2B18 177B200C",
+    "BBE11757 7A615D6C 770988C0 BAD946E2 08E24FA0 74E5AB31",
+    "43DB5BFC E0FD108E 4B82D120 A9210801 1A723C12 A787E6D7",
+    "88719A10 BDBA5B26 99C32718 6AF4E23C 1A946834 B6150BDA",
+    "2583E9CA 2AD44CE8 DBBBC2DB 04DE8EF9 2E8EFC14 1FBECAA6",
+    "287C5947 4E6BC05D 99B2964F A090C3A2 233BA186 515BE7ED",
+    "1F612970 CEE2D7AF B81BDD76 2170481C D0069127 D5B05AA9",
+    "93B4EA98 8D8FDDC1 86FFB7DC 90A6C08F 4DF435C9 34063199",
+    "FFFFFFFF FFFFFFFF",
+];
+
+// [RFC3526 § 7](https://datatracker.ietf.org/doc/html/rfc3526#section-7) 8192-bit MODP Group
+const GROUP18_PRIME: &[&str] = &[
+    "FFFFFFFF FFFFFFFF C90FDAA2 2168C234 C4C6628B 80DC1CD1",
+    "29024E08 8A67CC74 020BBEA6 3B139B22 514A0879 8E3404DD",
+    "EF9519B3 CD3A431B 302B0A6D F25F1437 4FE1356D 6D51C245",
+    "E485B576 625E7EC6 F44C42E9 A637ED6B 0BFF5CB6 F406B7ED",
+    "EE386BFB 5A899FA5 AE9F2411 7C4B1FE6 49286651 ECE45B3D",
+    "C2007CB8 A163BF05 98DA4836 1C55D39A 69163FA8 FD24CF5F",
+    "83655D23 DCA3AD96 1C62F356 208552BB 9ED52907 7096966D",
+    "670C354E 4ABC9804 F1746C08 CA18217C 32905E46 2E36CE3B",
+    "E39E772C 180E8603 9B2783A2 EC07A28F B5C55DF0 6F4C52C9",
+    "DE2BCBF6 95581718 3995497C EA956AE5 15D22618 98FA0510",
+    "15728E5A 8AAAC42D AD33170D 04507A33 A85521AB DF1CBA64",
+    "ECFB8504 58DBEF0A 8AEA7157 5D060C7D B3970F85 A6E1E4C7",
+    "ABF5AE8C DB0933D7 1E8C94E0 4A25619D CEE3D226 1AD2EE6B",
+    "F12FFA06 D98A0864 D8760273 3EC86A64 521F2B18 177B200C",
+    "BBE11757 7A615D6C 770988C0 BAD946E2 08E24FA0 74E5AB31",
+    "43DB5BFC E0FD108E 4B82D120 A9210801 1A723C12 A787E6D7",
+    "88719A10 BDBA5B26 99C32718 6AF4E23C 1A946834 B6150BDA",
+    "2583E9CA 2AD44CE8 DBBBC2DB 04DE8EF9 2E8EFC14 1FBECAA6",
+    "287C5947 4E6BC05D 99B2964F A090C3A2 233BA186 515BE7ED",
+    "1F612970 CEE2D7AF B81BDD76 2170481C D0069127 D5B05AA9",
+    "93B4EA98 8D8FDDC1 86FFB7DC 90A6C08F 4DF435C9 34028492",
+    "36C3FAB4 D27C7026 C1D4DCB2 602646DE C9751E76 3DBA37BD",
+    "F8FF9406 AD9E530E E5DB382F 413001AE B06A53ED 9027D831",
+    "179727B0 865A8918 DA3EDBEB CF9B14ED 44CE6CBA CED4BB1B",
+    "DB7F1447 E6CC254B 33205151 2BD7AF42 6FB8F401 378CD2BF",
+    "5983CA01 C64B92EC F032EA15 D1721D03 F482D7CE 6E74FEF6",
+    "D55E702F 46980C82 B5A84031 900B1C9E 59E7C97F BEC7E8F3",
+    "23A97A7E 36CC88BE 0F1D45B7 FF585AC5 4BD407B2 2B4154AA",
+    "CC8F6D7E BF48E1D8 14CC5ED2 0F8037E0 A79715EE F29BE328",
+    "06A1D58B B7C5DA76 F550AA3D 8A1FBFF0 EB19CCB1 A313D55C",
+    "DA56C9EC 2EF29632 387FE8D7 6E3C0468 043E8F66 3F4860EE",
+    "12BF2D5B 0B7474D6 E694F91E 6DBE1159 74A3926F 12FEE5E4",
+    "38777CB6 A932DF8C D8BEC4D0 73B931BA 3BC832B6 8D9DD300",
+    "741FA7BF 8AFC47ED 2576F693 6BA42466 3AAB639C 5AE4F568",
+    "3423B474 2BF1C978 238F16CB E39D652D E3FDB8BE FC848AD9",
+    "22222E04 A4037C07 13EB57A8 1A23F0C7 3473FC64 6CEA306B",
+    "4BCBC886 2F8385DD FA9D4B7F A2C087E8 79683303 ED5BDD3A",
+    "062B3CF5 B3A278A6 6D2A13F8 3F44F82D DF310EE0 74AB6A36",
+    "4597E899 A0255DC1 64F31CC5 0846851D F9AB4819 5DED7EA1",
+    "B1D510BD 7EE74D73 FAF36BC3 1ECFA268 359046F4 EB879F92",
+    "4009438B 481C6CD7 889A002E D5EE382B C9190DA6 FC026E47",
+    "9558E447 5677E9AA 9E3050E2 765694DF C81F56E8 80B96E71",
+    "60C980DD 98EDD3DF FFFFFFFF FFFFFFFF",
+];
+
+#[test]
+fn dh_group14_agreement() {
+    let mut client = DiffieHellmanGroup14Sha256::new();
+    let mut server = DiffieHellmanGroup14Sha256::new();
+    assert!(client.public_key().len() <= 256);
+
+    let client_secret = client.shared_secret(&server.public_key()).unwrap();
+    let server_secret = server.shared_secret(&client.public_key()).unwrap();
+    assert_eq!(client_secret, server_secret);
+
+    // a leading zero from the mpint encoding does not change the value
+    let mut f = vec![0];
+    f.extend(server.public_key());
+    assert_eq!(client.shared_secret(&f).unwrap(), client_secret);
+
+    assert!(client.shared_secret(&[1]).is_err());
+    assert!(client
+        .shared_secret(&(Group14::prime() - 1u32).to_bytes_be())
+        .is_err());
 }
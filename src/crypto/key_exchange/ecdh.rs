@@ -0,0 +1,91 @@
+use super::KexMethodAdapter;
+use crate::protocol::error::{Result, SshError};
+use p256::elliptic_curve::{
+    ecdh::EphemeralSecret,
+    sec1::{FromEncodedPoint, ModulusSize, ToEncodedPoint},
+    AffinePoint, CurveArithmetic, FieldBytesSize, PublicKey,
+};
+use p256::NistP256;
+use p384::NistP384;
+use p521::NistP521;
+use rand_core::OsRng;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::marker::PhantomData;
+
+// [RFC5656 § 6.2.1](https://datatracker.ietf.org/doc/html/rfc5656#section-6.2.1)
+// The hash follows the size of the curve.
+pub type EcdhSha2Nistp256 = EcdhSha2<NistP256, Sha256>;
+pub type EcdhSha2Nistp384 = EcdhSha2<NistP384, Sha384>;
+pub type EcdhSha2Nistp521 = EcdhSha2<NistP521, Sha512>;
+
+// SEC1 tag of an uncompressed point, 0x04 || x || y
+const UNCOMPRESSED_POINT: u8 = 0x04;
+
+// [RFC5656 § 4](https://datatracker.ietf.org/doc/html/rfc5656#section-4)
+// Q_C and Q_S are uncompressed SEC1 points sent as string,
+// K is the x coordinate of the shared point.
+pub struct EcdhSha2<C: CurveArithmetic, D> {
+    private_key: Option<EphemeralSecret<C>>,
+    public_key: PublicKey<C>,
+    _digest: PhantomData<D>,
+}
+
+impl<C, D> KexMethodAdapter for EcdhSha2<C, D>
+where
+    C: CurveArithmetic,
+    FieldBytesSize<C>: ModulusSize,
+    AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+    D: Digest,
+{
+    fn new() -> Self {
+        let private_key = EphemeralSecret::random(&mut OsRng);
+        let public_key = private_key.public_key();
+        EcdhSha2 {
+            private_key: Some(private_key),
+            public_key,
+            _digest: PhantomData,
+        }
+    }
+    fn public_key(&self) -> Vec<u8> {
+        self.public_key.to_encoded_point(false).as_bytes().to_vec()
+    }
+    fn shared_secret(&mut self, public_key: &[u8]) -> Result<Vec<u8>> {
+        // [RFC5656 § 4](https://datatracker.ietf.org/doc/html/rfc5656#section-4)
+        // Q_S must be a valid point on the curve and not the point at infinity.
+        if public_key.first() != Some(&UNCOMPRESSED_POINT) {
+            return Err(SshError::InvalidPublicValue("ECDH public key".to_string()));
+        }
+        let public_key = PublicKey::<C>::from_sec1_bytes(public_key)
+            .map_err(|_| SshError::InvalidPublicValue("ECDH public key".to_string()))?;
+        let private_key = self
+            .private_key
+            .take()
+            .ok_or_else(|| SshError::Protocol("ECDH private key already used".to_string()))?;
+        let shared_secret = private_key.diffie_hellman(&public_key);
+        Ok(shared_secret.raw_secret_bytes().to_vec())
+    }
+    fn hash(&self, seed: &[u8]) -> Vec<u8> {
+        D::digest(seed).to_vec()
+    }
+}
+
+#[test]
+fn ecdh_nistp_agreement() {
+    fn agree<Method: KexMethodAdapter>(point_size: usize) {
+        let mut client = Method::new();
+        let mut server = Method::new();
+        assert_eq!(client.public_key().len(), point_size);
+
+        let client_secret = client.shared_secret(&server.public_key()).unwrap();
+        let server_secret = server.shared_secret(&client.public_key()).unwrap();
+        assert_eq!(client_secret, server_secret);
+
+        // a point that is not on the curve is refused
+        let mut point = Method::new().public_key();
+        *point.last_mut().unwrap() ^= 1;
+        assert!(Method::new().shared_secret(&point).is_err());
+    }
+    agree::<EcdhSha2Nistp256>(65);
+    agree::<EcdhSha2Nistp384>(97);
+    agree::<EcdhSha2Nistp521>(133);
+}
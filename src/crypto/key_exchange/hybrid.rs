@@ -0,0 +1,161 @@
+use super::{curve::Curve25519Sha256, KexMethodAdapter};
+use crate::protocol::{
+    data::{ByteString, DataType},
+    error::{Result, SshError},
+};
+use ml_kem::{kem::Decapsulate, EncodedSizeUser, KemCore};
+use rand::Rng;
+use rand_core::OsRng;
+use sha2::{Digest, Sha256, Sha512};
+use std::marker::PhantomData;
+
+// [draft-ietf-sshm-mlkem-hybrid-kex](https://datatracker.ietf.org/doc/html/draft-ietf-sshm-mlkem-hybrid-kex)
+// [draft-josefsson-ntruprime-ssh](https://datatracker.ietf.org/doc/html/draft-josefsson-ntruprime-ssh)
+pub type MlKem768X25519Sha256 = Hybrid<MlKem768, Sha256>;
+pub type Sntrup761X25519Sha512 = Hybrid<Sntrup761, Sha512>;
+
+// Post-quantum KEM half of a hybrid method. The client generates the key pair
+// and decapsulates the server's ciphertext.
+pub trait Kem {
+    const CIPHERTEXT_SIZE: usize;
+    type DecapsulationKey;
+
+    fn generate() -> (Self::DecapsulationKey, Vec<u8>);
+    fn decapsulate(key: &Self::DecapsulationKey, ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub struct MlKem768;
+pub struct Sntrup761;
+
+impl Kem for MlKem768 {
+    const CIPHERTEXT_SIZE: usize = 1088;
+    type DecapsulationKey = <ml_kem::MlKem768 as KemCore>::DecapsulationKey;
+
+    fn generate() -> (Self::DecapsulationKey, Vec<u8>) {
+        let (private_key, public_key) = ml_kem::MlKem768::generate(&mut OsRng);
+        (private_key, public_key.as_bytes().to_vec())
+    }
+    fn decapsulate(key: &Self::DecapsulationKey, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let ciphertext = ml_kem::Ciphertext::<ml_kem::MlKem768>::try_from(ciphertext)
+            .map_err(|_| SshError::InvalidPublicValue("ML-KEM ciphertext".to_string()))?;
+        let shared_secret = key
+            .decapsulate(&ciphertext)
+            .map_err(|_| SshError::Crypto("ML-KEM decapsulation failed".to_string()))?;
+        Ok(shared_secret.to_vec())
+    }
+}
+
+impl Kem for Sntrup761 {
+    const CIPHERTEXT_SIZE: usize = sntrup761::CIPHERTEXT_SIZE;
+    type DecapsulationKey = sntrup761::DecapsulationKey;
+
+    fn generate() -> (Self::DecapsulationKey, Vec<u8>) {
+        let (public_key, private_key) = sntrup761::generate_key_from_seed(rand::thread_rng().gen());
+        (private_key, public_key.as_ref().to_vec())
+    }
+    fn decapsulate(key: &Self::DecapsulationKey, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let ciphertext = sntrup761::Ciphertext::try_from(ciphertext)
+            .map_err(|_| SshError::InvalidPublicValue("sntrup761 ciphertext".to_string()))?;
+        Ok(key.decapsulate(&ciphertext).as_ref().to_vec())
+    }
+}
+
+// C_INIT  = C_PK2 || C_PK1, KEM public key and X25519 public key
+// S_REPLY = S_CT2 || S_PK1, KEM ciphertext and X25519 public key
+// K       = HASH(K_PQ || K_CL)
+//
+// C_INIT and S_REPLY are sent in place of Q_C and Q_S. K is already a hash
+// output and is encoded as string instead of mpint.
+pub struct Hybrid<K: Kem, D> {
+    kem_private_key: K::DecapsulationKey,
+    kem_public_key: Vec<u8>,
+    curve: Curve25519Sha256,
+    _digest: PhantomData<D>,
+}
+
+impl<K: Kem, D: Digest> KexMethodAdapter for Hybrid<K, D> {
+    fn new() -> Self {
+        let (kem_private_key, kem_public_key) = K::generate();
+        Hybrid {
+            kem_private_key,
+            kem_public_key,
+            curve: Curve25519Sha256::new(),
+            _digest: PhantomData,
+        }
+    }
+    fn public_key(&self) -> Vec<u8> {
+        let mut public_key = self.kem_public_key.clone();
+        public_key.extend(self.curve.public_key());
+        public_key
+    }
+    fn encode_shared_secret(&self, shared_secret: &[u8], buf: &mut Vec<u8>) {
+        ByteString(shared_secret.to_vec()).encode(buf)
+    }
+    fn shared_secret(&mut self, public_key: &[u8]) -> Result<Vec<u8>> {
+        if public_key.len() != K::CIPHERTEXT_SIZE + 32 {
+            return Err(SshError::InvalidPublicValue(
+                "hybrid key exchange reply".to_string(),
+            ));
+        }
+        let (ciphertext, curve_public_key) = public_key.split_at(K::CIPHERTEXT_SIZE);
+        let mut shared_secret = K::decapsulate(&self.kem_private_key, ciphertext)?;
+        let curve_shared_secret = self.curve.shared_secret(curve_public_key)?;
+        shared_secret.extend(curve_shared_secret);
+        Ok(D::digest(&shared_secret).to_vec())
+    }
+    fn hash(&self, seed: &[u8]) -> Vec<u8> {
+        D::digest(seed).to_vec()
+    }
+}
+
+#[test]
+fn hybrid_agreement() {
+    use ml_kem::kem::Encapsulate;
+    use x25519_dalek::{EphemeralSecret, PublicKey};
+
+    // server side: encapsulate to C_PK2 and answer with a fresh X25519 key
+    fn reply(kem_secret: &[u8], ciphertext: &[u8], client_x25519: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let mut s_reply = ciphertext.to_vec();
+        s_reply.extend(PublicKey::from(&secret).as_bytes());
+        let client_x25519: [u8; 32] = client_x25519.try_into().unwrap();
+        let mut k = kem_secret.to_vec();
+        k.extend(
+            secret
+                .diffie_hellman(&PublicKey::from(client_x25519))
+                .as_bytes(),
+        );
+        (s_reply, k)
+    }
+
+    let mut client = MlKem768X25519Sha256::new();
+    let c_init = client.public_key();
+    assert_eq!(c_init.len(), 1184 + 32);
+    let (c_pk2, c_pk1) = c_init.split_at(1184);
+    let key =
+        <ml_kem::MlKem768 as KemCore>::EncapsulationKey::from_bytes(&c_pk2.try_into().unwrap());
+    let (ciphertext, kem_secret) = key.encapsulate(&mut OsRng).unwrap();
+    let (s_reply, k) = reply(&kem_secret, &ciphertext, c_pk1);
+    assert_eq!(
+        client.shared_secret(&s_reply).unwrap(),
+        Sha256::digest(&k).to_vec()
+    );
+
+    let mut client = Sntrup761X25519Sha512::new();
+    let c_init = client.public_key();
+    assert_eq!(c_init.len(), sntrup761::PUBLIC_KEY_SIZE + 32);
+    let (c_pk2, c_pk1) = c_init.split_at(sntrup761::PUBLIC_KEY_SIZE);
+    let key = sntrup761::EncapsulationKey::try_from(c_pk2).unwrap();
+    let (ciphertext, kem_secret) = key.encapsulate_deterministic(rand::thread_rng().gen());
+    let (s_reply, k) = reply(kem_secret.as_ref(), ciphertext.as_ref(), c_pk1);
+    assert_eq!(
+        client.shared_secret(&s_reply).unwrap(),
+        Sha512::digest(&k).to_vec()
+    );
+    assert!(client.shared_secret(&s_reply[1..]).is_err());
+
+    // K is hashed as a string
+    let mut encoded = Vec::new();
+    client.encode_shared_secret(&[0x80; 64], &mut encoded);
+    assert_eq!(&encoded[..4], &[0, 0, 0, 64]);
+}
@@ -0,0 +1,150 @@
+use rand::seq::SliceRandom;
+use rsa::BigUint;
+use std::path::Path;
+
+// Where OpenSSH's sshd looks for the groups it offers in group exchange.
+pub const MODULI_PATH: &str = "/etc/ssh/moduli";
+
+// type: safe prime, (p - 1) / 2 is also prime
+const MODULI_TYPE_SAFE: u32 = 2;
+// tests: bit mask of the tests run on the prime
+const MODULI_TESTS_COMPOSITE: u32 = 0x01;
+const MODULI_TESTS_SIEVE: u32 = 0x02;
+
+// One group from a moduli(5) file.
+#[derive(Debug, Clone)]
+pub struct Modulus {
+    pub bits: usize,
+    pub generator: BigUint,
+    pub prime: BigUint,
+}
+
+// Parses an OpenSSH moduli file, one group per line:
+//
+//   timestamp type tests tries size generator modulus
+//
+// size is the bit length of the prime minus one, generator is decimal and modulus hex.
+// Like sshd, entries that are not safe primes or were not properly tested are skipped.
+pub fn parse(text: &str) -> Vec<Modulus> {
+    text.lines().filter_map(parse_line).collect()
+}
+
+pub fn read(path: impl AsRef<Path>) -> std::io::Result<Vec<Modulus>> {
+    Ok(parse(&std::fs::read_to_string(path)?))
+}
+
+fn parse_line(line: &str) -> Option<Modulus> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [_timestamp, kind, tests, tries, size, generator, modulus] = fields[..] else {
+        return None;
+    };
+
+    let kind: u32 = kind.parse().ok()?;
+    let tests: u32 = tests.parse().ok()?;
+    let tries: u32 = tries.parse().ok()?;
+    if kind != MODULI_TYPE_SAFE
+        || tests & MODULI_TESTS_COMPOSITE != 0
+        || tests & !MODULI_TESTS_SIEVE == 0
+        || tries == 0
+    {
+        return None;
+    }
+
+    // the whole group is one bit larger than the recorded size
+    let bits = size.parse::<usize>().ok()? + 1;
+    let generator = BigUint::parse_bytes(generator.as_bytes(), 10)?;
+    let prime = BigUint::parse_bytes(modulus.as_bytes(), 16)?;
+    if prime.bits() != bits || generator <= BigUint::from(1u32) {
+        return None;
+    }
+    Some(Modulus {
+        bits,
+        generator,
+        prime,
+    })
+}
+
+// Picks a group for SSH_MSG_KEX_DH_GEX_REQUEST the way sshd does: the smallest
+// group of at least `preferred` bits, otherwise the largest one below it, and
+// a random one of that size when there are several. None when no group lies
+// within min..=max, in which case a server falls back to a fixed group.
+pub fn choose(moduli: &[Modulus], min: u32, preferred: u32, max: u32) -> Option<&Modulus> {
+    let (min, preferred, max) = (min as usize, preferred as usize, max as usize);
+    let candidates: Vec<&Modulus> = moduli
+        .iter()
+        .filter(|m| (min..=max).contains(&m.bits))
+        .collect();
+    let best = candidates.iter().fold(0, |best, m| {
+        if (m.bits > preferred && m.bits < best) || (m.bits > best && best < preferred) {
+            m.bits
+        } else {
+            best
+        }
+    });
+    let best: Vec<&Modulus> = candidates.into_iter().filter(|m| m.bits == best).collect();
+    best.choose(&mut rand::thread_rng()).copied()
+}
+
+#[test]
+fn group_exchange_from_moduli_file() {
+    use super::dh::{DiffieHellmanGroupExchangeSha256, Group, Group1, Group14, Group16};
+    use super::KexMethodAdapter;
+    use crate::protocol::error::SshError;
+
+    let hex = |prime: &[&str]| prime.concat().replace(' ', "");
+    let moduli = parse(&format!(
+        "# Time Type Tests Tries Size Generator Modulus\n\
+         20240101000000 2 6 100 1023 2 {}\n\
+         20240101000000 2 6 100 2047 2 {}\n\
+         20240101000000 2 6 100 4095 5 {}\n\
+         20240101000000 2 1 100 2047 2 {}\n\
+         20240101000000 2 6 100 2047 5 {}\n",
+        hex(Group1::PRIME),
+        hex(Group14::PRIME),
+        hex(Group16::PRIME),
+        hex(Group14::PRIME),
+        hex(Group16::PRIME),
+    ));
+    // the composite entry and the one with the wrong size are skipped
+    assert_eq!(
+        moduli.iter().map(|m| m.bits).collect::<Vec<_>>(),
+        [1024, 2048, 4096]
+    );
+
+    assert_eq!(choose(&moduli, 2048, 3072, 8192).unwrap().bits, 4096);
+    assert_eq!(choose(&moduli, 2048, 2048, 8192).unwrap().bits, 2048);
+    assert_eq!(choose(&moduli, 1024, 8192, 8192).unwrap().bits, 4096);
+    assert!(choose(&moduli, 6144, 8192, 8192).is_none());
+
+    // server side: the chosen group is sent as mpint p, mpint g
+    let group = choose(&moduli, 2048, 2048, 8192).unwrap();
+    let mut client = DiffieHellmanGroupExchangeSha256::new();
+    let mut server = DiffieHellmanGroupExchangeSha256::new();
+    client
+        .set_group(&group.prime.to_bytes_be(), &group.generator.to_bytes_be())
+        .unwrap();
+    server
+        .set_group(&group.prime.to_bytes_be(), &group.generator.to_bytes_be())
+        .unwrap();
+    assert_eq!(
+        client.shared_secret(&server.public_key()).unwrap(),
+        server.shared_secret(&client.public_key()).unwrap()
+    );
+
+    // a group smaller than the requested minimum is refused
+    let small = &moduli[0];
+    let e = client
+        .set_group(&small.prime.to_bytes_be(), &small.generator.to_bytes_be())
+        .unwrap_err();
+    assert!(matches!(e, SshError::DhGexOutOfRange));
+
+    // as is a modulus that is not a safe prime
+    let not_safe = Group14::prime() + 2u32;
+    assert!(client.set_group(&not_safe.to_bytes_be(), &[2]).is_err());
+    let composite = Group14::prime() + 4u32;
+    assert!(client.set_group(&composite.to_bytes_be(), &[2]).is_err());
+}
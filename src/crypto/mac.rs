@@ -1,5 +1,4 @@
-use strum_macros::{AsRefStr, EnumString};
-
+pub mod etm;
 pub mod hmac;
 pub mod none;
 
@@ -16,15 +15,8 @@ pub trait MACAdapter {
     where
         Self: Sized;
     fn sign(&self, msg: &[u8]) -> Vec<u8>;
-}
-
-/// MAC(message authentication code) algorithm
-#[derive(Copy, Clone, PartialEq, Eq, AsRefStr, EnumString)]
-pub enum Mac {
-    #[strum(serialize = "hmac-sha1")]
-    HmacSha1,
-    #[strum(serialize = "hmac-sha2-256")]
-    HmacSha2_256,
-    #[strum(serialize = "hmac-sha2-512")]
-    HmacSha2_512,
+    // encrypt-then-MAC: the MAC is computed over the ciphertext instead of the plaintext
+    fn is_etm(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,22 @@
+use super::MACAdapter;
+
+// *-etm@openssh.com
+// mac = MAC(key, sequence_number || packet_length || encrypted_packet)
+pub struct Etm<M> {
+    mac: M,
+}
+
+impl<M: MACAdapter> MACAdapter for Etm<M> {
+    fn size(&self) -> usize {
+        self.mac.size()
+    }
+    fn new(key: Vec<u8>) -> Self {
+        Etm { mac: M::new(key) }
+    }
+    fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        self.mac.sign(msg)
+    }
+    fn is_etm(&self) -> bool {
+        true
+    }
+}
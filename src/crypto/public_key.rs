@@ -1,6 +1,3 @@
-use strum_macros::{AsRefStr, EnumString};
-
-pub mod ecc;
 pub mod rsa;
 
 // ssh-dss           REQUIRED     sign   Raw DSS Key
@@ -16,17 +13,3 @@ pub trait PublicKeyAdapter {
     fn identifier(&self) -> Vec<u8>;
     fn signature(&self) -> Vec<u8>;
 }
-
-/// pubkey hash algorithm
-#[derive(Copy, Clone, PartialEq, Eq, AsRefStr, EnumString)]
-pub enum PubKey {
-    #[strum(serialize = "ssh-ed25519")]
-    SshEd25519,
-    #[cfg(feature = "dangerous-rsa-sha1")]
-    #[strum(serialize = "ssh-rsa")]
-    SshRsa,
-    #[strum(serialize = "rsa-sha2-256")]
-    RsaSha2_256,
-    #[strum(serialize = "rsa-sha2-512")]
-    RsaSha2_512,
-}
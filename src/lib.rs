@@ -0,0 +1,8 @@
+// lets the code derived by sseichi-derive name this crate as ::sseichi
+extern crate self as sseichi;
+
+pub mod config;
+pub mod crypto;
+pub mod network;
+pub mod protocol;
+pub mod utils;
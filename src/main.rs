@@ -1,15 +1,9 @@
-mod config;
-mod crypto;
-mod network;
-mod protocol;
-pub mod utils;
-
-use crate::{
+use sseichi::{
     config::{cli_options, get_config},
-    protocol::client::SessionBuilder,
+    protocol::{client::SessionBuilder, error::Result, known_hosts::HostKeyPolicy},
 };
 
-fn main() -> anyhow::Result<()> {
+fn main() -> Result<()> {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::INFO)
         .init();
@@ -22,6 +16,7 @@ fn main() -> anyhow::Result<()> {
     let mut client = SessionBuilder::create_session()
         .username(&config.username)
         .private_key_path("~/.ssh/id_rsa")
+        .host_key_policy(HostKeyPolicy::Ask)
         .connect(config.remote_address)?;
     let mut client = client.pack_channel();
 
@@ -1,4 +1,4 @@
-use crate::protocol::error::SshError;
+use crate::protocol::error::{Result, SshError};
 use std::io::Read;
 use std::io::Write;
 use std::net::{SocketAddr, TcpStream};
@@ -9,37 +9,28 @@ pub struct TcpClient {
 }
 
 impl TcpClient {
-    pub fn new(address: SocketAddr) -> anyhow::Result<Self> {
+    // A read that waits longer than `timeout` fails, None waits forever.
+    pub fn new(address: SocketAddr, timeout: Option<Duration>) -> Result<Self> {
         tracing::info!("{:?}", address);
-        let client = TcpStream::connect(address).expect("failed to connect server");
-        client.set_nonblocking(false).expect("out of service");
-        client
-            .set_read_timeout(Some(Duration::new(3, 0)))
-            .expect("set_read_timeout call failed");
+        let client = TcpStream::connect(address)?;
+        client.set_nonblocking(false)?;
+        client.set_read_timeout(timeout)?;
         Ok(TcpClient { client })
     }
 
-    pub fn send(&self, response: &[u8]) -> anyhow::Result<()> {
-        let mut socket = self
-            .client
-            .try_clone()
-            .map_err(|_| SshError::RecvError("io".to_string()))?;
-        socket
-            .write_all(response)
-            .map_err(|_| SshError::RecvError("io".to_string()))?;
+    pub fn send(&self, response: &[u8]) -> Result<()> {
+        let mut socket = self.client.try_clone()?;
+        socket.write_all(response)?;
         Ok(())
     }
 
-    pub fn recv(&mut self) -> anyhow::Result<Vec<u8>> {
+    // Returns whatever a single read yields, which may be part of a packet or several packets.
+    pub fn recv(&mut self) -> Result<Vec<u8>> {
         let mut recv_data = [0; 65535];
-        loop {
-            let packet_length = self
-                .client
-                .read(&mut recv_data)
-                .map_err(|_| SshError::RecvError("io".to_string()))?;
-            if packet_length > 0 {
-                return Ok(recv_data[..packet_length].to_vec());
-            }
+        let packet_length = self.client.read(&mut recv_data)?;
+        if packet_length == 0 {
+            return Err(SshError::ConnectionClosed);
         }
+        Ok(recv_data[..packet_length].to_vec())
     }
 }
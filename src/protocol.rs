@@ -1,10 +1,19 @@
 mod auth;
 mod channel;
 pub mod client;
+pub mod connection;
 pub mod data;
 pub mod error;
+pub mod ext_info;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
 mod key_exchange;
 mod key_exchange_init;
+pub mod known_hosts;
+pub mod packet_reader;
 pub mod session;
 pub mod ssh2;
+#[cfg(test)]
+mod test_server;
+pub mod transport;
 pub mod version_exchange;
@@ -1,14 +1,30 @@
 use std::str::FromStr;
 
-use super::{client::SshClient, data::DataType};
+use super::{
+    client::SshClient,
+    connection::{Connection, Event},
+    data::DataType,
+};
 use crate::{
-    crypto::public_key::rsa::RsaSha256,
+    crypto::{public_key::rsa::RsaKey, PubKey},
     protocol::{
         data::{ByteString, Data, NameList},
+        error::{Result, SshError},
         ssh2::message_code,
     },
 };
 
+// [RFC4252 § 7](https://datatracker.ietf.org/doc/html/rfc4252#section-7)
+//   byte      SSH_MSG_USERAUTH_REQUEST
+//   string    user name
+//   string    service name
+//   string    "publickey"
+//   boolean   TRUE
+//   string    public key algorithm name
+//   string    public key to be used for authentication
+//   string    signature, only when the boolean is TRUE
+#[derive(DataType)]
+#[data(message = message_code::SSH_MSG_USERAUTH_REQUEST)]
 struct PublicKeyAuthenticationMethod {
     username: String,
     service_name: String,
@@ -16,56 +32,22 @@ struct PublicKeyAuthenticationMethod {
     with_authentication: bool,
     publickey_algorithm_name: String,
     publickey_blob: ByteString,
-}
-
-impl DataType for PublicKeyAuthenticationMethod {
-    fn decode(input: &[u8]) -> nom::IResult<&[u8], Self>
-    where
-        Self: Sized,
-    {
-        let (input, username) = <String>::decode(input)?;
-        let (input, service_name) = <String>::decode(input)?;
-        let (input, method_name) = <String>::decode(input)?;
-        let (input, with_authentication) = <bool>::decode(input)?;
-        let (input, publickey_algorithm_name) = <String>::decode(input)?;
-        let (input, publickey_blob) = <ByteString>::decode(input)?;
-
-        Ok((
-            input,
-            PublicKeyAuthenticationMethod {
-                username,
-                service_name,
-                method_name,
-                with_authentication,
-                publickey_algorithm_name,
-                publickey_blob,
-            },
-        ))
-    }
-
-    fn encode(&self, buf: &mut Vec<u8>) {
-        self.username.encode(buf);
-        self.service_name.encode(buf);
-        self.method_name.encode(buf);
-        self.with_authentication.encode(buf);
-        self.publickey_algorithm_name.encode(buf);
-        self.publickey_blob.encode(buf);
-    }
+    #[data(when = with_authentication)]
+    signature: Option<ByteString>,
 }
 
 impl SshClient {
-    pub fn user_auth(&mut self) -> anyhow::Result<()> {
-        self.service_request()?;
-        let service_name: String = self.service_accept()?;
-        println!("service accepted: {}", service_name);
-        self.userauth_request()?;
-        self.userauth_accept()?;
-        println!("userauth accepted");
-
-        Ok(())
+    // Answers Event::AuthPrompt with the RSA key.
+    pub fn user_auth(&mut self) -> Result<()> {
+        let rsa = RsaKey::read_from_file()?;
+        let result = self.connection.userauth_publickey(&rsa);
+        self.flush()?;
+        result
     }
+}
 
-    fn service_request(&mut self) -> anyhow::Result<()> {
+impl Connection {
+    pub(crate) fn service_request(&mut self) -> Result<()> {
         let mut payload = Data::new();
         payload
             .put(&message_code::SSH_MSG_SERVICE_REQUEST)
@@ -73,105 +55,97 @@ impl SshClient {
         self.send(&payload)
     }
 
-    fn service_accept(&mut self) -> anyhow::Result<String> {
-        let mut payload = self.recv()?;
-        payload.expect(message_code::SSH_MSG_SERVICE_ACCEPT);
-        let service_name: String = payload.get();
-        Ok(service_name)
-    }
-
-    fn userauth_request(&mut self) -> anyhow::Result<()> {
-        let rsa = RsaSha256::read_from_file()?;
+    pub fn userauth_publickey(&mut self, rsa: &RsaKey) -> Result<()> {
+        let algorithm = match self
+            .session
+            .ext_info
+            .accepts_signature(PubKey::RsaSha2_512.as_ref())
+        {
+            true => PubKey::RsaSha2_512,
+            false => PubKey::RsaSha2_256,
+        };
 
-        let publickey_method = PublicKeyAuthenticationMethod {
+        let mut publickey_method = PublicKeyAuthenticationMethod {
             username: self.config.username.clone(),
             service_name: self.config.service_name.clone(),
             method_name: "publickey".to_string(),
             with_authentication: true,
-            publickey_algorithm_name: "rsa-sha2-256".to_string(),
+            publickey_algorithm_name: algorithm.to_string(),
             publickey_blob: rsa.public_key_blob(),
+            signature: None,
         };
 
+        // the signature covers the request without it
         let mut data = Data::new();
-        data.put(&ByteString(self.session.get_keys().exchange_hash)) // session identifier
-            .put(&message_code::SSH_MSG_USERAUTH_REQUEST)
+        data.put(&ByteString(self.session.get_keys()?.session_id)) // session identifier
             .put(&publickey_method);
+        publickey_method.signature = Some(rsa.signature_blob(data, algorithm)?);
 
-        let mut payload = Data::new();
-        payload
-            .put(&message_code::SSH_MSG_USERAUTH_REQUEST)
-            .put(&publickey_method)
-            .put(&rsa.signature_blob(data));
-
-        self.send(&payload)
+        self.send(Data::new().put(&publickey_method))
     }
 
-    pub fn userauth_accept(&mut self) -> anyhow::Result<()> {
-        let mut payload = self.recv()?;
-        let message_code: u8 = payload.get();
+    // SSH_MSG_SERVICE_ACCEPT and the user authentication messages (50 to 79)
+    pub(crate) fn handle_user_auth(&mut self, mut payload: Data) -> Result<()> {
+        let message_code: u8 = payload.get()?;
         match message_code {
             message_code::SSH_MSG_SERVICE_ACCEPT => {
-                let service_name: ByteString = payload.get();
-                println!("{:?}", String::from_utf8(service_name.0));
-            }
-            message_code::SSH_MSG_USERAUTH_FAILURE => {
-                let auth: NameList = payload.get();
-                let success: bool = payload.get();
-                println!("auth: {:?}", auth);
-                println!("success: {}", success)
+                let service_name: String = payload.get()?;
+                tracing::debug!("service accepted: {}", service_name);
+                self.push_event(Event::AuthPrompt);
             }
-            message_code::SSH_MSG_USERAUTH_SUCCESS => {}
+            message_code::SSH_MSG_USERAUTH_FAILURE => self.push_event(Event::AuthFailure {
+                methods: payload.get::<NameList>()?,
+                partial_success: payload.get()?,
+            }),
+            message_code::SSH_MSG_USERAUTH_SUCCESS => self.push_event(Event::Authenticated),
             message_code::SSH_MSG_USERAUTH_BANNER => {
-                let message: String = payload.get();
-                let language_tag: String = payload.get();
-                println!("message: {}", message);
-                println!("language: {}", language_tag);
+                let message: String = payload.get()?;
+                let _language_tag: String = payload.get()?;
+                self.push_event(Event::Banner(message));
             }
             message_code::SSH2_MSG_USERAUTH_PK_OK => {
-                let pubkey_algo: String = payload.get();
-                let pubkey_blob: ByteString = payload.get();
-                println!("pubkey: {} {:?}", pubkey_algo, pubkey_blob.0);
-            }
-            _ => {
-                panic!("unexpected message code")
+                let pubkey_algo: String = payload.get()?;
+                let _pubkey_blob: ByteString = payload.get()?;
+                tracing::debug!("public key {} accepted", pubkey_algo);
             }
+            _ => return Err(SshError::UnexpectedMessage(message_code)),
         }
         Ok(())
     }
 
-    // fn user_request_recv(&mut self) -> anyhow::Result<()> {
+    // fn user_request_recv(&mut self) -> Result<()> {
     //     let mut payload = self.recv()?;
-    //     let message_code: u8 = payload.get();
+    //     let message_code: u8 = payload.get()?;
     //     match message_code {
     //         message_code::SSH_MSG_SERVICE_REQUEST => {
-    //             let service_name: String = payload.get();
+    //             let service_name: String = payload.get()?;
     //         }
     //         message_code::SSH_MSG_USERAUTH_REQUEST => {
-    //             let user_name: String = payload.get();
-    //             let service_name: String = payload.get();
-    //             let method_name: String = payload.get();
+    //             let user_name: String = payload.get()?;
+    //             let service_name: String = payload.get()?;
+    //             let method_name: String = payload.get()?;
     //             match method_name.as_str() {
     //                 "publickey" => {
-    //                     let is_signature: bool = payload.get();
-    //                     let pubkey_algo: String = payload.get();
-    //                     let pubkey_blob: ByteString = payload.get();
-    //                     let signature: ByteString = payload.get();
+    //                     let is_signature: bool = payload.get()?;
+    //                     let pubkey_algo: String = payload.get()?;
+    //                     let pubkey_blob: ByteString = payload.get()?;
+    //                     let signature: ByteString = payload.get()?;
     //                 }
     //                 "password" => {
-    //                     let is_first: bool = payload.get();
+    //                     let is_first: bool = payload.get()?;
     //                     if is_first {
-    //                         let password: String = payload.get();
+    //                         let password: String = payload.get()?;
     //                     } else {
-    //                         let old_password: String = payload.get();
-    //                         let new_password: String = payload.get();
+    //                         let old_password: String = payload.get()?;
+    //                         let new_password: String = payload.get()?;
     //                     }
     //                 }
     //                 "hostbased" => {
-    //                     let host_pubkey_algo: String = payload.get();
-    //                     let host_pubkey_cert: ByteString = payload.get();
-    //                     let hostname: String = payload.get();
-    //                     let username: String = payload.get();
-    //                     let signature: ByteString = payload.get();
+    //                     let host_pubkey_algo: String = payload.get()?;
+    //                     let host_pubkey_cert: ByteString = payload.get()?;
+    //                     let hostname: String = payload.get()?;
+    //                     let username: String = payload.get()?;
+    //                     let signature: ByteString = payload.get()?;
     //                 }
     //                 "none" => {
     //                     panic!("none auth");
@@ -186,13 +160,13 @@ impl SshClient {
     //     Ok(())
     // match message_code {
     //     message_code::SSH_MSG_DISCONNECT => {
-    //         let disconnect_code: u32 = payload.get();
-    //         let description: String = payload.get();
-    //         let language_tag: String = payload.get();
+    //         let disconnect_code: u32 = payload.get()?;
+    //         let description: String = payload.get()?;
+    //         let language_tag: String = payload.get()?;
     //     }
     //     message_code::SSH2_MSG_USERAUTH_PASSWD_CHANGEREQ => {
-    //         let prompt: String = payload.get();
-    //         let language_tag: String = payload.get();
+    //         let prompt: String = payload.get()?;
+    //         let language_tag: String = payload.get()?;
     //     }
     //     _ => {
     //         panic!("unexpected message code")
@@ -200,3 +174,60 @@ impl SshClient {
     // }
     // }
 }
+
+#[test]
+fn publickey_request_round_trip() {
+    let mut request = PublicKeyAuthenticationMethod {
+        username: "anko".to_string(),
+        service_name: "ssh-connection".to_string(),
+        method_name: "publickey".to_string(),
+        with_authentication: true,
+        publickey_algorithm_name: "rsa-sha2-256".to_string(),
+        publickey_blob: ByteString(vec![1, 2, 3]),
+        signature: Some(ByteString(vec![4, 5])),
+    };
+    let mut payload = Data::new();
+    payload.put(&request);
+    assert_eq!(payload.0[0], message_code::SSH_MSG_USERAUTH_REQUEST);
+    assert_eq!(payload.0[payload.0.len() - 6..], [0, 0, 0, 2, 4, 5]);
+    let decoded: PublicKeyAuthenticationMethod = payload.clone().get().unwrap();
+    assert_eq!(decoded.username, "anko");
+    assert_eq!(decoded.publickey_blob.0, [1, 2, 3]);
+    assert_eq!(decoded.signature.unwrap().0, [4, 5]);
+
+    // without authentication the request ends after the public key
+    request.with_authentication = false;
+    request.signature = None;
+    let mut payload = Data::new();
+    payload.put(&request).put(&7u8);
+    let decoded: PublicKeyAuthenticationMethod = payload.get().unwrap();
+    assert!(decoded.signature.is_none());
+    assert_eq!(payload.into_inner(), [7]);
+
+    // another message number is not a userauth request
+    let mut payload = Data(vec![message_code::SSH_MSG_USERAUTH_FAILURE]);
+    assert!(payload.get::<PublicKeyAuthenticationMethod>().is_err());
+}
+
+#[test]
+fn publickey_needs_keys() {
+    use super::test_server::TestServer;
+    use crate::crypto::public_key::rsa::TEST_RSA_KEY;
+
+    // there is no session identifier to sign before the first NEWKEYS
+    let key = RsaKey::from_openssh(TEST_RSA_KEY).unwrap();
+    let mut connection = TestServer::client();
+    assert!(matches!(
+        connection.userauth_publickey(&key),
+        Err(SshError::Protocol(_))
+    ));
+    assert!(connection.poll_transmit().is_none());
+
+    let mut server = TestServer::new();
+    let mut connection = server.connect();
+    connection.userauth_publickey(&key).unwrap();
+    assert_eq!(
+        server.codes(&mut connection),
+        [message_code::SSH_MSG_USERAUTH_REQUEST]
+    );
+}
@@ -1,4 +1,10 @@
-use crate::protocol::{client::SshClient, data::Data, ssh2::message_code};
+use crate::protocol::{
+    client::SshClient,
+    connection::Event,
+    data::{ByteString, Data},
+    error::{Result, SshError},
+    ssh2::message_code,
+};
 
 pub struct Channel<'a> {
     client: &'a mut SshClient,
@@ -10,28 +16,46 @@ pub struct Channel<'a> {
 }
 
 impl<'a> Channel<'a> {
-    pub fn recv(&mut self) -> anyhow::Result<(u8, Data)> {
-        let mut payload = self.client.recv()?;
-        let message_code: u8 = payload.get();
-        println!("message code: {}", message_code);
+    pub fn recv(&mut self) -> Result<(u8, Data)> {
+        let mut payload = match self.client.next_event()? {
+            Event::Message(payload) => payload,
+            // furiwake takes channel data as it was on the wire
+            Event::ChannelData {
+                recipient_channel,
+                data,
+            } => {
+                let mut payload = Data::new();
+                payload
+                    .put(&message_code::SSH_MSG_CHANNEL_DATA)
+                    .put(&recipient_channel)
+                    .put(&ByteString(data));
+                payload
+            }
+            Event::Disconnected {
+                reason_code,
+                description,
+            } => {
+                return Err(SshError::Disconnected {
+                    reason_code,
+                    description,
+                })
+            }
+            event => return Err(SshError::Protocol(format!("unexpected {:?}", event))),
+        };
+        let message_code: u8 = payload.get()?;
+        tracing::debug!("message code: {}", message_code);
         Ok((message_code, payload))
     }
 
-    pub fn send(&mut self, packet: &Data) -> anyhow::Result<()> {
+    pub fn send(&mut self, packet: &Data) -> Result<()> {
         self.client.send(packet)
     }
 
-    pub fn client_setup(&mut self) -> anyhow::Result<()> {
+    pub fn client_setup(&mut self) -> Result<()> {
         let (code, mut payload) = self.recv()?;
         match code {
-            message_code::SSH_MSG_GLOBAL_REQUEST => self.global_request(&mut payload),
-            _ => unimplemented!(),
-        }
-
-        let (code, mut payload) = self.recv()?;
-        match code {
-            message_code::SSH_MSG_DEBUG => self.debug(&mut payload),
-            _ => unimplemented!(),
+            message_code::SSH_MSG_GLOBAL_REQUEST => self.global_request(&mut payload)?,
+            _ => return Err(SshError::UnexpectedMessage(code)),
         }
 
         self.send_channel_open()?;
@@ -39,16 +63,15 @@ impl<'a> Channel<'a> {
         let (code, mut payload) = self.recv()?;
         match code {
             message_code::SSH_MSG_CHANNEL_OPEN_CONFIRMATION => self.furiwake(code, &mut payload)?,
-            _ => unimplemented!(),
+            _ => return Err(SshError::UnexpectedMessage(code)),
         }
 
         Ok(())
     }
 
-    pub fn furiwake(&mut self, message_code: u8, payload: &mut Data) -> anyhow::Result<()> {
+    pub fn furiwake(&mut self, message_code: u8, payload: &mut Data) -> Result<()> {
         match message_code {
-            message_code::SSH_MSG_DEBUG => self.debug(payload),
-            message_code::SSH_MSG_GLOBAL_REQUEST => self.global_request(payload),
+            message_code::SSH_MSG_GLOBAL_REQUEST => self.global_request(payload)?,
             message_code::SSH_MSG_REQUEST_SUCCESS => {
                 // self.recv_message_request_success(&mut payload)
             }
@@ -56,56 +79,54 @@ impl<'a> Channel<'a> {
                 self.channel_open_confirmation(payload)?
             }
             message_code::SSH_MSG_REQUEST_FAILURE => {}
-            message_code::SSH_MSG_CHANNEL_OPEN => self.message_channel_open(payload),
-            message_code::SSH_MSG_CHANNEL_OPEN_FAILURE => self.message_channel_failure(payload),
+            message_code::SSH_MSG_CHANNEL_OPEN => self.message_channel_open(payload)?,
+            message_code::SSH_MSG_CHANNEL_OPEN_FAILURE => self.message_channel_failure(payload)?,
             message_code::SSH_MSG_CHANNEL_WINDOW_ADJUST => {
-                let recipient_channel: u32 = payload.get();
-                let bytes_to_add: u32 = payload.get();
-                println!("server channel: {}", recipient_channel);
-                println!("window adjust: {}", bytes_to_add);
+                let recipient_channel: u32 = payload.get()?;
+                let bytes_to_add: u32 = payload.get()?;
+                tracing::debug!("server channel: {}", recipient_channel);
+                tracing::debug!("window adjust: {}", bytes_to_add);
             }
             message_code::SSH_MSG_CHANNEL_DATA => {
-                let recipient_channel: u32 = payload.get();
-                let data: String = payload.get();
-                println!("server channel: {}", recipient_channel);
-                println!("{}", data);
-                println!("{}", data.chars().last().unwrap())
+                let recipient_channel: u32 = payload.get()?;
+                let data: String = payload.get()?;
+                tracing::debug!("server channel: {}", recipient_channel);
+                tracing::debug!("{}", data);
+                tracing::debug!("{:?}", data.chars().last())
             }
             message_code::SSH_MSG_CHANNEL_EXTENDED_DATA => {
-                let recipient_channel: u32 = payload.get();
-                let data_type_code: u32 = payload.get();
-                let data: String = payload.get();
-                println!("server channel: {}", recipient_channel);
-                println!("data type: {}", data_type_code);
-                println!("{}", data);
+                let recipient_channel: u32 = payload.get()?;
+                let data_type_code: u32 = payload.get()?;
+                let data: String = payload.get()?;
+                tracing::debug!("server channel: {}", recipient_channel);
+                tracing::debug!("data type: {}", data_type_code);
+                tracing::debug!("{}", data);
             }
             message_code::SSH_MSG_CHANNEL_EOF => {
-                let recipient_channel: u32 = payload.get();
-                println!("server channel: {}", recipient_channel);
+                let recipient_channel: u32 = payload.get()?;
+                tracing::debug!("server channel: {}", recipient_channel);
             }
             message_code::SSH_MSG_CHANNEL_CLOSE => {
-                let recipient_channel: u32 = payload.get();
-                println!("server channel: {}", recipient_channel);
+                let recipient_channel: u32 = payload.get()?;
+                tracing::debug!("server channel: {}", recipient_channel);
             }
-            message_code::SSH_MSG_CHANNEL_REQUEST => self.message_channel_request(payload),
+            message_code::SSH_MSG_CHANNEL_REQUEST => self.message_channel_request(payload)?,
             message_code::SSH_MSG_CHANNEL_SUCCESS => {
-                let recipient_channel: u32 = payload.get();
-                println!("server channel: {}", recipient_channel);
+                let recipient_channel: u32 = payload.get()?;
+                tracing::debug!("server channel: {}", recipient_channel);
             }
             message_code::SSH_MSG_CHANNEL_FAILURE => {
-                let recipient_channel: u32 = payload.get();
-                println!("server channel: {}", recipient_channel);
-            }
-            _ => {
-                panic!("unexpected message code")
+                let recipient_channel: u32 = payload.get()?;
+                tracing::debug!("server channel: {}", recipient_channel);
             }
+            _ => return Err(SshError::UnexpectedMessage(message_code)),
         }
         Ok(())
     }
 }
 
 impl SshClient {
-    pub fn pack_channel(&mut self) -> Channel {
+    pub fn pack_channel(&mut self) -> Channel<'_> {
         const BUF_SIZE: u32 = 0x8000;
         const LOCAL_WINDOW_SIZE: u32 = 0x200000;
         Channel {
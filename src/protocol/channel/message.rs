@@ -1,78 +1,77 @@
 use crate::protocol::channel::channel::Channel;
 use crate::protocol::data::{ByteString, Data};
+use crate::protocol::error::{Result, SshError};
 use crate::protocol::ssh2::message_code;
 
 impl<'a> Channel<'a> {
-    pub fn debug(&mut self, payload: &mut Data) {
-        let want_reply: bool = payload.get();
-        let debug: String = payload.get();
-        println!("debug: {}", debug);
-        println!("reply: {}", want_reply);
-    }
-
-    pub fn global_request(&mut self, payload: &mut Data) {
-        let request_name: String = payload.get();
-        let want_reply: bool = payload.get();
-        println!("request: {}, reply: {}", request_name, want_reply);
+    pub fn global_request(&mut self, payload: &mut Data) -> Result<()> {
+        let request_name: String = payload.get()?;
+        let want_reply: bool = payload.get()?;
+        tracing::debug!("request: {}, reply: {}", request_name, want_reply);
         match request_name.as_str() {
             "tcpip-forward" => {
-                let address: String = payload.get();
-                let port: u32 = payload.get();
-                println!("{}:{}", address, port);
+                let address: String = payload.get()?;
+                let port: u32 = payload.get()?;
+                tracing::debug!("{}:{}", address, port);
             }
             "cancel-tcpip-forward" => {
-                let address: String = payload.get();
-                let port: u32 = payload.get();
-                println!("{}:{}", address, port);
+                let address: String = payload.get()?;
+                let port: u32 = payload.get()?;
+                tracing::debug!("{}:{}", address, port);
             }
             "hostkeys-00@openssh.com" => {
                 // inform all keys
-                let hostkey: ByteString = payload.get();
-                let hostkey: ByteString = payload.get();
-                let hostkey: ByteString = payload.get();
+                while !payload.0.is_empty() {
+                    let hostkey: ByteString = payload.get()?;
+                    tracing::debug!("host key: {} bytes", hostkey.0.len());
+                }
             }
             _ => {}
         }
+        Ok(())
     }
 
-    pub fn channel_open_confirmation(&mut self, payload: &mut Data) -> anyhow::Result<()> {
-        let recipient_channel: u32 = payload.get();
-        let sender_channel: u32 = payload.get();
-        let initial_window_size: u32 = payload.get();
-        let maximum_packet_size: u32 = payload.get();
-        println!(
+    pub fn channel_open_confirmation(&mut self, payload: &mut Data) -> Result<()> {
+        let recipient_channel: u32 = payload.get()?;
+        let sender_channel: u32 = payload.get()?;
+        let initial_window_size: u32 = payload.get()?;
+        let maximum_packet_size: u32 = payload.get()?;
+        tracing::debug!(
             "{} {} {} {}",
-            recipient_channel, sender_channel, initial_window_size, maximum_packet_size
+            recipient_channel,
+            sender_channel,
+            initial_window_size,
+            maximum_packet_size
         );
         match self.channel_type.as_str() {
             "session" => {}
             "x11" => {
-                let originator_address: String = payload.get();
-                let originator_port: u32 = payload.get();
-                println!("{}:{}", originator_address, originator_port);
+                let originator_address: String = payload.get()?;
+                let originator_port: u32 = payload.get()?;
+                tracing::debug!("{}:{}", originator_address, originator_port);
             }
             "forwarded-tcpip" => {
-                let address: String = payload.get();
-                let port: u32 = payload.get();
-                let originator_address: String = payload.get();
-                let originator_port: u32 = payload.get();
-                println!("old: {}:{}", address, port);
-                println!("new: {}:{}", originator_address, originator_port);
+                let address: String = payload.get()?;
+                let port: u32 = payload.get()?;
+                let originator_address: String = payload.get()?;
+                let originator_port: u32 = payload.get()?;
+                tracing::debug!("old: {}:{}", address, port);
+                tracing::debug!("new: {}:{}", originator_address, originator_port);
             }
             "direct-tcpip" => {
-                let host: String = payload.get();
-                let port: u32 = payload.get();
-                let originator_address: String = payload.get();
-                let originator_port: u32 = payload.get();
-                println!("old: {}:{}", host, port);
-                println!("new: {}:{}", originator_address, originator_port);
+                let host: String = payload.get()?;
+                let port: u32 = payload.get()?;
+                let originator_address: String = payload.get()?;
+                let originator_port: u32 = payload.get()?;
+                tracing::debug!("old: {}:{}", host, port);
+                tracing::debug!("new: {}:{}", originator_address, originator_port);
             }
             _ => {}
         }
         Ok(())
     }
 
-    pub fn send_channel_open(&mut self) -> anyhow::Result<()> {
+    pub fn send_channel_open(&mut self) -> Result<()> {
         self.send(
             Data::new()
                 .put(&message_code::SSH_MSG_CHANNEL_OPEN)
@@ -83,7 +82,7 @@ impl<'a> Channel<'a> {
         )
     }
 
-    pub fn send_channel_success(&mut self) -> anyhow::Result<()> {
+    pub fn send_channel_success(&mut self) -> Result<()> {
         self.send(
             Data::new()
                 .put(&message_code::SSH_MSG_CHANNEL_SUCCESS)
@@ -91,81 +90,99 @@ impl<'a> Channel<'a> {
         )
     }
 
-    pub fn message_channel_open(&mut self, payload: &mut Data) {
-        let channel_type: String = payload.get();
-        let sender_channel: u32 = payload.get();
-        let initial_window_size: u32 = payload.get();
-        let maximum_packet_size: u32 = payload.get();
-        println!("client channel num: {}", sender_channel);
-        println!("initial window size: {}", initial_window_size);
-        println!("maximum packet size: {}", maximum_packet_size);
+    pub fn message_channel_open(&mut self, payload: &mut Data) -> Result<()> {
+        let channel_type: String = payload.get()?;
+        let sender_channel: u32 = payload.get()?;
+        let initial_window_size: u32 = payload.get()?;
+        let maximum_packet_size: u32 = payload.get()?;
+        tracing::debug!("client channel num: {}", sender_channel);
+        tracing::debug!("initial window size: {}", initial_window_size);
+        tracing::debug!("maximum packet size: {}", maximum_packet_size);
         match channel_type.as_str() {
             "session" => {}
             "x11" => {
-                let originator_address: String = payload.get();
-                let originator_port: u32 = payload.get();
-                println!("{}:{}", originator_address, originator_port);
+                let originator_address: String = payload.get()?;
+                let originator_port: u32 = payload.get()?;
+                tracing::debug!("{}:{}", originator_address, originator_port);
             }
             "forwarded-tcpip" => {
-                let address: String = payload.get();
-                let port: u32 = payload.get();
-                let originator_address: String = payload.get();
-                let originator_port: u32 = payload.get();
-                println!("old: {}:{}", address, port);
-                println!("new: {}:{}", originator_address, originator_port);
+                let address: String = payload.get()?;
+                let port: u32 = payload.get()?;
+                let originator_address: String = payload.get()?;
+                let originator_port: u32 = payload.get()?;
+                tracing::debug!("old: {}:{}", address, port);
+                tracing::debug!("new: {}:{}", originator_address, originator_port);
             }
             "direct-tcpip" => {
-                let host: String = payload.get();
-                let port: u32 = payload.get();
-                let originator_address: String = payload.get();
-                let originator_port: u32 = payload.get();
-                println!("old: {}:{}", host, port);
-                println!("new: {}:{}", originator_address, originator_port);
+                let host: String = payload.get()?;
+                let port: u32 = payload.get()?;
+                let originator_address: String = payload.get()?;
+                let originator_port: u32 = payload.get()?;
+                tracing::debug!("old: {}:{}", host, port);
+                tracing::debug!("new: {}:{}", originator_address, originator_port);
             }
             _ => {}
         }
+        Ok(())
     }
 
-    pub fn message_channel_failure(&mut self, payload: &mut Data) {
-        let recipient_channel: u32 = payload.get();
-        let reason_code: u32 = payload.get();
-        let description: String = payload.get();
-        let language_tag: String = payload.get();
-        println!("server channel: {}", recipient_channel);
-        println!("{} {} {}", reason_code, description, language_tag);
+    // uint32    recipient channel
+    // uint32    reason code
+    // string    description in ISO-10646 UTF-8 encoding [RFC3629]
+    // string    language tag [RFC3066]
+    pub fn message_channel_failure(&mut self, payload: &mut Data) -> Result<()> {
+        let recipient_channel: u32 = payload.get()?;
+        tracing::debug!("server channel: {}", recipient_channel);
+        Err(SshError::ChannelOpenFailure {
+            reason_code: payload.get()?,
+            description: payload.get()?,
+        })
     }
 
-    pub fn message_channel_request(&mut self, payload: &mut Data) {
-        let recipient_channel: u32 = payload.get();
-        let request_type: String = payload.get();
-        let want_reply: bool = payload.get();
-        println!("server channel: {}", recipient_channel);
-        println!("request type: {}", request_type);
-        println!("want_reply: {}", want_reply);
+    pub fn message_channel_request(&mut self, payload: &mut Data) -> Result<()> {
+        let recipient_channel: u32 = payload.get()?;
+        let request_type: String = payload.get()?;
+        let want_reply: bool = payload.get()?;
+        tracing::debug!("server channel: {}", recipient_channel);
+        tracing::debug!("request type: {}", request_type);
+        tracing::debug!("want_reply: {}", want_reply);
+        // [RFC4254 § 6](https://datatracker.ietf.org/doc/html/rfc4254#section-6)
+        // these requests are sent with want reply FALSE
+        if want_reply
+            && matches!(
+                request_type.as_str(),
+                "window-change" | "xon-xoff" | "signal" | "exit-signal"
+            )
+        {
+            return Err(SshError::Protocol(format!(
+                "{} request wants a reply",
+                request_type
+            )));
+        }
         match request_type.as_str() {
             "pty-req" => {
-                let env: String = payload.get();
-                let terminal_width_characters: u32 = payload.get();
-                let terminal_height_rows: u32 = payload.get();
-                let terminal_width_pixels: u32 = payload.get();
-                let terminal_height_pixels: u32 = payload.get();
-                let encoded_terminal_modes: String = payload.get();
-                println!("env: {}", env);
-                println!(
+                let env: String = payload.get()?;
+                let terminal_width_characters: u32 = payload.get()?;
+                let terminal_height_rows: u32 = payload.get()?;
+                let terminal_width_pixels: u32 = payload.get()?;
+                let terminal_height_pixels: u32 = payload.get()?;
+                let encoded_terminal_modes: String = payload.get()?;
+                tracing::debug!("env: {}", env);
+                tracing::debug!(
                     "terminal: ({}, {}, {}, {})",
                     terminal_width_characters,
                     terminal_height_rows,
                     terminal_width_pixels,
                     terminal_height_pixels
                 );
-                println!("terminal mode: {}", encoded_terminal_modes);
+                tracing::debug!("terminal mode: {}", encoded_terminal_modes);
             }
             "x11-req" => {
-                let single_connection: bool = payload.get();
-                let x11_authentication_protocol: String = payload.get();
-                let x11_authentication_cookie: String = payload.get();
-                let x11_screen_number: u32 = payload.get();
-                println!(
+                let single_connection: bool = payload.get()?;
+                let x11_authentication_protocol: String = payload.get()?;
+                let x11_authentication_cookie: String = payload.get()?;
+                let x11_screen_number: u32 = payload.get()?;
+                tracing::debug!(
                     "{} {} {} {}",
                     single_connection,
                     x11_authentication_protocol,
@@ -174,26 +191,25 @@ impl<'a> Channel<'a> {
                 );
             }
             "env" => {
-                let variable_name: String = payload.get();
-                let variable_value: String = payload.get();
-                println!("env: {} = {}", variable_name, variable_value);
+                let variable_name: String = payload.get()?;
+                let variable_value: String = payload.get()?;
+                tracing::debug!("env: {} = {}", variable_name, variable_value);
             }
             "shell" => {}
             "command" => {
-                let command: String = payload.get();
-                println!("command: {}", command);
+                let command: String = payload.get()?;
+                tracing::debug!("command: {}", command);
             }
             "subsystem" => {
-                let subsystem_name: String = payload.get();
-                println!("subsystem: {}", subsystem_name);
+                let subsystem_name: String = payload.get()?;
+                tracing::debug!("subsystem: {}", subsystem_name);
             }
             "window-change" => {
-                assert!(!want_reply);
-                let terminal_width_columns: u32 = payload.get();
-                let terminal_height_rows: u32 = payload.get();
-                let terminal_width_pixels: u32 = payload.get();
-                let terminal_height_pixels: u32 = payload.get();
-                println!(
+                let terminal_width_columns: u32 = payload.get()?;
+                let terminal_height_rows: u32 = payload.get()?;
+                let terminal_width_pixels: u32 = payload.get()?;
+                let terminal_height_pixels: u32 = payload.get()?;
+                tracing::debug!(
                     "terminal: ({}, {}, {}, {})",
                     terminal_width_columns,
                     terminal_height_rows,
@@ -202,36 +218,34 @@ impl<'a> Channel<'a> {
                 );
             }
             "xon-xoff" => {
-                assert!(!want_reply);
-                let client_can_do: bool = payload.get();
-                println!("{}", client_can_do);
+                let client_can_do: bool = payload.get()?;
+                tracing::debug!("xon-xoff: {}", client_can_do);
             }
             "signal" => {
-                assert!(!want_reply);
-                let signal_name: String = payload.get();
-                println!("signal: {}", signal_name);
+                let signal_name: String = payload.get()?;
+                tracing::debug!("signal: {}", signal_name);
             }
             "exit-status" => {
                 // assert!(want_reply == false);
-                let exit_status: u32 = payload.get();
-                println!("exit: {}", exit_status);
+                let exit_status: u32 = payload.get()?;
+                tracing::debug!("exit: {}", exit_status);
             }
             "exit-signal" => {
-                assert!(!want_reply);
-                let signal_name: String = payload.get();
-                let core_dumped: bool = payload.get();
-                let error_message: String = payload.get();
-                let language_tag: String = payload.get();
-                println!("{} {} {}", signal_name, error_message, language_tag);
+                let signal_name: String = payload.get()?;
+                let core_dumped: bool = payload.get()?;
+                let error_message: String = payload.get()?;
+                let language_tag: String = payload.get()?;
+                tracing::debug!("{} {} {}", signal_name, error_message, language_tag);
                 if core_dumped {
-                    println!("core dumped");
+                    tracing::debug!("core dumped");
                 }
             }
             _ => {}
         }
+        Ok(())
     }
 
-    pub fn shell(&mut self) -> anyhow::Result<()> {
+    pub fn shell(&mut self) -> Result<()> {
         let env: String = "".to_string();
         let (columns, rows) = termion::terminal_size()?;
         let (width, height) = termion::terminal_size_pixels()?;
@@ -290,8 +304,8 @@ impl<'a> Channel<'a> {
         Ok(())
     }
 
-    pub fn exec(&mut self, command: String) -> anyhow::Result<()> {
-        println!("exec: {}", command);
+    pub fn exec(&mut self, command: String) -> Result<()> {
+        tracing::debug!("exec: {}", command);
         let mut data = Data::new();
         data.put(&message_code::SSH_MSG_CHANNEL_REQUEST)
             .put(&self.server_channel)
@@ -303,13 +317,13 @@ impl<'a> Channel<'a> {
         let (code, mut payload) = self.recv()?;
         match code {
             message_code::SSH_MSG_CHANNEL_WINDOW_ADJUST => self.furiwake(code, &mut payload)?,
-            _ => unimplemented!(),
+            _ => return Err(SshError::UnexpectedMessage(code)),
         }
 
         let (code, mut payload) = self.recv()?;
         match code {
             message_code::SSH_MSG_CHANNEL_SUCCESS => self.furiwake(code, &mut payload)?,
-            _ => unimplemented!(),
+            _ => return Err(SshError::UnexpectedMessage(code)),
         }
 
         loop {
@@ -317,18 +331,18 @@ impl<'a> Channel<'a> {
             match code {
                 message_code::SSH_MSG_CHANNEL_DATA => self.furiwake(code, &mut payload)?,
                 message_code::SSH_MSG_CHANNEL_EOF => break,
-                _ => unimplemented!(),
+                _ => return Err(SshError::UnexpectedMessage(code)),
             }
         }
         let (code, mut payload) = self.recv()?;
         match code {
             message_code::SSH_MSG_CHANNEL_REQUEST => self.furiwake(code, &mut payload)?,
-            _ => unimplemented!(),
+            _ => return Err(SshError::UnexpectedMessage(code)),
         }
         let (code, mut payload) = self.recv()?;
         match code {
             message_code::SSH_MSG_CHANNEL_REQUEST => self.furiwake(code, &mut payload)?,
-            _ => unimplemented!(),
+            _ => return Err(SshError::UnexpectedMessage(code)),
         }
         self.client_channel += 1;
         self.send_channel_open()?;
@@ -345,7 +359,7 @@ impl<'a> Channel<'a> {
 
     // fn message_request_success(&mut self, payload: &mut Data) {
     //     pub const FILE_CHUNK: usize = 30000;
-    //     let port: u32 = payload.get();
+    //     let port: u32 = payload.get()?;
     //     let data = Data::new()
     //         .put(message_code::SSH_MSG_CHANNEL_REQUEST)
     //         .put(self.server_channel_no)
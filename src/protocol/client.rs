@@ -1,17 +1,23 @@
 use super::{
-    data::{Data, DataType},
-    key_exchange_init::{AlgList, KexAlgorithms},
-    session::Session,
-    version_exchange::Version,
+    connection::{Connection, Event},
+    data::Data,
+    key_exchange_init::AlgList,
+    known_hosts::{HostKeyPolicy, HostKeyVerifier},
+    ssh2::message_code,
 };
-use crate::crypto::key_exchange::curve::Curve25519Sha256;
-use crate::{network::tcp_client::TcpClient, protocol::error::SshError};
-use nom::{bytes::complete::take, AsBytes, IResult};
-use rand::Rng;
-use std::net::SocketAddr;
+use crate::crypto::Compress;
+use crate::{
+    network::tcp_client::TcpClient,
+    protocol::error::{Result, SshError},
+};
+use std::{env, net::SocketAddr, sync::Arc, time::Duration};
 
-const SSH_CLIENT_VERSION: &str = "SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.1";
+// softwareversion SP comments, sent after "SSH-2.0-"
+const SSH_CLIENT_IDENTIFICATION: &str = concat!("sseichi_", env!("CARGO_PKG_VERSION"));
 const SSH_CLIENT_SERVICE: &str = "ssh-connection";
+const REKEY_LIMIT: u64 = 1 << 30;
+const REKEY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const READ_TIMEOUT: Duration = Duration::from_secs(3);
 
 // enum SessionState<S>
 // where
@@ -70,11 +76,21 @@ pub struct Config {
     pub password: String,
     pub private_key_path: String,
     pub service_name: String,
-    pub version: Version,
+    pub identification: String,
+    // the name looked up in known_hosts, the address when unset
+    pub host_name: Option<String>,
+    pub known_hosts_path: String,
+    pub host_key_policy: HostKeyPolicy,
+    pub rekey_limit: u64,
+    pub rekey_interval: Duration,
+    pub compression: bool,
+    // how long a read from the server may wait, None for no limit
+    pub timeout: Option<Duration>,
 }
 
 pub struct SessionBuilder {
     config: Config,
+    host_key_verifier: Option<Arc<dyn HostKeyVerifier>>,
 }
 
 impl SessionBuilder {
@@ -85,11 +101,20 @@ impl SessionBuilder {
                 password: String::from(""),
                 private_key_path: String::from(""),
                 service_name: SSH_CLIENT_SERVICE.to_string(),
-                version: Version {
-                    version: SSH_CLIENT_VERSION.to_string(),
-                    crnl: true,
-                },
+                identification: SSH_CLIENT_IDENTIFICATION.to_string(),
+                host_name: None,
+                known_hosts_path: env::home_dir()
+                    .unwrap_or_default()
+                    .join(".ssh/known_hosts")
+                    .to_string_lossy()
+                    .to_string(),
+                host_key_policy: HostKeyPolicy::AcceptNew,
+                rekey_limit: REKEY_LIMIT,
+                rekey_interval: REKEY_INTERVAL,
+                compression: false,
+                timeout: Some(READ_TIMEOUT),
             },
+            host_key_verifier: None,
         }
     }
 
@@ -108,176 +133,192 @@ impl SessionBuilder {
         self
     }
 
-    pub fn connect(&self, address: SocketAddr) -> anyhow::Result<SshClient> {
-        let mut client = SshClient {
-            client: TcpClient::new(address)?,
-            session: Session::init_state(),
-            config: self.config.clone(),
-            key_exchange: KexAlgorithms {
-                cookie: rand::thread_rng().gen::<[u8; 16]>(),
-                key_exchange: vec!["curve25519-sha256".to_string()],
-                server_host_key: vec!["rsa-sha2-256".to_string()],
-                client_encryption: vec!["chacha20-poly1305@openssh.com".to_string()],
-                server_encryption: vec!["chacha20-poly1305@openssh.com".to_string()],
-                client_mac: vec!["hmac-sha2-256".to_string()],
-                server_mac: vec!["hmac-sha2-256".to_string()],
-                client_compression: vec!["none".to_string()],
-                server_compression: vec!["none".to_string()],
-                client_languages: vec![],
-                server_languages: vec![],
-                first_kex_packet_follows: false,
-                reserved: 0,
-            },
-            buffer: Vec::new(),
-        };
+    // The name of the host being connected to, as written in known_hosts.
+    pub fn host_name(mut self, host: &str) -> Self {
+        self.config.host_name = Some(host.to_string());
+        self
+    }
 
-        client.connection_setup()?;
+    pub fn known_hosts_path(mut self, path: &str) -> Self {
+        self.config.known_hosts_path = path.to_string();
+        self
+    }
 
-        Ok(client)
+    // Our identification string after "SSH-2.0-": softwareversion, optionally
+    // followed by a space and comments. It is checked when connecting.
+    pub fn identification(mut self, identification: &str) -> Self {
+        self.config.identification = identification.to_string();
+        self
     }
-}
 
-pub struct SshClient {
-    pub client: TcpClient,
-    pub session: Session,
-    pub config: Config,
-    pub buffer: Vec<u8>,
-    pub key_exchange: KexAlgorithms,
-    // pub state: SessionState,
-}
+    pub fn host_key_policy(mut self, policy: HostKeyPolicy) -> Self {
+        self.config.host_key_policy = policy;
+        self
+    }
 
-impl SshClient {
-    pub fn connection_setup(&mut self) -> anyhow::Result<()> {
-        self.version_exchange()?;
-        self.key_exchange_init()?;
-        self.key_exchange::<Curve25519Sha256>()?;
-        self.user_auth()?;
-        Ok(())
+    // Re-key after this many bytes have been sent and received with the same keys.
+    pub fn rekey_limit(mut self, bytes: u64) -> Self {
+        self.config.rekey_limit = bytes;
+        self
     }
-}
 
-//   uint32    packet_length
-//   byte      padding_length
-//   byte[n1]  payload; n1 = packet_length - padding_length - 1 Initially, compression MUST be "none".
-//   byte[n2]  random padding; n2 = padding_length
-//   byte[m]   mac (Message Authentication Code - MAC); m = mac_length Initially, the MAC algorithm MUST be "none".
-struct BinaryPacketProtocol {
-    packet_length: u32,
-    padding_length: u8,
-    payload: Vec<u8>,
-}
+    // Re-key after the same keys have been used for this long.
+    pub fn rekey_interval(mut self, interval: Duration) -> Self {
+        self.config.rekey_interval = interval;
+        self
+    }
 
-impl DataType for BinaryPacketProtocol {
-    fn encode(&self, buf: &mut Vec<u8>) {
-        self.packet_length.encode(buf);
-        self.padding_length.encode(buf);
-        self.payload.as_bytes().encode(buf);
-        vec![0; self.padding_length as usize].as_bytes().encode(buf);
+    // Offer zlib@openssh.com and zlib compression, preferred over none.
+    pub fn compression(mut self, compression: bool) -> Self {
+        self.config.compression = compression;
+        self
     }
 
-    fn decode(input: &[u8]) -> IResult<&[u8], Self>
-    where
-        Self: Sized,
-    {
-        let (input, packet_length) = <u32>::decode(input)?;
-        let (input, padding_length) = <u8>::decode(input)?;
-        let payload_length = packet_length - padding_length as u32 - 1;
-        let (input, payload) = take(payload_length)(input)?;
-        let (input, _padding) = take(padding_length as usize)(input)?;
-
-        Ok((
-            input,
-            BinaryPacketProtocol {
-                packet_length,
-                padding_length,
-                payload: payload.to_vec(),
-            },
-        ))
+    // Give up on a server that has not sent anything for this long.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.timeout = timeout;
+        self
     }
-}
 
-impl SshClient {
-    fn create_binary_packet(&mut self, payload: &Data) -> BinaryPacketProtocol {
-        let payload = payload.clone().into_inner();
-        let payload_length = (payload.len() + 1) as u32;
-        let packet_length = self.session.client_method.enc.packet_length(payload_length);
-        let padding_length = (packet_length - payload_length) as u8;
-
-        BinaryPacketProtocol {
-            packet_length,
-            padding_length,
-            payload,
-        }
+    pub fn host_key_verifier(mut self, verifier: impl HostKeyVerifier + 'static) -> Self {
+        self.host_key_verifier = Some(Arc::new(verifier));
+        self
     }
 
-    fn read_binary_packet_protocol(&mut self, payload: &mut Data) -> anyhow::Result<Data> {
-        let packet: BinaryPacketProtocol = payload.get();
+    pub fn connect(&self, address: SocketAddr) -> Result<SshClient> {
+        let mut client = self.build(TcpClient::new(address, self.config.timeout)?, address)?;
 
-        let mac_length = self.session.server_method.mac.size();
-        let mac: Vec<u8> = payload.get_bytes(mac_length);
+        client.connection_setup()?;
 
-        if mac != self.calc_mac(&packet) {
-            return Err(SshError::RecvError("Don't match mac".to_string()).into());
+        Ok(client)
+    }
+
+    // The protocol state machine on its own, to be driven over any transport.
+    pub fn connection(&self) -> Result<Connection> {
+        let mut algorithms = AlgList::default();
+        if self.config.compression {
+            let compress = vec![Compress::ZlibOpenSsh, Compress::Zlib, Compress::None];
+            algorithms.client_compress = compress.clone();
+            algorithms.server_compress = compress;
         }
 
-        Ok(Data(packet.payload))
+        Connection::new(self.config.clone(), algorithms.to_kex_algorithms())
     }
 
-    // mac = MAC(key, sequence_number || unencrypted_packet)
-    fn calc_mac(&self, packet: &BinaryPacketProtocol) -> Vec<u8> {
-        let mut data = Vec::new();
-        self.session.client_sequence_number.encode(&mut data);
-        packet.encode(&mut data);
-        self.session.server_method.mac.sign(&data)
+    // The client on a connected stream, before the version exchange.
+    pub(crate) fn build(&self, client: TcpClient, address: SocketAddr) -> Result<SshClient> {
+        Ok(SshClient {
+            client,
+            address,
+            connection: self.connection()?,
+            host_key_verifier: self.host_key_verifier.clone(),
+        })
     }
 }
 
-impl SshClient {
-    pub fn send(&mut self, payload: &Data) -> anyhow::Result<()> {
-        let packet = self.create_binary_packet(payload);
-
-        let mut data = Data::new();
-        data.put(&packet);
-
-        println!("client -> server");
-        data.hexdump();
-
-        self.session
-            .client_method
-            .enc
-            .encrypt(&mut data, self.session.client_sequence_number);
+// Drives a Connection over a blocking TcpClient: whatever the connection has to
+// transmit is written out before each read.
+pub struct SshClient {
+    pub client: TcpClient,
+    pub address: SocketAddr,
+    pub connection: Connection,
+    pub host_key_verifier: Option<Arc<dyn HostKeyVerifier>>,
+}
 
-        data.put(&self.calc_mac(&packet).as_bytes());
+impl SshClient {
+    // Runs the version exchange, the initial key exchange and user authentication.
+    // Connection protocol messages the server sends right after stay queued.
+    pub fn connection_setup(&mut self) -> Result<()> {
+        loop {
+            match self.next_event()? {
+                Event::HostKey(host_key) => {
+                    self.verify_host_key(&host_key)?;
+                    let result = self.connection.accept_host_key();
+                    self.flush()?;
+                    result?;
+                }
+                Event::AuthPrompt => self.user_auth()?,
+                Event::AuthFailure {
+                    methods,
+                    partial_success,
+                } => {
+                    return Err(SshError::AuthFailure {
+                        methods,
+                        partial_success,
+                    })
+                }
+                Event::ServerBanner(lines) => {
+                    for line in lines {
+                        tracing::info!("{}", line);
+                    }
+                }
+                Event::Banner(message) => tracing::info!("{}", message),
+                Event::Authenticated => {
+                    tracing::debug!("userauth accepted");
+                    return Ok(());
+                }
+                Event::ChannelData { .. } => {
+                    return Err(SshError::UnexpectedMessage(
+                        message_code::SSH_MSG_CHANNEL_DATA,
+                    ))
+                }
+                Event::Message(payload) => {
+                    return Err(SshError::UnexpectedMessage(
+                        payload.0.first().copied().unwrap_or_default(),
+                    ))
+                }
+                Event::Disconnected {
+                    reason_code,
+                    description,
+                } => {
+                    return Err(SshError::Disconnected {
+                        reason_code,
+                        description,
+                    })
+                }
+            }
+        }
+    }
 
-        self.session.client_sequence_number += 1;
-        self.client.send(&data.into_inner())
+    // Reads until the connection has an event.
+    pub fn next_event(&mut self) -> Result<Event> {
+        loop {
+            self.flush()?;
+            if let Some(event) = self.connection.poll_event() {
+                return Ok(event);
+            }
+            self.read()?;
+        }
     }
 
-    pub fn recv(&mut self) -> anyhow::Result<Data> {
-        let mut packet = if !self.buffer.is_empty() {
-            self.buffer.clone()
-        } else {
-            self.client.recv()?
-        };
-
-        let (next, packet, length) = self
-            .session
-            .client_method
-            .enc
-            .decrypt(&mut packet, self.session.server_sequence_number)?;
-
-        if !self.buffer.is_empty() {
-            self.buffer.drain(..length);
+    pub fn send(&mut self, payload: &Data) -> Result<()> {
+        let result = self.connection.send(payload);
+        self.flush()?;
+        result?;
+        // a re-key started by this packet holds it back until the exchange is over
+        while self.connection.session.kex_in_progress && !self.connection.is_closed() {
+            self.read()?;
         }
+        Ok(())
+    }
 
-        self.buffer.extend_from_slice(next);
+    pub fn disconnect(&mut self, reason_code: u32, description: &str) -> Result<()> {
+        self.connection.disconnect(reason_code, description)?;
+        self.flush()
+    }
 
-        println!("server -> client");
-        let mut packet = Data(packet);
-        packet.hexdump();
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        while let Some(data) = self.connection.poll_transmit() {
+            self.client.send(&data)?;
+        }
+        Ok(())
+    }
 
-        let payload = self.read_binary_packet_protocol(&mut packet)?;
-        self.session.server_sequence_number += 1;
-        Ok(payload)
+    // A failing packet may have left a SSH_MSG_DISCONNECT to send.
+    fn read(&mut self) -> Result<()> {
+        let data = self.client.recv()?;
+        let result = self.connection.feed(&data);
+        self.flush()?;
+        result
     }
 }
@@ -0,0 +1,540 @@
+use super::{
+    client::Config,
+    data::{ByteString, Data, DataType, NameList},
+    error::{Result, SshError},
+    key_exchange::KexState,
+    key_exchange_init::KexAlgorithms,
+    packet_reader::{calc_mac, PacketReader},
+    session::Session,
+    ssh2::message_code,
+    version_exchange::Version,
+};
+use nom::{
+    bytes::complete::take,
+    error::{Error, ErrorKind, ParseError},
+    AsBytes, Err, IResult,
+};
+use std::collections::VecDeque;
+
+// What the application has to act on, taken out with Connection::poll_event.
+#[derive(Debug)]
+pub enum Event {
+    // lines the server sent before its identification string, kept in
+    // session.server_banner as well
+    ServerBanner(Vec<String>),
+    // K_S of the initial key exchange, once the server has signed the exchange hash
+    // with it. Nothing more is read until Connection::accept_host_key.
+    HostKey(ByteString),
+    // the server accepted ssh-userauth, answered with Connection::userauth_publickey
+    AuthPrompt,
+    // byte      SSH_MSG_USERAUTH_FAILURE
+    // name-list authentications that can continue
+    // boolean   partial success
+    AuthFailure {
+        methods: NameList,
+        partial_success: bool,
+    },
+    // byte      SSH_MSG_USERAUTH_BANNER
+    // string    message in ISO-10646 UTF-8 encoding [RFC3629]
+    // string    language tag [RFC3066]
+    Banner(String),
+    // SSH_MSG_USERAUTH_SUCCESS
+    Authenticated,
+    // byte      SSH_MSG_CHANNEL_DATA
+    // uint32    recipient channel
+    // string    data
+    ChannelData {
+        recipient_channel: u32,
+        data: Vec<u8>,
+    },
+    // any other connection protocol message, message code included
+    Message(Data),
+    // byte      SSH_MSG_DISCONNECT
+    // uint32    reason code
+    // string    description in ISO-10646 UTF-8 encoding [RFC3629]
+    // string    language tag [RFC3066]
+    // The server closed the connection, nothing it sends afterwards is read.
+    Disconnected {
+        reason_code: u32,
+        description: String,
+    },
+}
+
+// The client side of a connection without any I/O. Bytes from the server go into
+// `feed`, bytes for the server come out of `poll_transmit` and what the
+// application has to act on comes out of `poll_event`. SshClient drives it over
+// a TcpClient.
+pub struct Connection {
+    pub config: Config,
+    pub session: Session,
+    pub key_exchange: KexAlgorithms,
+    pub reader: PacketReader,
+    pub(crate) kex: KexState,
+    transmit: VecDeque<Vec<u8>>,
+    events: VecDeque<Event>,
+    // the server sent SSH_MSG_DISCONNECT
+    closed: bool,
+}
+
+impl Connection {
+    // Our identification string is the first thing to transmit.
+    pub fn new(config: Config, key_exchange: KexAlgorithms) -> Result<Self> {
+        let client_version = Version::parse(&format!("SSH-2.0-{}", config.identification))?;
+        let mut identification = Vec::new();
+        client_version.encode(&mut identification);
+
+        let mut session = Session::init_state();
+        session.client_version = Some(client_version);
+        Ok(Connection {
+            config,
+            session,
+            key_exchange,
+            reader: PacketReader::new(),
+            kex: KexState::Idle,
+            transmit: VecDeque::from([identification]),
+            events: VecDeque::new(),
+            closed: false,
+        })
+    }
+
+    // Takes bytes read from the server, any amount at a time. An error ends the
+    // connection; a SSH_MSG_DISCONNECT explaining it may be left to transmit.
+    pub fn feed(&mut self, data: &[u8]) -> Result<()> {
+        self.reader.feed(data);
+        self.process()
+    }
+
+    // Bytes to write to the server, in order.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.transmit.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    // Handles the buffered packets, stopping at a host key the application has
+    // yet to accept.
+    pub(crate) fn process(&mut self) -> Result<()> {
+        if self.session.server_version.is_none() && !self.version_exchange()? {
+            return Ok(());
+        }
+        while !self.closed && !matches!(self.kex, KexState::HostKey { .. }) {
+            let Some(payload) = self.recv_packet()? else {
+                break;
+            };
+            self.handle_packet(payload)?;
+        }
+        if !self.closed && self.needs_rekey() {
+            self.start_key_exchange()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn push_event(&mut self, event: Event) {
+        self.events.push_back(event);
+    }
+
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+    }
+
+    // Messages other than the transport layer's wait while keys are being exchanged.
+    pub fn send(&mut self, payload: &Data) -> Result<()> {
+        if self.needs_rekey() {
+            self.start_key_exchange()?;
+        }
+        match self.session.kex_in_progress {
+            true => self.session.pending.push_back(payload.clone()),
+            false => self.write_packet(payload)?,
+        }
+        Ok(())
+    }
+
+    // byte      SSH_MSG_DISCONNECT
+    // uint32    reason code
+    // string    description in ISO-10646 UTF-8 encoding [RFC3629]
+    // string    language tag [RFC3066]
+    pub fn disconnect(&mut self, reason_code: u32, description: &str) -> Result<()> {
+        self.write_packet(
+            Data::new()
+                .put(&message_code::SSH_MSG_DISCONNECT)
+                .put(&reason_code)
+                .put(&description.to_string())
+                .put(&"".to_string()),
+        )
+    }
+
+    // Tells the server why the connection ends, unless the error means it is
+    // no longer listening, and hands the error back.
+    pub(crate) fn fail<T>(&mut self, e: SshError) -> Result<T> {
+        if let Some(reason_code) = e.disconnect_reason() {
+            self.disconnect(reason_code, &e.to_string())?;
+        }
+        Err(e)
+    }
+}
+
+//   uint32    packet_length
+//   byte      padding_length
+//   byte[n1]  payload; n1 = packet_length - padding_length - 1 Initially, compression MUST be "none".
+//   byte[n2]  random padding; n2 = padding_length
+//   byte[m]   mac (Message Authentication Code - MAC); m = mac_length Initially, the MAC algorithm MUST be "none".
+struct BinaryPacketProtocol {
+    packet_length: u32,
+    padding_length: u8,
+    payload: Vec<u8>,
+}
+
+impl DataType for BinaryPacketProtocol {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.packet_length.encode(buf);
+        self.padding_length.encode(buf);
+        self.payload.as_bytes().encode(buf);
+        vec![0; self.padding_length as usize].as_bytes().encode(buf);
+    }
+
+    fn decode(input: &[u8]) -> IResult<&[u8], Self>
+    where
+        Self: Sized,
+    {
+        let (input, packet_length) = <u32>::decode(input)?;
+        let (input, padding_length) = <u8>::decode(input)?;
+        let payload_length = packet_length
+            .checked_sub(padding_length as u32 + 1)
+            .ok_or(Err::Error(Error::from_error_kind(input, ErrorKind::Verify)))?;
+        let (input, payload) = take(payload_length)(input)?;
+        let (input, _padding) = take(padding_length as usize)(input)?;
+
+        Ok((
+            input,
+            BinaryPacketProtocol {
+                packet_length,
+                padding_length,
+                payload: payload.to_vec(),
+            },
+        ))
+    }
+}
+
+impl Connection {
+    fn create_binary_packet(&mut self, payload: &Data) -> BinaryPacketProtocol {
+        let payload = payload.clone().into_inner();
+        let payload_length = (payload.len() + 1) as u32;
+        let packet_length = self.session.client_method.enc.packet_length(payload_length);
+        let padding_length = (packet_length - payload_length) as u8;
+
+        BinaryPacketProtocol {
+            packet_length,
+            padding_length,
+            payload,
+        }
+    }
+
+    fn read_binary_packet_protocol(&mut self, payload: &mut Data) -> Result<Data> {
+        let packet: BinaryPacketProtocol = payload.get()?;
+        Ok(Data(packet.payload))
+    }
+
+    // Compresses, encrypts and MACs `payload` with the current keys, whatever
+    // else is going on.
+    pub(crate) fn write_packet(&mut self, payload: &Data) -> Result<()> {
+        let code = payload.0.first().copied().unwrap_or_default();
+        let method = &mut self.session.client_method;
+        let payload = match !method.comp.is_delayed() || self.session.authenticated {
+            true => Data(method.comp.compress(payload.clone().into_inner())?),
+            false => payload.clone(),
+        };
+        let packet = self.create_binary_packet(&payload);
+
+        let mut data = Data::new();
+        data.put(&packet);
+
+        tracing::trace!(
+            "client -> server: message {} ({} bytes)",
+            code,
+            data.0.len()
+        );
+
+        let plaintext = data.clone().into_inner();
+        self.session
+            .client_method
+            .enc
+            .encrypt(&mut data, self.session.client_sequence_number);
+
+        let mac = calc_mac(
+            &self.session.client_method,
+            self.session.client_sequence_number,
+            match self.session.client_method.mac.is_etm() {
+                true => &data.0,
+                false => &plaintext,
+            },
+        );
+        data.put(&mac.as_bytes());
+
+        self.session.client_sequence_number += 1;
+        self.session.bytes_since_kex += data.0.len() as u64;
+        self.transmit.push_back(data.into_inner());
+        Ok(())
+    }
+
+    fn recv_packet(&mut self) -> Result<Option<Data>> {
+        let packet = match self.reader.read_packet(
+            &mut self.session.server_method,
+            self.session.server_sequence_number,
+        ) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return Ok(None),
+            Err(e) => return self.fail(e),
+        };
+
+        let packet_size = packet.len() as u64;
+        let mut packet = Data(packet);
+
+        let mut payload = self.read_binary_packet_protocol(&mut packet)?;
+        let method = &mut self.session.server_method;
+        if !method.comp.is_delayed() || self.session.authenticated {
+            payload = Data(method.comp.decompress(payload.into_inner())?);
+        }
+        // delayed compression starts with the packets after this one
+        if payload.0.first() == Some(&message_code::SSH_MSG_USERAUTH_SUCCESS)
+            && self.session.keys.is_some()
+        {
+            self.session.authenticated = true;
+        }
+        tracing::trace!(
+            "server -> client: message {} ({} bytes)",
+            payload.0.first().copied().unwrap_or_default(),
+            packet_size
+        );
+        self.session.server_sequence_number += 1;
+        self.session.bytes_since_kex += packet_size;
+        Ok(Some(payload))
+    }
+
+    // Transport layer generic messages, key exchange and user authentication are
+    // handled here, the connection protocol goes to the application.
+    fn handle_packet(&mut self, payload: Data) -> Result<()> {
+        let code = payload.0.first().copied().unwrap_or_default();
+        // strict key exchange: nothing but key exchange messages (20 to 49)
+        // until the initial exchange is over, not even SSH_MSG_IGNORE
+        if self.session.kex_in_progress
+            && self.session.strict_kex
+            && self.session.keys.is_none()
+            && code != message_code::SSH_MSG_DISCONNECT
+            && !(message_code::SSH_MSG_KEXINIT..message_code::SSH_MSG_USERAUTH_REQUEST)
+                .contains(&code)
+        {
+            let e = SshError::Protocol(format!(
+                "strict KEX violation: unexpected packet type {}",
+                code
+            ));
+            self.disconnect(message_code::SSH_DISCONNECT_PROTOCOL_ERROR, &e.to_string())?;
+            return Err(e);
+        }
+        let ext_info_expected = std::mem::take(&mut self.session.ext_info_expected);
+        if let Some(ext_info) = self.session.held_ext_info.take() {
+            match code {
+                message_code::SSH_MSG_USERAUTH_SUCCESS => self.update_ext_info(ext_info)?,
+                _ => tracing::warn!("ignoring SSH_MSG_EXT_INFO not followed by USERAUTH_SUCCESS"),
+            }
+        }
+        let Some(payload) = self.dispatch_transport(payload)? else {
+            return Ok(());
+        };
+        // the packet after a KEXINIT with a wrong guess, see recv_kexinit
+        if std::mem::take(&mut self.session.ignore_guess) {
+            return Ok(());
+        }
+
+        match code {
+            message_code::SSH_MSG_KEXINIT => self.recv_kexinit(payload),
+            message_code::SSH_MSG_NEWKEYS
+            | message_code::SSH_MSG_KEXDH_INIT..=message_code::SSH2_MSG_KEX_DH_GEX_REQUEST => {
+                self.handle_key_exchange(payload)
+            }
+            message_code::SSH_MSG_EXT_INFO => self.recv_ext_info(payload, ext_info_expected),
+            // nothing from the server is trusted before the first NEWKEYS
+            message_code::SSH_MSG_SERVICE_ACCEPT
+            | message_code::SSH_MSG_USERAUTH_REQUEST..message_code::SSH_MSG_GLOBAL_REQUEST
+                if self.session.keys.is_some() =>
+            {
+                self.handle_user_auth(payload)
+            }
+            // the connection protocol starts once the user is authenticated. What the
+            // server sent before it saw our KEXINIT waits for the re-key to end.
+            message_code::SSH_MSG_GLOBAL_REQUEST.. if self.session.authenticated => {
+                match self.session.kex_in_progress {
+                    true => self.session.deferred.push_back(payload),
+                    false => self.handle_connection(payload)?,
+                }
+                Ok(())
+            }
+            _ => {
+                let e = SshError::UnexpectedMessage(code);
+                self.disconnect(message_code::SSH_DISCONNECT_PROTOCOL_ERROR, &e.to_string())?;
+                Err(e)
+            }
+        }
+    }
+
+    // Connection protocol messages (80 and up) go to the application.
+    pub(crate) fn handle_connection(&mut self, mut payload: Data) -> Result<()> {
+        match payload.0.first().copied() {
+            Some(message_code::SSH_MSG_CHANNEL_DATA) => {
+                payload.expect(message_code::SSH_MSG_CHANNEL_DATA)?;
+                let recipient_channel: u32 = payload.get()?;
+                let data: ByteString = payload.get()?;
+                self.push_event(Event::ChannelData {
+                    recipient_channel,
+                    data: data.0,
+                });
+            }
+            _ => self.push_event(Event::Message(payload)),
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn handshake_without_io() {
+    use super::client::SessionBuilder;
+    use super::key_exchange_init::SERVER_KEXINIT_PAYLOAD;
+
+    // unencrypted, padded to 8 bytes
+    let packet = |payload: &[u8]| {
+        let padding_length = 4 + (8 - (payload.len() + 9) % 8) % 8;
+        let mut packet = Data::new();
+        packet.put(&BinaryPacketProtocol {
+            packet_length: (payload.len() + padding_length + 1) as u32,
+            padding_length: padding_length as u8,
+            payload: payload.to_vec(),
+        });
+        packet.into_inner()
+    };
+    let sent = |connection: &mut Connection| {
+        std::iter::from_fn(|| connection.poll_transmit())
+            .map(|packet| Data(packet).get::<BinaryPacketProtocol>().unwrap().payload[0])
+            .collect::<Vec<_>>()
+    };
+
+    // the server's identification string and KEXINIT, one byte at a time
+    let started = || {
+        let mut connection = SessionBuilder::create_session().connection().unwrap();
+        // a Q_S of 32 bytes
+        connection.key_exchange.key_exchange = vec!["curve25519-sha256".to_string()];
+        let identification = connection.poll_transmit().unwrap();
+        assert!(identification.starts_with(b"SSH-2.0-sseichi_"));
+        assert!(connection.poll_transmit().is_none());
+
+        let mut stream = b"SSH-2.0-OpenSSH_9.6\r\n".to_vec();
+        stream.extend(packet(SERVER_KEXINIT_PAYLOAD));
+        for byte in &stream {
+            connection.feed(&[*byte]).unwrap();
+        }
+        connection
+    };
+    let mut connection = started();
+    assert_eq!(
+        sent(&mut connection),
+        [
+            message_code::SSH_MSG_KEXINIT,
+            message_code::SSH2_MSG_KEX_ECDH_INIT
+        ]
+    );
+
+    // what the application sends waits for the exchange to end
+    connection
+        .send(
+            Data::new()
+                .put(&message_code::SSH_MSG_CHANNEL_EOF)
+                .put(&0u32),
+        )
+        .unwrap();
+    assert!(sent(&mut connection).is_empty());
+
+    // a reply that is not signed by the host key ends the connection
+    let mut payload = Data::new();
+    payload
+        .put(&message_code::SSH2_MSG_KEX_ECDH_REPLY)
+        .put(&ByteString(vec![0; 51]))
+        .put(&ByteString(vec![9; 32]))
+        .put(&ByteString(vec![0; 83]));
+    assert!(matches!(
+        connection.feed(&packet(&payload.0)),
+        Err(SshError::SignatureInvalid)
+    ));
+    assert_eq!(sent(&mut connection), [message_code::SSH_MSG_DISCONNECT]);
+
+    // as does connection protocol data before there are keys and a user
+    let mut connection = started();
+    sent(&mut connection);
+    let mut payload = Data::new();
+    payload
+        .put(&message_code::SSH_MSG_CHANNEL_DATA)
+        .put(&1u32)
+        .put(&ByteString(b"ls\n".to_vec()));
+    assert!(matches!(
+        connection.feed(&packet(&payload.0)),
+        Err(SshError::UnexpectedMessage(
+            message_code::SSH_MSG_CHANNEL_DATA
+        ))
+    ));
+    assert!(connection.poll_event().is_none());
+    assert_eq!(sent(&mut connection), [message_code::SSH_MSG_DISCONNECT]);
+}
+
+#[test]
+fn delayed_compression_starts_after_authentication() {
+    use super::client::SessionBuilder;
+    use super::test_server::TestServer;
+    use crate::crypto::Compress;
+
+    let mut server = TestServer::new();
+    server.kexinit.client_compression = vec!["zlib@openssh.com".to_string()];
+    server.kexinit.server_compression = vec!["zlib@openssh.com".to_string()];
+    let mut connection =
+        TestServer::client_with(SessionBuilder::create_session().compression(true));
+    server.handshake(&mut connection);
+    let algorithms = connection.session.get_algorithms().unwrap();
+    assert_eq!(algorithms.client_compress, Compress::ZlibOpenSsh);
+    assert_eq!(algorithms.server_compress, Compress::ZlibOpenSsh);
+
+    // compresses well, when it is compressed
+    let mut data = Data::new();
+    data.put(&message_code::SSH_MSG_CHANNEL_DATA)
+        .put(&0u32)
+        .put(&ByteString(vec![b'a'; 1000]));
+    let mut ignore = Data::new();
+    ignore
+        .put(&message_code::SSH_MSG_IGNORE)
+        .put(&ByteString(vec![b'a'; 1000]));
+
+    // not before USERAUTH_SUCCESS, in either direction
+    connection.send(&ignore).unwrap();
+    let packet = connection.poll_transmit().unwrap();
+    assert!(packet.len() > 1000);
+    assert_eq!(server.receive(&packet)[0].0, ignore.0);
+    let packet = server.packet(&ignore);
+    assert!(packet.len() > 1000);
+    connection.feed(&packet).unwrap();
+    assert!(connection.poll_event().is_none());
+
+    // USERAUTH_SUCCESS itself goes uncompressed, the packets after it do not
+    server.authenticate(&mut connection);
+    connection.send(&data).unwrap();
+    let packet = connection.poll_transmit().unwrap();
+    assert!(packet.len() < 100);
+    assert_eq!(server.receive(&packet)[0].0, data.0);
+    let packet = server.packet(&data);
+    assert!(packet.len() < 100);
+    connection.feed(&packet).unwrap();
+    assert!(matches!(
+        connection.poll_event(),
+        Some(Event::ChannelData { recipient_channel: 0, data }) if data == [b'a'; 1000]
+    ));
+}
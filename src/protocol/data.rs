@@ -1,11 +1,26 @@
+use super::error::{Result, SshError};
 use crate::utils::hexdump;
 use nom::bytes::complete::take;
 use nom::error::{Error, ErrorKind, ParseError};
 use nom::number::complete::{be_u32, be_u64, be_u8};
 use nom::{AsBytes, Err, IResult};
+use rsa::BigUint;
+use std::any::type_name;
 use std::str::FromStr;
 
-#[derive(Debug, Clone)]
+// #[derive(DataType)], see sseichi-derive. The derived code reaches nom
+// through here, so that crates using it need not depend on nom themselves.
+#[doc(hidden)]
+pub use nom;
+pub use sseichi_derive::DataType;
+
+// [RFC4251 § 6](https://datatracker.ietf.org/doc/html/rfc4251#section-6)
+// Algorithm and method names are at most 64 characters.
+const MAX_NAME_LENGTH: usize = 64;
+// Longest name-list accepted from the peer; OpenSSH's own proposals stay well below.
+const MAX_NAME_LIST_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Default)]
 pub struct Data(pub Vec<u8>);
 
 impl Data {
@@ -21,29 +36,37 @@ impl Data {
         self
     }
 
-    pub fn get<T>(&mut self) -> T
+    // Takes the next value off the front. Nothing is consumed when the bytes
+    // left do not decode as a `T`.
+    pub fn get<T>(&mut self) -> Result<T>
     where
         T: DataType,
     {
-        let (input, data) = match T::decode(&self.0) {
-            Ok(t) => t,
-            Err(e) => panic!("Error decoding {}", e),
-        };
-        let size = (input.as_ptr() as usize) - (self.0.as_ptr() as usize);
+        let (input, data) = T::decode(&self.0).map_err(|_| {
+            SshError::InvalidFormat(format!("truncated or malformed {}", type_name::<T>()))
+        })?;
+        let size = self.0.len() - input.len();
         self.0.drain(..size);
-        data
+        Ok(data)
     }
 
-    pub fn expect<T>(&mut self, value: T)
-    where
-        T: DataType + std::cmp::PartialEq + std::fmt::Debug,
-    {
-        assert_eq!(self.get::<T>(), value);
+    // Takes the message number and checks that it is `code`.
+    pub fn expect(&mut self, code: u8) -> Result<()> {
+        match self.get::<u8>()? {
+            received if received == code => Ok(()),
+            received => Err(SshError::UnexpectedMessage(received)),
+        }
     }
 
-    pub fn get_bytes(&mut self, len: usize) -> Vec<u8> {
-        let bytes = self.0.drain(..len).collect::<Vec<u8>>();
-        bytes
+    pub fn get_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        if len > self.0.len() {
+            return Err(SshError::InvalidFormat(format!(
+                "{} bytes wanted, {} left",
+                len,
+                self.0.len()
+            )));
+        }
+        Ok(self.0.drain(..len).collect())
     }
 
     pub fn into_inner(self) -> Vec<u8> {
@@ -62,6 +85,11 @@ impl From<&[u8]> for Data {
 }
 
 // [RFC4251 § 5](https://datatracker.ietf.org/doc/html/rfc4251#section-5)
+//
+// Round trip: for every value v, decode(encode(v)) gives back v and consumes exactly
+// the encoded bytes. decode reads bytes from the peer, so it returns an error and
+// never panics on truncated or malformed input. The exceptions are noted on the
+// impls below.
 pub trait DataType {
     fn encode(&self, buf: &mut Vec<u8>);
     fn decode(input: &[u8]) -> IResult<&[u8], Self>
@@ -129,13 +157,14 @@ impl DataType for u64 {
 }
 
 // byte[n]
+// Encode only: n is not on the wire, decode as [u8; N] or with Data::get_bytes.
 impl DataType for &[u8] {
     fn encode(&self, buf: &mut Vec<u8>) {
         buf.extend(*self)
     }
 
-    fn decode(_input: &[u8]) -> IResult<&[u8], Self> {
-        todo!();
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> {
+        Err(Err::Failure(Error::from_error_kind(input, ErrorKind::Fail)))
     }
 }
 
@@ -146,8 +175,10 @@ impl<const N: usize> DataType for [u8; N] {
     }
 
     fn decode(input: &[u8]) -> IResult<&[u8], Self> {
-        let (input, result) = take(N as u8)(input)?;
-        Ok((input, result.try_into().unwrap()))
+        let (input, result) = take(N)(input)?;
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(result);
+        Ok((input, bytes))
     }
 }
 
@@ -155,7 +186,7 @@ impl<const N: usize> DataType for [u8; N] {
 #[derive(Debug)]
 pub struct ByteString(pub Vec<u8>);
 impl FromStr for ByteString {
-    type Err = anyhow::Error;
+    type Err = SshError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Ok(ByteString(s.as_bytes().to_vec()))
     }
@@ -182,13 +213,16 @@ impl DataType for String {
     }
 
     fn decode(input: &[u8]) -> IResult<&[u8], Self> {
-        let (input, length) = be_u32(input)?;
-        let (input, payload) = take(length)(input)?;
-        Ok((input, String::from_utf8(payload.to_vec()).unwrap()))
+        let (input, ByteString(payload)) = ByteString::decode(input)?;
+        let string = String::from_utf8(payload)
+            .map_err(|_| Err::Error(Error::from_error_kind(input, ErrorKind::Verify)))?;
+        Ok((input, string))
     }
 }
 
 // name-list
+// An empty name-list decodes to no names. Names must be printable US-ASCII without
+// spaces or commas, at most 64 characters each.
 pub type NameList = Vec<String>;
 impl<T> DataType for Vec<T>
 where
@@ -206,46 +240,237 @@ where
     where
         Self: Sized,
     {
-        let (input, payload) = <ByteString>::decode(input)?;
-        let result = String::from_utf8(payload.0)
-            .unwrap()
-            .split(',')
-            .map(|s| s.to_string())
-            .map(|v| T::from_str(&v))
-            .collect::<Result<Vec<T>, _>>()
-            .map_err(|_| Err::Error(Error::from_error_kind(input, ErrorKind::Eof)))?;
-        Ok((input, result))
+        let invalid = || Err::Error(Error::from_error_kind(input, ErrorKind::Verify));
+        let (input, ByteString(payload)) = ByteString::decode(input)?;
+        if payload.len() > MAX_NAME_LIST_LENGTH {
+            return Err(invalid());
+        }
+        if payload.is_empty() {
+            return Ok((input, Vec::new()));
+        }
+        let names = payload
+            .split(|b| *b == b',')
+            .map(|name| {
+                std::str::from_utf8(name)
+                    .ok()
+                    .filter(|name| {
+                        !name.is_empty()
+                            && name.len() <= MAX_NAME_LENGTH
+                            && name.bytes().all(|b| b.is_ascii_graphic())
+                    })
+                    .and_then(|name| T::from_str(name).ok())
+                    .ok_or_else(invalid)
+            })
+            .collect::<Result<Vec<T>, _>>()?;
+        Ok((input, names))
     }
 }
 
 // mpint
-#[derive(Debug, Clone)]
-pub struct Mpint(pub Vec<u8>);
+// [RFC4251 § 5](https://datatracker.ietf.org/doc/html/rfc4251#section-5)
+// Two's complement, big-endian, without unnecessary leading 0x00 or 0xff bytes.
+// Zero has no bytes at all. The bytes are kept in that canonical form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mpint(Vec<u8>);
+
+impl Mpint {
+    // A non-negative number from its unsigned big-endian bytes, which may
+    // have leading zeros such as a fixed-size shared secret.
+    pub fn from_unsigned(bytes: &[u8]) -> Mpint {
+        let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+        let bytes = &bytes[start..];
+        let mut mpint = Vec::with_capacity(bytes.len() + 1);
+        if bytes.first().is_some_and(|b| b & 0x80 != 0) {
+            mpint.push(0);
+        }
+        mpint.extend_from_slice(bytes);
+        Mpint(mpint)
+    }
+
+    fn from_twos_complement(bytes: &[u8]) -> Mpint {
+        let mut bytes = bytes;
+        while let [first, rest @ ..] = bytes {
+            let sign = rest.first().map(|b| b & 0x80);
+            match (first, sign) {
+                (0x00, None | Some(0)) | (0xff, Some(0x80)) => bytes = rest,
+                _ => break,
+            }
+        }
+        Mpint(bytes.to_vec())
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0.first().is_some_and(|b| b & 0x80 != 0)
+    }
+
+    // The unsigned big-endian bytes, without a leading zero.
+    pub fn to_unsigned(&self) -> Result<Vec<u8>> {
+        if self.is_negative() {
+            return Err(SshError::InvalidFormat("negative mpint".to_string()));
+        }
+        let start = self.0.iter().position(|b| *b != 0).unwrap_or(self.0.len());
+        Ok(self.0[start..].to_vec())
+    }
+}
+
+impl From<i64> for Mpint {
+    fn from(value: i64) -> Self {
+        Mpint::from_twos_complement(&value.to_be_bytes())
+    }
+}
+
+impl From<&BigUint> for Mpint {
+    fn from(value: &BigUint) -> Self {
+        Mpint::from_unsigned(&value.to_bytes_be())
+    }
+}
+
+impl TryFrom<&Mpint> for BigUint {
+    type Error = SshError;
+    fn try_from(value: &Mpint) -> Result<Self> {
+        Ok(BigUint::from_bytes_be(&value.to_unsigned()?))
+    }
+}
+
+// Redundant leading bytes are dropped when decoding, like OpenSSH does.
 impl DataType for Mpint {
     fn encode(&self, buf: &mut Vec<u8>) {
-        if self.0[0] & 0x80 == 0 {
-            self.0.len().encode(buf);
-            self.0.as_bytes().encode(buf)
-        } else {
-            (self.0.len() + 1).encode(buf);
-            0_u8.encode(buf);
-            self.0.as_bytes().encode(buf)
-        }
+        self.0.len().encode(buf);
+        self.0.as_bytes().encode(buf)
     }
 
     fn decode(input: &[u8]) -> IResult<&[u8], Self> {
-        let (input, length) = be_u32(input)?;
-        let (input, payload) = take(length)(input)?;
-        Ok((input, Mpint(payload.to_vec())))
+        let (input, ByteString(payload)) = ByteString::decode(input)?;
+        Ok((input, Mpint::from_twos_complement(&payload)))
     }
 }
 
+// Whatever is left, such as a nested message.
 impl DataType for Data {
     fn encode(&self, buf: &mut Vec<u8>) {
         buf.extend(self.clone().into_inner());
     }
 
-    fn decode(_input: &[u8]) -> IResult<&[u8], Self> {
-        todo!();
+    fn decode(input: &[u8]) -> IResult<&[u8], Self> {
+        Ok((&input[input.len()..], Data(input.to_vec())))
     }
 }
+
+#[test]
+fn decode_untrusted_input() {
+    let mut data = Data::new();
+    data.put(&true)
+        .put(&7u8)
+        .put(&0xdeadbeefu32)
+        .put(&u64::MAX)
+        .put(&[0xab; 300])
+        .put(&ByteString(vec![0, 1, 2]))
+        .put(&"ssh-userauth".to_string())
+        .put(&vec![
+            "curve25519-sha256".to_string(),
+            "ext-info-c".to_string(),
+        ])
+        .put(&NameList::new());
+    assert!(data.get::<bool>().unwrap());
+    assert_eq!(data.get::<u8>().unwrap(), 7);
+    assert_eq!(data.get::<u32>().unwrap(), 0xdeadbeef);
+    assert_eq!(data.get::<u64>().unwrap(), u64::MAX);
+    assert_eq!(data.get::<[u8; 300]>().unwrap(), [0xab; 300]);
+    assert_eq!(data.get::<ByteString>().unwrap().0, [0, 1, 2]);
+    assert_eq!(data.get::<String>().unwrap(), "ssh-userauth");
+    assert_eq!(
+        data.get::<NameList>().unwrap(),
+        ["curve25519-sha256", "ext-info-c"]
+    );
+    assert!(data.get::<NameList>().unwrap().is_empty());
+    assert!(data.into_inner().is_empty());
+
+    // truncated input is an error and consumes nothing
+    let mut data = Data(vec![0, 0, 0, 5, b'a']);
+    assert!(data.get::<String>().is_err());
+    assert!(data.get::<u64>().is_err());
+    assert_eq!(data.get::<u32>().unwrap(), 5);
+    assert!(data.get_bytes(2).is_err());
+    assert_eq!(data.get_bytes(1).unwrap(), b"a");
+
+    // invalid UTF-8 and names outside RFC 4251 § 6
+    let mut data = Data::new();
+    data.put(&ByteString(vec![0xff, 0xfe]));
+    assert!(data.clone().get::<String>().is_err());
+    for names in ["a,,b", "a b", &"a".repeat(65), "a,"] {
+        let mut data = Data::new();
+        data.put(&names.to_string());
+        assert!(data.get::<NameList>().is_err(), "{:?}", names);
+    }
+
+    use super::ssh2::message_code;
+    let mut data = Data(vec![message_code::SSH_MSG_SERVICE_ACCEPT]);
+    assert!(matches!(
+        data.expect(message_code::SSH_MSG_KEXINIT),
+        Err(SshError::UnexpectedMessage(
+            message_code::SSH_MSG_SERVICE_ACCEPT
+        ))
+    ));
+}
+
+#[test]
+fn mpint_rfc4251_vectors() {
+    // [RFC4251 § 5](https://datatracker.ietf.org/doc/html/rfc4251#section-5)
+    let vectors: [(Mpint, &[u8]); 5] = [
+        (Mpint::from(0), b"\x00\x00\x00\x00"),
+        (
+            Mpint::from(0x9a378f9b2e332a7),
+            b"\x00\x00\x00\x08\x09\xa3\x78\xf9\xb2\xe3\x32\xa7",
+        ),
+        (Mpint::from(0x80), b"\x00\x00\x00\x02\x00\x80"),
+        (Mpint::from(-0x1234), b"\x00\x00\x00\x02\xed\xcc"),
+        (
+            Mpint::from(-0xdeadbeef),
+            b"\x00\x00\x00\x05\xff\x21\x52\x41\x11",
+        ),
+    ];
+    for (mpint, wire) in vectors {
+        let mut data = Data::new();
+        data.put(&mpint);
+        assert_eq!(data.0, wire);
+        assert_eq!(data.get::<Mpint>().unwrap(), mpint);
+    }
+    assert!(Mpint::from(-0x1234).is_negative());
+    assert!(Mpint::from(-0x1234).to_unsigned().is_err());
+
+    // shared secrets are fixed-size and may start with zero bytes
+    let mut data = Data::new();
+    data.put(&Mpint::from_unsigned(&[0, 0, 0x80, 1]));
+    assert_eq!(data.0, b"\x00\x00\x00\x03\x00\x80\x01");
+    data.0.clear();
+    data.put(&Mpint::from_unsigned(&[0; 32]));
+    assert_eq!(data.0, b"\x00\x00\x00\x00");
+
+    // redundant sign bytes from the peer are dropped
+    let mut data = Data(b"\x00\x00\x00\x03\x00\x00\x7f".to_vec());
+    assert_eq!(data.get::<Mpint>().unwrap(), Mpint::from(0x7f));
+    let mut data = Data(b"\x00\x00\x00\x03\xff\xff\x80".to_vec());
+    assert_eq!(data.get::<Mpint>().unwrap(), Mpint::from(-0x80));
+
+    let n = BigUint::from_bytes_be(&[0xff; 256]);
+    let mpint = Mpint::from(&n);
+    assert_eq!(mpint.to_unsigned().unwrap(), [0xff; 256]);
+    assert_eq!(BigUint::try_from(&mpint).unwrap(), n);
+}
+
+#[test]
+fn derive_with_crate_path() {
+    // the path to sseichi can be given, here as seen from inside it
+    #[derive(Debug, DataType)]
+    #[data(crate = crate, message = crate::protocol::ssh2::message_code::SSH_MSG_IGNORE)]
+    struct Ignore {
+        data: ByteString,
+    }
+
+    let mut payload = Data::new();
+    payload.put(&Ignore {
+        data: ByteString(b"abc".to_vec()),
+    });
+    assert_eq!(payload.0, b"\x02\x00\x00\x00\x03abc");
+    assert_eq!(payload.get::<Ignore>().unwrap().data.0, b"abc");
+}
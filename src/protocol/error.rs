@@ -1,32 +1,219 @@
-use std::{fmt, io};
+use super::ssh2::{error_code, message_code};
+use std::io;
 use thiserror::Error;
 
+pub type Result<T, E = SshError> = std::result::Result<T, E>;
+
+// Everything that can go wrong on a connection. Each variant maps to one of
+// OpenSSH's SSH_ERR_* codes, see `SshError::code`.
 #[derive(Error, Debug)]
 pub enum SshError {
-    // #[error("data store disconnected")]
-    ParseError,
-    IoError(io::Error),
-    SshError(String),
-    SendError(String),
-    RecvError(String),
+    // bytes from the server that do not decode
+    #[error("invalid format: {0}")]
+    InvalidFormat(String),
+    // a well-formed message that is not allowed at this point
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("unexpected message {0}")]
+    UnexpectedMessage(u8),
+    #[error("protocol version mismatch: {0}")]
+    ProtocolMismatch(String),
+    #[error("bad packet length {0}")]
+    BadPacketLength(u32),
+    #[error("message authentication code incorrect")]
+    MacInvalid,
+    #[error("no matching cipher found")]
+    NoCipherAlgMatch,
+    #[error("no matching MAC found")]
+    NoMacAlgMatch,
+    #[error("no matching compression method found")]
+    NoCompressAlgMatch,
+    #[error("no matching key exchange method found")]
+    NoKexAlgMatch,
+    #[error("no matching host key type found")]
+    NoHostkeyAlgMatch,
+    #[error("DH GEX group out of range")]
+    DhGexOutOfRange,
+    // the server's key exchange value is not a valid public key or ciphertext
+    #[error("invalid key exchange value: {0}")]
+    InvalidPublicValue(String),
+    #[error("incorrect signature")]
+    SignatureInvalid,
+    #[error("host key verification failed: {0}")]
+    HostKeyNotVerifiable(String),
+    // our own private key could not be loaded
+    #[error("invalid private key: {0}")]
+    KeyInvalid(String),
+    #[error("error in libcrypto: {0}")]
+    Crypto(String),
+    // [RFC4253 § 11.1](https://datatracker.ietf.org/doc/html/rfc4253#section-11.1)
+    #[error("disconnected by server: {description} ({})", disconnect_reason(*reason_code))]
+    Disconnected {
+        reason_code: u32,
+        description: String,
+    },
+    // [RFC4252 § 5.1](https://datatracker.ietf.org/doc/html/rfc4252#section-5.1)
+    #[error("permission denied ({})", methods.join(","))]
+    AuthFailure {
+        // authentications that can continue
+        methods: Vec<String>,
+        partial_success: bool,
+    },
+    // [RFC4254 § 5.1](https://datatracker.ietf.org/doc/html/rfc4254#section-5.1)
+    #[error("channel open failed: {description} ({})", channel_open_reason(*reason_code))]
+    ChannelOpenFailure {
+        reason_code: u32,
+        description: String,
+    },
+    #[error("connection closed")]
+    ConnectionClosed,
+    #[error("connection timed out")]
     Timeout,
+    #[error(transparent)]
+    Io(io::Error),
 }
 
-impl SshError {
-    pub fn from(e: String) -> SshError {
-        SshError::SshError(e)
+// A read timeout shows up as WouldBlock on Unix and TimedOut on Windows.
+impl From<io::Error> for SshError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => SshError::Timeout,
+            _ => SshError::Io(e),
+        }
     }
 }
 
-impl fmt::Display for SshError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl SshError {
+    // The matching OpenSSH error code.
+    pub fn code(&self) -> isize {
         match self {
-            SshError::ParseError => write!(f, "parse error"),
-            SshError::SshError(e) => write!(f, "{}", e),
-            SshError::IoError(v) => write!(f, "{}", v),
-            SshError::SendError(e) => write!(f, "{}", e),
-            SshError::RecvError(e) => write!(f, "{}", e),
-            SshError::Timeout => write!(f, "time out."),
+            SshError::InvalidFormat(_) => error_code::SSH_ERR_INVALID_FORMAT,
+            SshError::Protocol(_) | SshError::UnexpectedMessage(_) => {
+                error_code::SSH_ERR_PROTOCOL_ERROR
+            }
+            SshError::ProtocolMismatch(_) => error_code::SSH_ERR_PROTOCOL_MISMATCH,
+            SshError::BadPacketLength(_) => error_code::SSH_ERR_CONN_CORRUPT,
+            SshError::MacInvalid => error_code::SSH_ERR_MAC_INVALID,
+            SshError::NoCipherAlgMatch => error_code::SSH_ERR_NO_CIPHER_ALG_MATCH,
+            SshError::NoMacAlgMatch => error_code::SSH_ERR_NO_MAC_ALG_MATCH,
+            SshError::NoCompressAlgMatch => error_code::SSH_ERR_NO_COMPRESS_ALG_MATCH,
+            SshError::NoKexAlgMatch => error_code::SSH_ERR_NO_KEX_ALG_MATCH,
+            SshError::NoHostkeyAlgMatch => error_code::SSH_ERR_NO_HOSTKEY_ALG_MATCH,
+            SshError::DhGexOutOfRange => error_code::SSH_ERR_DH_GEX_OUT_OF_RANGE,
+            SshError::InvalidPublicValue(_) => error_code::SSH_ERR_KEY_INVALID_EC_VALUE,
+            SshError::SignatureInvalid => error_code::SSH_ERR_SIGNATURE_INVALID,
+            SshError::HostKeyNotVerifiable(_) => error_code::SSH_ERR_KEY_CERT_MISMATCH,
+            SshError::KeyInvalid(_) => error_code::SSH_ERR_INVALID_FORMAT,
+            SshError::Crypto(_) => error_code::SSH_ERR_LIBCRYPTO_ERROR,
+            SshError::Disconnected { .. } => error_code::SSH_ERR_DISCONNECTED,
+            // OpenSSH has no codes of its own for these, the peer refused a request
+            SshError::AuthFailure { .. } | SshError::ChannelOpenFailure { .. } => {
+                error_code::SSH_ERR_PROTOCOL_ERROR
+            }
+            SshError::ConnectionClosed => error_code::SSH_ERR_CONN_CLOSED,
+            SshError::Timeout => error_code::SSH_ERR_CONN_TIMEOUT,
+            SshError::Io(_) => error_code::SSH_ERR_SYSTEM_ERROR,
         }
     }
+
+    // The reason code for our SSH_MSG_DISCONNECT when this error ends the
+    // connection, None when there is nobody left to tell.
+    pub fn disconnect_reason(&self) -> Option<u32> {
+        match self {
+            SshError::MacInvalid => Some(message_code::SSH_DISCONNECT_MAC_ERROR),
+            SshError::NoCipherAlgMatch
+            | SshError::NoMacAlgMatch
+            | SshError::NoCompressAlgMatch
+            | SshError::NoKexAlgMatch
+            | SshError::NoHostkeyAlgMatch
+            | SshError::DhGexOutOfRange
+            | SshError::InvalidPublicValue(_)
+            | SshError::SignatureInvalid => Some(message_code::SSH_DISCONNECT_KEY_EXCHANGE_FAILED),
+            SshError::HostKeyNotVerifiable(_) => {
+                Some(message_code::SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE)
+            }
+            SshError::ProtocolMismatch(_) => {
+                Some(message_code::SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED)
+            }
+            SshError::Disconnected { .. }
+            | SshError::ConnectionClosed
+            | SshError::Timeout
+            | SshError::Io(_) => None,
+            _ => Some(message_code::SSH_DISCONNECT_PROTOCOL_ERROR),
+        }
+    }
+}
+
+fn disconnect_reason(reason_code: u32) -> &'static str {
+    match reason_code {
+        message_code::SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT => "host not allowed to connect",
+        message_code::SSH_DISCONNECT_PROTOCOL_ERROR => "protocol error",
+        message_code::SSH_DISCONNECT_KEY_EXCHANGE_FAILED => "key exchange failed",
+        message_code::SSH_DISCONNECT_RESERVED => "reserved",
+        message_code::SSH_DISCONNECT_MAC_ERROR => "MAC error",
+        message_code::SSH_DISCONNECT_COMPRESSION_ERROR => "compression error",
+        message_code::SSH_DISCONNECT_SERVICE_NOT_AVAILABLE => "service not available",
+        message_code::SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED => {
+            "protocol version not supported"
+        }
+        message_code::SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE => "host key not verifiable",
+        message_code::SSH_DISCONNECT_CONNECTION_LOST => "connection lost",
+        message_code::SSH_DISCONNECT_BY_APPLICATION => "by application",
+        message_code::SSH_DISCONNECT_TOO_MANY_CONNECTIONS => "too many connections",
+        message_code::SSH_DISCONNECT_AUTH_CANCELLED_BY_USER => "auth cancelled by user",
+        message_code::SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE => {
+            "no more auth methods available"
+        }
+        message_code::SSH_DISCONNECT_ILLEGAL_USER_NAME => "illegal user name",
+        _ => "unknown reason",
+    }
+}
+
+fn channel_open_reason(reason_code: u32) -> &'static str {
+    match reason_code {
+        message_code::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED => "administratively prohibited",
+        message_code::SSH_OPEN_CONNECT_FAILED => "connect failed",
+        message_code::SSH_OPEN_UNKNOWN_CHANNEL_TYPE => "unknown channel type",
+        message_code::SSH_OPEN_RESOURCE_SHORTAGE => "resource shortage",
+        _ => "unknown reason",
+    }
+}
+
+#[test]
+fn typed_errors() {
+    let e = SshError::Disconnected {
+        reason_code: message_code::SSH_DISCONNECT_BY_APPLICATION,
+        description: "bye".to_string(),
+    };
+    assert_eq!(
+        e.to_string(),
+        "disconnected by server: bye (by application)"
+    );
+    assert_eq!(e.code(), error_code::SSH_ERR_DISCONNECTED);
+    assert_eq!(e.disconnect_reason(), None);
+
+    let e = SshError::AuthFailure {
+        methods: vec!["publickey".to_string(), "password".to_string()],
+        partial_success: false,
+    };
+    assert_eq!(e.to_string(), "permission denied (publickey,password)");
+
+    let e = SshError::ChannelOpenFailure {
+        reason_code: message_code::SSH_OPEN_CONNECT_FAILED,
+        description: "no route".to_string(),
+    };
+    assert_eq!(
+        e.to_string(),
+        "channel open failed: no route (connect failed)"
+    );
+
+    assert_eq!(
+        SshError::MacInvalid.disconnect_reason(),
+        Some(message_code::SSH_DISCONNECT_MAC_ERROR)
+    );
+    let e = SshError::from(io::Error::from(io::ErrorKind::BrokenPipe));
+    assert_eq!(e.code(), error_code::SSH_ERR_SYSTEM_ERROR);
+    let e = SshError::from(io::Error::from(io::ErrorKind::WouldBlock));
+    assert_eq!(e.code(), error_code::SSH_ERR_CONN_TIMEOUT);
+    assert_eq!(e.disconnect_reason(), None);
 }
@@ -0,0 +1,214 @@
+use super::connection::Connection;
+use super::data::{ByteString, Data, NameList};
+use super::error::Result;
+use super::ssh2::message_code;
+
+// Pseudo-algorithm in the client's initial KEXINIT asking for SSH_MSG_EXT_INFO.
+// [RFC8308 § 2.1](https://datatracker.ietf.org/doc/html/rfc8308#section-2.1)
+pub const EXT_INFO_CLIENT: &str = "ext-info-c";
+
+// Extensions announced by the server. It sends SSH_MSG_EXT_INFO right after its first
+// SSH_MSG_NEWKEYS and may send it again just before SSH_MSG_USERAUTH_SUCCESS, in which
+// case the later values replace the earlier ones.
+#[derive(Debug, Clone, Default)]
+pub struct ExtInfo {
+    // [RFC8308 § 3.1](https://datatracker.ietf.org/doc/html/rfc8308#section-3.1)
+    // public key algorithms accepted in user authentication
+    pub server_sig_algs: Option<NameList>,
+    // [RFC8308 § 3.2](https://datatracker.ietf.org/doc/html/rfc8308#section-3.2)
+    // compression client to server and server to client, started after authentication
+    pub delay_compression: Option<(NameList, NameList)>,
+    // [RFC8308 § 3.3](https://datatracker.ietf.org/doc/html/rfc8308#section-3.3)
+    // "p" (preferred) or "s" (supported)
+    pub no_flow_control: Option<String>,
+    // [OpenSSH PROTOCOL § 1.9](https://github.com/openssh/openssh-portable/blob/master/PROTOCOL)
+    // version of publickey-hostbound-v00@openssh.com user authentication
+    pub publickey_hostbound: Option<String>,
+    // [OpenSSH PROTOCOL § 1.11](https://github.com/openssh/openssh-portable/blob/master/PROTOCOL)
+    // version of SSH2_MSG_PING / SSH2_MSG_PONG
+    pub ping: Option<String>,
+}
+
+impl ExtInfo {
+    // byte       SSH_MSG_EXT_INFO (value 7)
+    // uint32     nr-extensions
+    // repeat the following 2 fields "nr-extensions" times:
+    //   string   extension-name
+    //   string   extension-value (binary)
+    //
+    // `payload` starts after the message code. Unknown extensions are ignored.
+    pub fn update(&mut self, payload: &mut Data) -> Result<()> {
+        let count: u32 = payload.get()?;
+        for _ in 0..count {
+            let name: String = payload.get()?;
+            match name.as_str() {
+                "server-sig-algs" => self.server_sig_algs = Some(payload.get()?),
+                "delay-compression" => {
+                    let mut value = Data(payload.get::<ByteString>()?.0);
+                    self.delay_compression = Some((value.get()?, value.get()?));
+                }
+                "no-flow-control" => self.no_flow_control = Some(payload.get()?),
+                "publickey-hostbound@openssh.com" => {
+                    self.publickey_hostbound = Some(payload.get()?)
+                }
+                "ping@openssh.com" => self.ping = Some(payload.get()?),
+                _ => {
+                    payload.get::<ByteString>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn accepts_signature(&self, algorithm: &str) -> bool {
+        self.server_sig_algs
+            .as_ref()
+            .is_some_and(|algorithms| algorithms.iter().any(|name| name == algorithm))
+    }
+}
+
+impl Connection {
+    // [RFC8308 § 2.4](https://datatracker.ietf.org/doc/html/rfc8308#section-2.4)
+    // The server sends SSH_MSG_EXT_INFO as the next packet after its first NEWKEYS,
+    // or immediately before SSH_MSG_USERAUTH_SUCCESS. The latter is only known with
+    // the next packet, so it is held until then. Anywhere else it is ignored.
+    pub(crate) fn recv_ext_info(&mut self, payload: Data, expected: bool) -> Result<()> {
+        if expected {
+            self.update_ext_info(payload)
+        } else if self.session.keys.is_some() && !self.session.authenticated {
+            self.session.held_ext_info = Some(payload);
+            Ok(())
+        } else {
+            tracing::warn!("ignoring SSH_MSG_EXT_INFO out of place");
+            Ok(())
+        }
+    }
+
+    pub(crate) fn update_ext_info(&mut self, mut payload: Data) -> Result<()> {
+        payload.expect(message_code::SSH_MSG_EXT_INFO)?;
+        self.session.ext_info.update(&mut payload)?;
+        tracing::info!("server extensions: {:?}", self.session.ext_info);
+        Ok(())
+    }
+}
+
+#[test]
+fn parse_ext_info() {
+    let mut delay_compression = Data::new();
+    delay_compression
+        .put(&vec!["zlib@openssh.com".to_string(), "none".to_string()])
+        .put(&vec!["zlib@openssh.com".to_string()]);
+    let mut payload = Data::new();
+    payload
+        .put(&6u32)
+        .put(&"server-sig-algs".to_string())
+        .put(&"ssh-ed25519,rsa-sha2-256,rsa-sha2-512".to_string())
+        .put(&"unknown@example.com".to_string())
+        .put(&ByteString(vec![0, 1, 2]))
+        .put(&"delay-compression".to_string())
+        .put(&ByteString(delay_compression.into_inner()))
+        .put(&"no-flow-control".to_string())
+        .put(&"p".to_string())
+        .put(&"publickey-hostbound@openssh.com".to_string())
+        .put(&"0".to_string())
+        .put(&"ping@openssh.com".to_string())
+        .put(&"0".to_string());
+
+    let mut ext_info = ExtInfo::default();
+    assert!(!ext_info.accepts_signature("rsa-sha2-512"));
+    ext_info.update(&mut payload).unwrap();
+    assert!(payload.0.is_empty());
+    assert!(ext_info.accepts_signature("rsa-sha2-512"));
+    assert!(!ext_info.accepts_signature("ssh-rsa"));
+    assert_eq!(
+        ext_info.delay_compression,
+        Some((
+            vec!["zlib@openssh.com".to_string(), "none".to_string()],
+            vec!["zlib@openssh.com".to_string()]
+        ))
+    );
+    assert_eq!(ext_info.no_flow_control.as_deref(), Some("p"));
+    assert_eq!(ext_info.publickey_hostbound.as_deref(), Some("0"));
+    assert_eq!(ext_info.ping.as_deref(), Some("0"));
+
+    // the post-authentication message replaces earlier values
+    let mut payload = Data::new();
+    payload
+        .put(&1u32)
+        .put(&"server-sig-algs".to_string())
+        .put(&"rsa-sha2-256".to_string());
+    ext_info.update(&mut payload).unwrap();
+    assert!(!ext_info.accepts_signature("rsa-sha2-512"));
+    assert_eq!(ext_info.ping.as_deref(), Some("0"));
+}
+
+#[test]
+fn ext_info_only_where_allowed() {
+    use super::connection::Event;
+    use super::test_server::TestServer;
+
+    let ext_info = |name: &str, value: &str| {
+        let mut payload = Data::new();
+        payload
+            .put(&message_code::SSH_MSG_EXT_INFO)
+            .put(&1u32)
+            .put(&name.to_string())
+            .put(&value.to_string());
+        payload
+    };
+    let ignore = || {
+        let mut payload = Data::new();
+        payload
+            .put(&message_code::SSH_MSG_IGNORE)
+            .put(&ByteString(vec![]));
+        payload
+    };
+
+    // as the first packet after the first NEWKEYS
+    let mut server = TestServer::new();
+    let mut connection = TestServer::client();
+    connection.feed(&server.hello()).unwrap();
+    server.key_exchange(&mut connection).unwrap();
+    connection
+        .feed(&server.packet(&ext_info("server-sig-algs", "rsa-sha2-512")))
+        .unwrap();
+    connection.accept_host_key().unwrap();
+    assert!(connection
+        .session
+        .ext_info
+        .accepts_signature("rsa-sha2-512"));
+
+    // later on only right before USERAUTH_SUCCESS
+    let mut server = TestServer::new();
+    let mut connection = TestServer::client();
+    server.handshake(&mut connection);
+    let mut stream = server.packet(&ext_info("ping@openssh.com", "0"));
+    stream.extend(server.packet(&ignore()));
+    connection.feed(&stream).unwrap();
+    assert_eq!(connection.session.ext_info.ping, None);
+
+    let mut stream = server.packet(&ext_info("no-flow-control", "p"));
+    stream.extend(server.packet(Data::new().put(&message_code::SSH_MSG_USERAUTH_SUCCESS)));
+    connection.feed(&stream).unwrap();
+    assert!(matches!(
+        connection.poll_event(),
+        Some(Event::Authenticated)
+    ));
+    assert_eq!(
+        connection.session.ext_info.no_flow_control.as_deref(),
+        Some("p")
+    );
+    assert_eq!(connection.session.ext_info.ping, None);
+
+    // and not after it, nor after a re-key
+    connection
+        .feed(&server.packet(&ext_info("ping@openssh.com", "0")))
+        .unwrap();
+    connection.feed(&server.kexinit()).unwrap();
+    server.key_exchange(&mut connection).unwrap();
+    connection
+        .feed(&server.packet(&ext_info("ping@openssh.com", "0")))
+        .unwrap();
+    assert!(connection.poll_event().is_none());
+    assert_eq!(connection.session.ext_info.ping, None);
+}
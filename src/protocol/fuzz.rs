@@ -0,0 +1,116 @@
+// Entry points for the cargo-fuzz targets in fuzz/. Each one hands bytes from a
+// hostile server to a decoder; an error is a fine outcome, a panic or a hang is not.
+
+use super::{
+    client::{SessionBuilder, SshClient},
+    data::{ByteString, Data},
+    key_exchange_init::{AlgList, KexAlgorithms},
+    known_hosts::HostKeyPolicy,
+    packet_reader::PacketReader,
+    ssh2::message_code,
+    version_exchange::{read_version, Version},
+};
+use crate::crypto::{
+    compression::{zlib::Zlib, CompressAdapter},
+    PubKey,
+};
+use crate::network::tcp_client::TcpClient;
+use std::cell::RefCell;
+use std::io::Write;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::OnceLock;
+
+// SSH-protoversion-softwareversion SP comments CR LF, possibly after other lines
+pub fn version(data: &[u8]) {
+    let _ = Data(data.to_vec()).get::<Version>();
+
+    let mut reader = PacketReader::new();
+    reader.feed(data);
+    let _ = read_version(&mut reader, &mut Vec::new());
+}
+
+// The server's SSH_MSG_KEXINIT, negotiated against our default algorithms.
+// AlgList is only ever sent, as the KexAlgorithms it turns into.
+pub fn kexinit(data: &[u8]) {
+    let mut payload = Data(data.to_vec());
+    let Ok(server) = payload
+        .expect(message_code::SSH_MSG_KEXINIT)
+        .and_then(|_| payload.get::<KexAlgorithms>())
+    else {
+        return;
+    };
+    let client = AlgList::default().to_kex_algorithms();
+    let _ = client.negotiate(&server);
+    let _ = server.strict_kex();
+}
+
+// Everything the server sends from its identification string on, as a
+// Connection takes it apart: the version exchange, the binary packet protocol,
+// transport layer messages, key exchange and user authentication.
+pub fn recv(data: &[u8]) {
+    let mut connection = SessionBuilder::create_session().connection().unwrap();
+    let _ = connection.feed(data);
+    while connection.poll_event().is_some() {}
+    while connection.poll_transmit().is_some() {}
+}
+
+// A connection protocol message as Channel::furiwake dispatches it.
+pub fn channel(data: &[u8]) {
+    thread_local! {
+        static CLIENT: RefCell<SshClient> = RefCell::new(client_reading(&[]).0);
+    }
+    let mut payload = Data(data.to_vec());
+    let Ok(code) = payload.get::<u8>() else {
+        return;
+    };
+    CLIENT.with_borrow_mut(|client| {
+        let _ = client.pack_channel().furiwake(code, &mut payload);
+    });
+}
+
+//   string    K_S, the server's public host key blob
+//   string    signature blob
+//   byte[n]   the signed data
+pub fn public_key(data: &[u8]) {
+    let mut data = Data(data.to_vec());
+    let (Ok(ByteString(host_key)), Ok(ByteString(signature))) = (data.get(), data.get()) else {
+        return;
+    };
+    for algorithm in [PubKey::SshEd25519, PubKey::RsaSha2_256, PubKey::RsaSha2_512] {
+        let _ = algorithm.verify(&host_key, &signature, &data.0);
+    }
+}
+
+// Compressed payloads of consecutive packets, each one as a string, sharing a
+// zlib stream the way packets do once compression is on.
+pub fn decompress(data: &[u8]) {
+    let mut data = Data(data.to_vec());
+    let mut zlib = Zlib::new(false);
+    while let Ok(ByteString(payload)) = data.get() {
+        if zlib.decompress(payload).is_err() {
+            return;
+        }
+    }
+}
+
+// A client connected over loopback to a server that has sent `data` and closed
+// its side. What the client sends stays unread in the socket buffer, as does
+// `data`, so input beyond the buffer size is cut off rather than blocking.
+fn client_reading(data: &[u8]) -> (SshClient, TcpStream) {
+    static LISTENER: OnceLock<TcpListener> = OnceLock::new();
+    let listener = LISTENER.get_or_init(|| TcpListener::bind("127.0.0.1:0").unwrap());
+    let address = listener.local_addr().unwrap();
+
+    let client = TcpClient::new(address, None).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    server.set_nonblocking(true).unwrap();
+    let _ = server.write(data);
+    server.shutdown(Shutdown::Write).unwrap();
+
+    let client = SessionBuilder::create_session()
+        .known_hosts_path("/dev/null")
+        .host_key_policy(HostKeyPolicy::Strict)
+        .build(client, address)
+        .unwrap();
+    (client, server)
+}
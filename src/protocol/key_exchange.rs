@@ -1,16 +1,22 @@
-use super::client::SshClient;
+use super::connection::{Connection, Event};
 use super::data::{ByteString, Data, Mpint};
-use super::session::NewKeys;
+use super::error::{Result, SshError};
+use super::session::{NewKeys, Session};
 use super::ssh2::message_code;
-use crate::crypto::compression::none::NoneCompress;
-use crate::crypto::encryption::chachapoly::ChaCha20Poly1305;
-use crate::crypto::key_exchange::KexMethodAdapter;
+use crate::crypto::key_exchange::{
+    curve::{Curve25519Sha256, Curve448Sha512},
+    dh, ecdh, hybrid, KexMethodAdapter,
+};
 use crate::crypto::mac::none::NoneMac;
 use nom::AsBytes;
+use std::time::Instant;
+
+// Enough key material for the largest negotiable key (chacha20-poly1305, hmac-sha2-512).
+const KEY_MATERIAL_SIZE: usize = 64;
 
 #[derive(Debug, Clone)]
 pub struct Kex {
-    pub shared_secret_key: Mpint,
+    pub shared_secret_key: Vec<u8>,
     pub exchange_hash: Vec<u8>,
     pub session_id: Vec<u8>,
     pub client_initial_iv: Vec<u8>,
@@ -21,100 +27,299 @@ pub struct Kex {
     pub server_integrity_key: Vec<u8>,
 }
 
-impl SshClient {
-    pub fn key_exchange<Method: KexMethodAdapter>(&mut self) -> anyhow::Result<()> {
-        let mut method = Method::new();
-
-        let client_public_key = ByteString(method.public_key());
-        self.send_pubkey(&client_public_key)?;
-
-        let (server_public_host_key, server_public_key) = self.verify_signature_and_new_keys()?;
-
-        let shared_secret = Mpint(method.shared_secret(&server_public_key.0));
-        let exchange_hash = Kex::exchange_hash::<Method>(
-            &method,
-            &ByteString({
-                let mut data = Data::new();
-                data.put(
-                    self.session
-                        .client_version
-                        .as_mut()
-                        .unwrap()
-                        .set_crnl(false),
-                );
-                data.into_inner()
-            }),
-            &ByteString({
-                let mut data = Data::new();
-                data.put(
-                    self.session
-                        .server_version
-                        .as_mut()
-                        .unwrap()
-                        .set_crnl(false),
-                );
-                data.into_inner()
-            }),
-            &ByteString({
-                let mut data = Data::new();
-                data.put(&message_code::SSH_MSG_KEXINIT)
-                    .put(self.session.client_kex.as_ref().unwrap());
-                data.into_inner()
-            }),
-            &ByteString({
-                let mut data = Data::new();
-                data.put(&message_code::SSH_MSG_KEXINIT)
-                    .put(self.session.server_kex.as_ref().unwrap());
-                data.into_inner()
-            }),
+// Where the current key exchange stands. Packets are taken one at a time, so
+// every step waits for one message from the server.
+pub enum KexState {
+    Idle,
+    // our SSH_MSG_KEXINIT is sent, the server's has not arrived
+    Init,
+    // SSH_MSG_KEX_DH_GEX_REQUEST is sent, waiting for the group
+    Group(dh::DiffieHellmanGroupExchangeSha256),
+    // our public key is sent, waiting for the message `reply`
+    Reply {
+        method: Box<dyn KexMethodAdapter>,
+        client_public_key: Vec<u8>,
+        reply: u8,
+    },
+    // the application has yet to accept the host key of the initial exchange
+    HostKey {
+        kex: Kex,
+        host_key: ByteString,
+    },
+    // our SSH_MSG_NEWKEYS is sent, waiting for the server's
+    NewKeys(Kex),
+}
+
+impl Connection {
+    // Starts a key exchange, either the initial one or a re-exchange
+    // [RFC4253 § 9](https://datatracker.ietf.org/doc/html/rfc4253#section-9).
+    // Everything else the application sends waits until it is over.
+    pub(crate) fn start_key_exchange(&mut self) -> Result<()> {
+        self.session.kex_in_progress = true;
+        self.send_kexinit()?;
+        self.kex = KexState::Init;
+        Ok(())
+    }
+
+    // Both KEXINITs are known: the negotiated method sends its first message.
+    pub(crate) fn start_method(&mut self) -> Result<()> {
+        use crate::crypto::Kex as Method;
+        let method: Box<dyn KexMethodAdapter> = match self.session.get_algorithms()?.key_exchange {
+            Method::MlKem768X25519Sha256 => Box::new(hybrid::MlKem768X25519Sha256::new()),
+            Method::Sntrup761X25519Sha512 => Box::new(hybrid::Sntrup761X25519Sha512::new()),
+            Method::Curve25519Sha256 => Box::new(Curve25519Sha256::new()),
+            Method::Curve448Sha512 => Box::new(Curve448Sha512::new()),
+            Method::EcdhSha2Nistp256 => Box::new(ecdh::EcdhSha2Nistp256::new()),
+            Method::EcdhSha2Nistp384 => Box::new(ecdh::EcdhSha2Nistp384::new()),
+            Method::EcdhSha2Nistp521 => Box::new(ecdh::EcdhSha2Nistp521::new()),
+            #[cfg(feature = "deprecated-dh-group1-sha1")]
+            Method::DiffieHellmanGroup1Sha1 => Box::new(dh::DiffieHellmanGroup1Sha1::new()),
+            Method::DiffieHellmanGroup14Sha1 => Box::new(dh::DiffieHellmanGroup14Sha1::new()),
+            Method::DiffieHellmanGroup14Sha256 => Box::new(dh::DiffieHellmanGroup14Sha256::new()),
+            Method::DiffieHellmanGroup16Sha512 => Box::new(dh::DiffieHellmanGroup16Sha512::new()),
+            Method::DiffieHellmanGroup18Sha512 => Box::new(dh::DiffieHellmanGroup18Sha512::new()),
+            Method::DiffieHellmanGroupExchangeSha256 => {
+                return self.group_request(dh::DiffieHellmanGroupExchangeSha256::new())
+            }
+        };
+        self.send_pubkey(
+            method,
+            message_code::SSH2_MSG_KEX_ECDH_INIT,
+            message_code::SSH2_MSG_KEX_ECDH_REPLY,
+        )
+    }
+
+    // Re-keying is due after `rekey_limit` bytes or `rekey_interval` since the last exchange.
+    // Like OpenSSH, the client does not start one before the user is authenticated,
+    // so that it cannot race with the start of delayed compression.
+    pub fn needs_rekey(&self) -> bool {
+        !self.session.kex_in_progress
+            && self.session.authenticated
+            && (self.session.bytes_since_kex >= self.config.rekey_limit
+                || self.session.last_kex.elapsed() >= self.config.rekey_interval)
+    }
+
+    // Key exchange messages from the server, in the order the state expects them.
+    pub(crate) fn handle_key_exchange(&mut self, payload: Data) -> Result<()> {
+        let code = payload.0.first().copied().unwrap_or_default();
+        match std::mem::replace(&mut self.kex, KexState::Idle) {
+            KexState::Group(method) if code == message_code::SSH2_MSG_KEX_DH_GEX_GROUP => {
+                self.recv_group(method, payload)
+            }
+            KexState::Reply {
+                method,
+                client_public_key,
+                reply,
+            } if code == reply => self.recv_kex_reply(method, client_public_key, reply, payload),
+            KexState::NewKeys(kex) if code == message_code::SSH_MSG_NEWKEYS => {
+                self.recv_newkeys(kex)
+            }
+            _ => Err(SshError::UnexpectedMessage(code)),
+        }
+    }
+
+    // [RFC4419 § 3](https://datatracker.ietf.org/doc/html/rfc4419#section-3)
+    // The group is negotiated first, then e and f are exchanged as in a fixed group exchange.
+    //
+    // byte     SSH_MSG_KEX_DH_GEX_REQUEST
+    // uint32   min
+    // uint32   n
+    // uint32   max
+    fn group_request(&mut self, method: dh::DiffieHellmanGroupExchangeSha256) -> Result<()> {
+        let (min, preferred, max) = method.request();
+        self.write_packet(
+            Data::new()
+                .put(&message_code::SSH2_MSG_KEX_DH_GEX_REQUEST)
+                .put(&min)
+                .put(&preferred)
+                .put(&max),
+        )?;
+        self.kex = KexState::Group(method);
+        Ok(())
+    }
+
+    // byte     SSH_MSG_KEX_DH_GEX_GROUP
+    // mpint    p, safe prime
+    // mpint    g, generator for subgroup in GF(p)
+    fn recv_group(
+        &mut self,
+        mut method: dh::DiffieHellmanGroupExchangeSha256,
+        mut payload: Data,
+    ) -> Result<()> {
+        payload.expect(message_code::SSH2_MSG_KEX_DH_GEX_GROUP)?;
+        let prime: Mpint = payload.get()?;
+        let generator: Mpint = payload.get()?;
+        if let Err(e) = method.set_group(&prime.to_unsigned()?, &generator.to_unsigned()?) {
+            return self.fail(e);
+        }
+
+        self.send_pubkey(
+            Box::new(method),
+            message_code::SSH2_MSG_KEX_DH_GEX_INIT,
+            message_code::SSH2_MSG_KEX_DH_GEX_REPLY,
+        )
+    }
+
+    // byte     SSH_MSG_KEX_ECDH_INIT (SSH_MSG_KEXDH_INIT, SSH_MSG_KEX_DH_GEX_INIT)
+    // string   Q_C, client's ephemeral public key octet string (mpint e)
+    fn send_pubkey(
+        &mut self,
+        method: Box<dyn KexMethodAdapter>,
+        code: u8,
+        reply: u8,
+    ) -> Result<()> {
+        let client_public_key = method.public_key();
+        let mut payload = vec![code];
+        method.encode_public_key(&client_public_key, &mut payload);
+        self.write_packet(&Data(payload))?;
+        self.kex = KexState::Reply {
+            method,
+            client_public_key,
+            reply,
+        };
+        Ok(())
+    }
+
+    // byte     SSH_MSG_KEX_ECDH_REPLY (SSH_MSG_KEXDH_REPLY, SSH_MSG_KEX_DH_GEX_REPLY)
+    // string   K_S, server's public host key
+    // string   Q_S, server's ephemeral public key octet string (mpint f)
+    // string   the signature on the exchange hash
+    //
+    // An mpint has the same framing as a string, so f is read as its raw bytes.
+    fn recv_kex_reply(
+        &mut self,
+        mut method: Box<dyn KexMethodAdapter>,
+        client_public_key: Vec<u8>,
+        reply: u8,
+        mut payload: Data,
+    ) -> Result<()> {
+        payload.expect(reply)?;
+        let server_public_host_key: ByteString = payload.get()?;
+        let ByteString(server_public_key) = payload.get()?;
+        let signature: ByteString = payload.get()?;
+
+        let shared_secret = method.shared_secret(&server_public_key)?;
+        let exchange_hash = Kex::exchange_hash(
+            method.as_ref(),
+            &mut self.session,
             &server_public_host_key,
             &client_public_key,
             &server_public_key,
             &shared_secret,
         );
-        let kex = Kex::new::<Method>(method, exchange_hash, &shared_secret);
 
-        // New Keys
-        self.recv()?.expect(message_code::SSH_MSG_NEWKEYS);
-        self.send(Data::new().put(&message_code::SSH_MSG_NEWKEYS))?;
+        // The server signs H with its host key; anything else means we are not
+        // talking to the owner of K_S.
+        let algorithms = self.session.get_algorithms()?;
+        if let Err(e) =
+            algorithms
+                .public_key
+                .verify(&server_public_host_key.0, &signature.0, &exchange_hash)
+        {
+            return self.fail(e);
+        }
+
+        // The session identifier is the exchange hash of the first key exchange
+        // and stays the same across re-exchanges.
+        let session_id = match &self.session.keys {
+            Some(keys) => keys.session_id.clone(),
+            None => exchange_hash.clone(),
+        };
+        let kex = Kex::new(method.as_ref(), exchange_hash, session_id, &shared_secret);
 
+        match &self.session.server_host_key {
+            // the host key must not change within a connection
+            Some(host_key) if host_key.0 != server_public_host_key.0 => self.fail(
+                SshError::HostKeyNotVerifiable("host key changed during re-key".to_string()),
+            ),
+            Some(_) => self.send_newkeys(kex),
+            None => {
+                self.push_event(Event::HostKey(ByteString(server_public_host_key.0.clone())));
+                self.kex = KexState::HostKey {
+                    kex,
+                    host_key: server_public_host_key,
+                };
+                Ok(())
+            }
+        }
+    }
+
+    // Trusts the host key from Event::HostKey and carries on with the packets
+    // that arrived in the meantime. A host key that is not trusted ends the
+    // connection instead, with a SSH_MSG_DISCONNECT.
+    pub fn accept_host_key(&mut self) -> Result<()> {
+        let KexState::HostKey { kex, host_key } = std::mem::replace(&mut self.kex, KexState::Idle)
+        else {
+            return Err(SshError::Protocol("no host key to accept".to_string()));
+        };
+        self.session.server_host_key = Some(host_key);
+        self.send_newkeys(kex)?;
+        self.process()
+    }
+
+    // Everything we send after SSH_MSG_NEWKEYS uses the new keys; with strict key
+    // exchange the sequence number restarts with them.
+    fn send_newkeys(&mut self, kex: Kex) -> Result<()> {
+        self.write_packet(Data::new().put(&message_code::SSH_MSG_NEWKEYS))?;
+        if self.session.strict_kex {
+            self.session.client_sequence_number = 0;
+        }
+
+        let algorithms = self.session.get_algorithms()?;
         self.session.client_method = NewKeys::new(
-            Box::new(ChaCha20Poly1305::new(
+            algorithms.client_encryption.new_adapter(
                 &kex.client_encryption_key,
-                &kex.server_encryption_key,
-            )),
-            Box::new(NoneMac {}),
-            Box::new(NoneCompress {}),
+                &kex.client_initial_iv,
+                algorithms.client_mac.is_some_and(|mac| mac.is_etm()),
+            ),
+            match algorithms.client_mac {
+                Some(mac) => mac.new_adapter(&kex.client_integrity_key),
+                None => Box::new(NoneMac {}),
+            },
+            algorithms.client_compress.new_adapter(),
         );
+        self.kex = KexState::NewKeys(kex);
+        Ok(())
+    }
+
+    // Everything the server sends after SSH_MSG_NEWKEYS uses the new keys, which
+    // ends the exchange. Messages held back meanwhile, in either direction, are
+    // handled now.
+    fn recv_newkeys(&mut self, kex: Kex) -> Result<()> {
+        if self.session.strict_kex {
+            self.session.server_sequence_number = 0;
+        }
+
+        let algorithms = self.session.get_algorithms()?;
         self.session.server_method = NewKeys::new(
-            Box::new(ChaCha20Poly1305::new(
-                &kex.client_encryption_key,
+            algorithms.server_encryption.new_adapter(
                 &kex.server_encryption_key,
-            )),
-            Box::new(NoneMac {}),
-            Box::new(NoneCompress {}),
+                &kex.server_initial_iv,
+                algorithms.server_mac.is_some_and(|mac| mac.is_etm()),
+            ),
+            match algorithms.server_mac {
+                Some(mac) => mac.new_adapter(&kex.server_integrity_key),
+                None => Box::new(NoneMac {}),
+            },
+            algorithms.server_compress.new_adapter(),
         );
+        let initial = self.session.keys.is_none();
         self.session.keys = Some(kex);
-        Ok(())
-    }
+        self.session.ext_info_expected = initial;
 
-    fn send_pubkey(&mut self, pubkey: &ByteString) -> anyhow::Result<()> {
-        let mut payload = Data::new();
-        self.send(
-            payload
-                .put(&message_code::SSH2_MSG_KEX_ECDH_INIT)
-                .put(pubkey),
-        )
-    }
-
-    fn verify_signature_and_new_keys(&mut self) -> anyhow::Result<(ByteString, ByteString)> {
-        println!("verify signature");
-        let mut payload = self.recv()?;
-        println!("{:?}", payload);
-        payload.expect(message_code::SSH2_MSG_KEX_ECDH_REPLY);
-        let server_public_host_key: ByteString = payload.get();
-        let server_public_key: ByteString = payload.get();
-        Ok((server_public_host_key, server_public_key))
+        self.session.kex_in_progress = false;
+        self.session.bytes_since_kex = 0;
+        self.session.last_kex = Instant::now();
+        while let Some(payload) = self.session.pending.pop_front() {
+            self.write_packet(&payload)?;
+        }
+        while let Some(payload) = self.session.deferred.pop_front() {
+            self.handle_connection(payload)?;
+        }
+        if initial {
+            self.service_request()?;
+        }
+        Ok(())
     }
 }
 
@@ -126,41 +331,49 @@ impl Kex {
     // Integrity key client to server: HASH(K || H || "E" || session_id)
     // Integrity key server to client: HASH(K || H || "F" || session_id)
 
+    // K is encoded as in the exchange hash, normally as mpint.
     // K1 = HASH(K || H || X || session_id) (X is e.g., "A")
     // K2 = HASH(K || H || K1)
     // K3 = HASH(K || H || K1 || K2)
     // ...
     // key = K1 || K2 || K3 || ...
-    pub fn new<T: KexMethodAdapter>(
-        method: T,
+    pub fn new(
+        method: &dyn KexMethodAdapter,
         exchange_hash: Vec<u8>,
-        shared_secret_key: &Mpint,
+        session_id: Vec<u8>,
+        shared_secret_key: &[u8],
     ) -> Self {
+        let mut encoded_shared_secret_key = Vec::new();
+        method.encode_shared_secret(shared_secret_key, &mut encoded_shared_secret_key);
+        let shared_secret_key = encoded_shared_secret_key;
+
         let mut keys = Vec::new();
         for alphabet in ['A', 'B', 'C', 'D', 'E', 'F'] {
             let mut seed = Data::new();
-            seed.put(shared_secret_key)
+            seed.put(&shared_secret_key.as_bytes())
                 .put(&exchange_hash.as_bytes())
                 .put(&(alphabet as u8))
-                .put(&exchange_hash.as_bytes());
+                .put(&session_id.as_bytes());
 
             let mut key = Data::new();
             key.put(&method.hash(&seed.into_inner()).as_bytes());
 
-            let mut seed = Data::new();
-            seed.put(shared_secret_key)
-                .put(&exchange_hash.as_bytes())
-                .put(&key);
+            while key.0.len() < KEY_MATERIAL_SIZE {
+                let mut seed = Data::new();
+                seed.put(&shared_secret_key.as_bytes())
+                    .put(&exchange_hash.as_bytes())
+                    .put(&key);
 
-            key.put(&method.hash(&seed.into_inner()).as_bytes());
+                key.put(&method.hash(&seed.into_inner()).as_bytes());
+            }
 
             keys.push(key.into_inner());
         }
 
         Kex {
-            shared_secret_key: shared_secret_key.clone(),
-            exchange_hash: exchange_hash.clone(),
-            session_id: exchange_hash,
+            shared_secret_key,
+            exchange_hash,
+            session_id,
             client_initial_iv: keys[0].clone(),
             server_initial_iv: keys[1].clone(),
             client_encryption_key: keys[2].clone(),
@@ -175,29 +388,200 @@ impl Kex {
     // string   I_C, payload of the client's SSH_MSG_KEXINIT
     // string   I_S, payload of the server's SSH_MSG_KEXINIT
     // string   K_S, server's public host key
-    // string   Q_C, client's ephemeral public key octet string
-    // string   Q_S, server's ephemeral public key octet string
-    // mpint    K,   shared secret
-    fn exchange_hash<T: KexMethodAdapter>(
-        method: &T,
-        client_version: &ByteString,
-        server_version: &ByteString,
-        client_kex: &ByteString,
-        server_kex: &ByteString,
+    // uint32   min, n, max, mpint p, mpint g (group exchange only)
+    // string   Q_C, client's ephemeral public key octet string (mpint e for DH)
+    // string   Q_S, server's ephemeral public key octet string (mpint f for DH)
+    // mpint    K,   shared secret (string for the hybrid post-quantum methods)
+    pub(crate) fn exchange_hash(
+        method: &dyn KexMethodAdapter,
+        session: &mut Session,
         server_public_host_key: &ByteString,
-        client_public_key: &ByteString,
-        server_public_key: &ByteString,
-        shared_secret_key: &Mpint,
+        client_public_key: &[u8],
+        server_public_key: &[u8],
+        shared_secret_key: &[u8],
     ) -> Vec<u8> {
         let mut data = Data::new();
-        data.put(client_version)
-            .put(server_version)
-            .put(client_kex)
-            .put(server_kex)
-            .put(server_public_host_key)
-            .put(client_public_key)
-            .put(server_public_key)
-            .put(shared_secret_key);
+        for version in [&mut session.client_version, &mut session.server_version] {
+            let mut string = Data::new();
+            string.put(version.as_mut().unwrap().set_crnl(false));
+            data.put(&ByteString(string.into_inner()));
+        }
+        for kexinit in [&session.client_kex, &session.server_kex] {
+            let mut string = Data::new();
+            string
+                .put(&message_code::SSH_MSG_KEXINIT)
+                .put(kexinit.as_ref().unwrap());
+            data.put(&ByteString(string.into_inner()));
+        }
+        data.put(server_public_host_key);
+        method.encode_group(&mut data.0);
+        method.encode_public_key(client_public_key, &mut data.0);
+        method.encode_public_key(server_public_key, &mut data.0);
+        method.encode_shared_secret(shared_secret_key, &mut data.0);
         method.hash(&data.into_inner())
     }
 }
+
+#[test]
+fn rekey_keeps_session_id() {
+    let shared_secret = [0x42; 32];
+    let first = Kex::new(
+        &Curve25519Sha256::new(),
+        vec![1; 32],
+        vec![1; 32],
+        &shared_secret,
+    );
+    let rekey = Kex::new(
+        &Curve25519Sha256::new(),
+        vec![2; 32],
+        vec![1; 32],
+        &shared_secret,
+    );
+    let fresh = Kex::new(
+        &Curve25519Sha256::new(),
+        vec![2; 32],
+        vec![2; 32],
+        &shared_secret,
+    );
+
+    assert_eq!(rekey.session_id, first.session_id);
+    assert_eq!(rekey.exchange_hash, vec![2; 32]);
+    assert_ne!(rekey.client_encryption_key, first.client_encryption_key);
+    assert_ne!(rekey.client_encryption_key, fresh.client_encryption_key);
+}
+
+#[test]
+fn rekey_on_limits_and_server_request() {
+    use super::client::SessionBuilder;
+    use super::key_exchange_init::SERVER_KEXINIT_PAYLOAD;
+    use std::time::Duration;
+
+    // unencrypted, padded to 8 bytes
+    let packet = |payload: &Data| {
+        let padding_length = 4 + (8 - (payload.0.len() + 9) % 8) % 8;
+        let mut packet = Data::new();
+        packet
+            .put(&((payload.0.len() + padding_length + 1) as u32))
+            .put(&(padding_length as u8))
+            .put(&payload.0.as_slice())
+            .put(&vec![0; padding_length].as_slice());
+        packet.into_inner()
+    };
+    let sent = |connection: &mut Connection| {
+        std::iter::from_fn(|| connection.poll_transmit())
+            .map(|packet| packet[5])
+            .collect::<Vec<_>>()
+    };
+    let channel_data = || {
+        let mut payload = Data::new();
+        payload
+            .put(&message_code::SSH_MSG_CHANNEL_DATA)
+            .put(&0u32)
+            .put(&ByteString(vec![7; 200]));
+        payload
+    };
+    // past the initial key exchange and user authentication, still unencrypted
+    let established = || {
+        let mut connection = SessionBuilder::create_session().connection().unwrap();
+        connection.key_exchange.key_exchange = vec!["curve25519-sha256".to_string()];
+        connection.feed(b"SSH-2.0-OpenSSH_9.6\r\n").unwrap();
+        connection.kex = KexState::Idle;
+        connection.session.kex_in_progress = false;
+        connection.session.keys = Some(Kex::new(
+            &Curve25519Sha256::new(),
+            vec![1; 32],
+            vec![1; 32],
+            &[0x42; 32],
+        ));
+        connection.session.authenticated = true;
+        connection.session.bytes_since_kex = 0;
+        while connection.poll_transmit().is_some() {}
+        connection
+    };
+
+    // the byte limit: the packet that crosses it goes out, the next one starts
+    // a re-key and waits for it
+    let mut connection = established();
+    connection.config.rekey_limit = 100;
+    connection.send(&channel_data()).unwrap();
+    assert_eq!(sent(&mut connection), [message_code::SSH_MSG_CHANNEL_DATA]);
+    connection.send(&channel_data()).unwrap();
+    assert_eq!(sent(&mut connection), [message_code::SSH_MSG_KEXINIT]);
+    assert_eq!(connection.session.pending.len(), 1);
+
+    // not before the user is authenticated, as delayed compression starts then
+    let mut connection = established();
+    connection.config.rekey_limit = 100;
+    connection.session.authenticated = false;
+    connection.session.bytes_since_kex = 1000;
+    connection.send(&channel_data()).unwrap();
+    assert_eq!(sent(&mut connection), [message_code::SSH_MSG_CHANNEL_DATA]);
+
+    // the time limit, checked whenever a packet arrives
+    let mut connection = established();
+    connection.config.rekey_interval = Duration::ZERO;
+    let mut ignore = Data::new();
+    ignore
+        .put(&message_code::SSH_MSG_IGNORE)
+        .put(&ByteString(vec![]));
+    connection.feed(&packet(&ignore)).unwrap();
+    assert_eq!(sent(&mut connection), [message_code::SSH_MSG_KEXINIT]);
+    assert!(connection.session.kex_in_progress);
+
+    // a KEXINIT from the server is answered with ours and the exchange runs as
+    // usual, with the application's packets held back until the server's NEWKEYS
+    let mut connection = established();
+    connection
+        .feed(&packet(&Data(SERVER_KEXINIT_PAYLOAD.to_vec())))
+        .unwrap();
+    assert_eq!(
+        sent(&mut connection),
+        [
+            message_code::SSH_MSG_KEXINIT,
+            message_code::SSH2_MSG_KEX_ECDH_INIT
+        ]
+    );
+    connection.send(&channel_data()).unwrap();
+    assert!(sent(&mut connection).is_empty());
+    // as is what the server sent before it saw our KEXINIT
+    connection.feed(&packet(&channel_data())).unwrap();
+    assert!(connection.poll_event().is_none());
+
+    let kex = connection.session.get_keys().unwrap();
+    connection.kex = KexState::NewKeys(kex);
+    connection
+        .feed(&packet(Data::new().put(&message_code::SSH_MSG_NEWKEYS)))
+        .unwrap();
+    assert!(!connection.session.kex_in_progress);
+    assert_eq!(
+        connection.session.get_keys().unwrap().session_id,
+        vec![1; 32]
+    );
+    assert_eq!(sent(&mut connection), [message_code::SSH_MSG_CHANNEL_DATA]);
+    assert!(matches!(
+        connection.poll_event(),
+        Some(Event::ChannelData { recipient_channel: 0, data }) if data == [7; 200]
+    ));
+}
+
+#[test]
+fn host_key_change_on_rekey() {
+    use super::test_server::TestServer;
+
+    let mut server = TestServer::new();
+    let mut connection = server.connect();
+
+    server.host_key =
+        ssh_key::PrivateKey::random(&mut rand_core::OsRng, ssh_key::Algorithm::Ed25519).unwrap();
+    connection.feed(&server.kexinit()).unwrap();
+    assert!(matches!(
+        server.key_exchange(&mut connection),
+        Err(SshError::HostKeyNotVerifiable(_))
+    ));
+    let mut disconnect = server.read(&mut connection).pop().unwrap();
+    disconnect.expect(message_code::SSH_MSG_DISCONNECT).unwrap();
+    assert_eq!(
+        disconnect.get::<u32>().unwrap(),
+        message_code::SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE
+    );
+}
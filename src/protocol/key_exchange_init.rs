@@ -1,11 +1,22 @@
-use super::client::SshClient;
+use super::connection::Connection;
 use super::data::{Data, DataType, NameList};
+use super::error::{Result, SshError};
+use super::ext_info::EXT_INFO_CLIENT;
+use super::key_exchange::KexState;
 use super::ssh2::message_code;
 use crate::crypto::{Compress, Enc, Kex, Mac, PubKey};
 use rand::Rng;
 use std::fmt::Debug;
+use std::str::FromStr;
 
-#[derive(Debug, Clone)]
+// [OpenSSH PROTOCOL § 1.10](https://github.com/openssh/openssh-portable/blob/master/PROTOCOL)
+// Strict key exchange is offered through pseudo-algorithms in the initial SSH_MSG_KEXINIT.
+// Once both sides support it, sequence numbers restart at zero after every SSH_MSG_NEWKEYS
+// and the initial key exchange must not contain any other message.
+pub const KEX_STRICT_CLIENT: &str = "kex-strict-c-v00@openssh.com";
+pub const KEX_STRICT_SERVER: &str = "kex-strict-s-v00@openssh.com";
+
+#[derive(Debug, Clone, DataType)]
 pub struct KexAlgorithms {
     pub cookie: [u8; 16],
     pub key_exchange: NameList,
@@ -33,161 +44,226 @@ pub(crate) struct AlgList {
     pub server_compress: Vec<Compress>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NegotiatedAlgorithms {
+    pub key_exchange: Kex,
+    pub public_key: PubKey,
+    pub client_encryption: Enc,
+    pub server_encryption: Enc,
+    pub client_mac: Option<Mac>,
+    pub server_mac: Option<Mac>,
+    pub client_compress: Compress,
+    pub server_compress: Compress,
+}
+
 impl AlgList {
     pub fn default() -> Self {
         AlgList {
-            key_exchange: vec![Kex::Curve25519Sha256],
-            public_key: vec![PubKey::RsaSha2_256],
-            client_encryption: vec![Enc::Chacha20Poly1305Openssh],
-            server_encryption: vec![Enc::Chacha20Poly1305Openssh],
-            client_mac: vec![Mac::HmacSha2_256],
-            server_mac: vec![Mac::HmacSha2_256],
+            key_exchange: vec![
+                Kex::MlKem768X25519Sha256,
+                Kex::Sntrup761X25519Sha512,
+                Kex::Curve25519Sha256,
+                Kex::Curve448Sha512,
+                Kex::EcdhSha2Nistp256,
+                Kex::EcdhSha2Nistp384,
+                Kex::EcdhSha2Nistp521,
+                Kex::DiffieHellmanGroupExchangeSha256,
+                Kex::DiffieHellmanGroup16Sha512,
+                Kex::DiffieHellmanGroup18Sha512,
+                Kex::DiffieHellmanGroup14Sha256,
+                Kex::DiffieHellmanGroup14Sha1,
+            ],
+            public_key: vec![PubKey::SshEd25519, PubKey::RsaSha2_512, PubKey::RsaSha2_256],
+            client_encryption: vec![
+                Enc::Chacha20Poly1305Openssh,
+                Enc::Aes128Ctr,
+                Enc::Aes192Ctr,
+                Enc::Aes256Ctr,
+                Enc::Aes128GcmOpenssh,
+                Enc::Aes256GcmOpenssh,
+            ],
+            server_encryption: vec![
+                Enc::Chacha20Poly1305Openssh,
+                Enc::Aes128Ctr,
+                Enc::Aes192Ctr,
+                Enc::Aes256Ctr,
+                Enc::Aes128GcmOpenssh,
+                Enc::Aes256GcmOpenssh,
+            ],
+            client_mac: vec![
+                Mac::HmacSha2_256Etm,
+                Mac::HmacSha2_512Etm,
+                Mac::HmacSha1Etm,
+                Mac::HmacSha2_256,
+                Mac::HmacSha2_512,
+                Mac::HmacSha1,
+            ],
+            server_mac: vec![
+                Mac::HmacSha2_256Etm,
+                Mac::HmacSha2_512Etm,
+                Mac::HmacSha1Etm,
+                Mac::HmacSha2_256,
+                Mac::HmacSha2_512,
+                Mac::HmacSha1,
+            ],
             client_compress: vec![Compress::None],
             server_compress: vec![Compress::None],
         }
     }
+
+    pub fn to_kex_algorithms(&self) -> KexAlgorithms {
+        fn names<T: ToString>(algorithms: &[T]) -> NameList {
+            algorithms.iter().map(|v| v.to_string()).collect()
+        }
+        KexAlgorithms {
+            cookie: rand::thread_rng().gen::<[u8; 16]>(),
+            key_exchange: names(&self.key_exchange),
+            server_host_key: names(&self.public_key),
+            client_encryption: names(&self.client_encryption),
+            server_encryption: names(&self.server_encryption),
+            client_mac: names(&self.client_mac),
+            server_mac: names(&self.server_mac),
+            client_compression: names(&self.client_compress),
+            server_compression: names(&self.server_compress),
+            client_languages: vec![],
+            server_languages: vec![],
+            first_kex_packet_follows: false,
+            reserved: 0,
+        }
+    }
+}
+
+impl KexAlgorithms {
+    // [RFC4253 § 7.1](https://datatracker.ietf.org/doc/html/rfc4253#section-7.1)
+    // The chosen algorithm MUST be the first algorithm on the client's name-list
+    // that is also on the server's name-list.
+    pub fn negotiate(&self, server: &KexAlgorithms) -> Result<NegotiatedAlgorithms, SshError> {
+        let key_exchange =
+            choose(&self.key_exchange, &server.key_exchange).ok_or(SshError::NoKexAlgMatch)?;
+        let public_key = choose(&self.server_host_key, &server.server_host_key)
+            .ok_or(SshError::NoHostkeyAlgMatch)?;
+        let client_encryption: Enc = choose(&self.client_encryption, &server.client_encryption)
+            .ok_or(SshError::NoCipherAlgMatch)?;
+        let server_encryption: Enc = choose(&self.server_encryption, &server.server_encryption)
+            .ok_or(SshError::NoCipherAlgMatch)?;
+        // AEAD ciphers authenticate the packet themselves, so the MAC name-list is ignored
+        let client_mac = match client_encryption.is_aead() {
+            true => None,
+            false => {
+                Some(choose(&self.client_mac, &server.client_mac).ok_or(SshError::NoMacAlgMatch)?)
+            }
+        };
+        let server_mac = match server_encryption.is_aead() {
+            true => None,
+            false => {
+                Some(choose(&self.server_mac, &server.server_mac).ok_or(SshError::NoMacAlgMatch)?)
+            }
+        };
+        let client_compress = choose(&self.client_compression, &server.client_compression)
+            .ok_or(SshError::NoCompressAlgMatch)?;
+        let server_compress = choose(&self.server_compression, &server.server_compression)
+            .ok_or(SshError::NoCompressAlgMatch)?;
+
+        Ok(NegotiatedAlgorithms {
+            key_exchange,
+            public_key,
+            client_encryption,
+            server_encryption,
+            client_mac,
+            server_mac,
+            client_compress,
+            server_compress,
+        })
+    }
+
+    pub fn strict_kex(&self) -> bool {
+        self.key_exchange
+            .iter()
+            .any(|name| name == KEX_STRICT_SERVER)
+    }
+
+    // The guess is right when the preferred key exchange and host key
+    // algorithms are the same on both sides.
+    fn guess_is_right(&self, server: &KexAlgorithms) -> bool {
+        self.key_exchange.first() == server.key_exchange.first()
+            && self.server_host_key.first() == server.server_host_key.first()
+    }
 }
 
-impl SshClient {
-    pub fn key_exchange_init(&mut self) -> anyhow::Result<()> {
-        // recv key algorithms
-        let mut payload = self.recv()?;
-        payload.expect(message_code::SSH_MSG_KEXINIT);
-        let server_kex_algorithms: KexAlgorithms = payload.get();
+fn choose<T: FromStr>(client: &NameList, server: &NameList) -> Option<T> {
+    client
+        .iter()
+        .filter(|name| server.contains(name))
+        .find_map(|name| T::from_str(name).ok())
+}
 
-        // send key algorithms
-        self.send(
+impl Connection {
+    // Our SSH_MSG_KEXINIT, with a fresh cookie for every exchange.
+    pub(crate) fn send_kexinit(&mut self) -> Result<()> {
+        self.key_exchange.cookie = rand::thread_rng().gen::<[u8; 16]>();
+        let mut client_kex_algorithms = self.key_exchange.clone();
+        if self.session.keys.is_none() {
+            client_kex_algorithms
+                .key_exchange
+                .extend([EXT_INFO_CLIENT.to_string(), KEX_STRICT_CLIENT.to_string()]);
+        }
+        self.write_packet(
             Data::new()
                 .put(&message_code::SSH_MSG_KEXINIT)
-                .put(&rand::thread_rng().gen::<[u8; 16]>())
-                .put(&self.key_exchange),
+                .put(&client_kex_algorithms),
         )?;
+        self.session.client_kex = Some(client_kex_algorithms);
+        Ok(())
+    }
 
-        self.session.client_kex = Some(self.key_exchange.clone());
-        self.session.server_kex = Some(server_kex_algorithms.clone());
+    // The server's SSH_MSG_KEXINIT, either in answer to ours or starting a
+    // re-exchange, after which the negotiated method takes over.
+    pub(crate) fn recv_kexinit(&mut self, mut payload: Data) -> Result<()> {
+        if !self.session.kex_in_progress {
+            self.start_key_exchange()?;
+        }
+        if !matches!(self.kex, KexState::Init) {
+            return Err(SshError::UnexpectedMessage(message_code::SSH_MSG_KEXINIT));
+        }
+        let initial = self.session.keys.is_none();
 
-        tracing::info!("server algorithms: {:?}", server_kex_algorithms);
-        println!("client algorithms: {:?}", self.key_exchange);
+        payload.expect(message_code::SSH_MSG_KEXINIT)?;
+        let server_kex_algorithms: KexAlgorithms = payload.get()?;
 
-        Ok(())
-    }
-}
+        if initial {
+            self.session.strict_kex = server_kex_algorithms.strict_kex();
+            // with strict key exchange the server's KEXINIT must be its very first packet
+            if self.session.strict_kex && self.session.server_sequence_number != 1 {
+                let e = SshError::Protocol(
+                    "strict KEX violation: KEXINIT was not the first packet".to_string(),
+                );
+                self.disconnect(message_code::SSH_DISCONNECT_PROTOCOL_ERROR, &e.to_string())?;
+                return Err(e);
+            }
+        }
 
-impl DataType for AlgList {
-    fn decode(input: &[u8]) -> nom::IResult<&[u8], Self>
-    where
-        Self: Sized,
-    {
-        let (input, key_exchange) = <Vec<Kex>>::decode(input)?;
-        let (input, public_key) = <Vec<PubKey>>::decode(input)?;
-        let (input, client_encryption) = <Vec<Enc>>::decode(input)?;
-        let (input, server_encryption) = <Vec<Enc>>::decode(input)?;
-        let (input, client_mac) = <Vec<Mac>>::decode(input)?;
-        let (input, server_mac) = <Vec<Mac>>::decode(input)?;
-        let (input, client_compress) = <Vec<Compress>>::decode(input)?;
-        let (input, server_compress) = <Vec<Compress>>::decode(input)?;
-        let (input, _client_languages) = <NameList>::decode(input)?;
-        let (input, _server_languages) = <NameList>::decode(input)?;
-        let (input, _first_kex_packet_follows) = <bool>::decode(input)?;
-        let (input, _reserved) = <u32>::decode(input)?;
-
-        Ok((
-            input,
-            AlgList {
-                key_exchange,
-                public_key,
-                client_encryption,
-                server_encryption,
-                client_mac,
-                server_mac,
-                client_compress,
-                server_compress,
-            },
-        ))
-    }
+        let client_kex_algorithms = self.session.client_kex.as_ref().unwrap();
+        tracing::info!("server algorithms: {:?}", server_kex_algorithms);
+        tracing::info!("client algorithms: {:?}", client_kex_algorithms);
 
-    fn encode(&self, buf: &mut Vec<u8>) {
-        self.key_exchange.encode(buf);
-        self.public_key.encode(buf);
-        self.client_encryption.encode(buf);
-        self.server_encryption.encode(buf);
-        self.client_mac.encode(buf);
-        self.server_mac.encode(buf);
-        self.client_compress.encode(buf);
-        self.server_compress.encode(buf);
-        let client_languages: NameList = vec![];
-        client_languages.encode(buf);
-        let server_languages: NameList = vec![];
-        server_languages.encode(buf);
-        let first_kex_packet_follows = false;
-        first_kex_packet_follows.encode(buf);
-        let reserved = 0u32;
-        reserved.encode(buf);
-    }
-}
+        let algorithms = client_kex_algorithms.negotiate(&server_kex_algorithms)?;
+        tracing::info!("negotiated algorithms: {:?}", algorithms);
+        self.session.algorithms = Some(algorithms);
 
-impl DataType for KexAlgorithms {
-    fn decode(input: &[u8]) -> nom::IResult<&[u8], Self>
-    where
-        Self: Sized,
-    {
-        let (input, cookie) = <[u8; 16]>::decode(input)?;
-        let (input, key_exchange) = <NameList>::decode(input)?;
-        let (input, server_host_key) = <NameList>::decode(input)?;
-        let (input, client_encryption) = <NameList>::decode(input)?;
-        let (input, server_encryption) = <NameList>::decode(input)?;
-        let (input, client_mac) = <NameList>::decode(input)?;
-        let (input, server_mac) = <NameList>::decode(input)?;
-        let (input, client_compression) = <NameList>::decode(input)?;
-        let (input, server_compression) = <NameList>::decode(input)?;
-        let (input, client_languages) = <NameList>::decode(input)?;
-        let (input, server_languages) = <NameList>::decode(input)?;
-        let (input, first_kex_packet_follows) = <bool>::decode(input)?;
-        let (input, reserved) = <u32>::decode(input)?;
-
-        Ok((
-            input,
-            KexAlgorithms {
-                cookie,
-                key_exchange,
-                server_host_key,
-                client_encryption,
-                server_encryption,
-                client_mac,
-                server_mac,
-                client_compression,
-                server_compression,
-                client_languages,
-                server_languages,
-                first_kex_packet_follows,
-                reserved,
-            },
-        ))
-    }
+        // a wrongly guessed key exchange packet MUST be silently ignored
+        self.session.ignore_guess = server_kex_algorithms.first_kex_packet_follows
+            && !self.key_exchange.guess_is_right(&server_kex_algorithms);
+        self.session.server_kex = Some(server_kex_algorithms);
 
-    fn encode(&self, buf: &mut Vec<u8>) {
-        self.cookie.encode(buf);
-        self.key_exchange.encode(buf);
-        self.server_host_key.encode(buf);
-        self.client_encryption.encode(buf);
-        self.server_encryption.encode(buf);
-        self.client_mac.encode(buf);
-        self.server_mac.encode(buf);
-        self.client_compression.encode(buf);
-        self.server_compression.encode(buf);
-        self.client_languages.encode(buf);
-        self.server_languages.encode(buf);
-        self.first_kex_packet_follows.encode(buf);
-        self.reserved.encode(buf);
+        self.start_method()
     }
 }
 
 // When acting as server: "ext-info-s"
 // When acting as client: "ext-info-c"
-#[test]
-fn parse_test_key_exchange_init_packet() {
-    // \x00\x00\x05\xdc\x04\x14
-    let mut payload = Data(b"\x14\x11\x58\xa5\x0f\xa6\x66\x70\x27\x00\x75\x6b\xd9\x62\xe5\xdc\xb2\
+// \x00\x00\x05\xdc\x04\x14
+#[cfg(test)]
+pub(super) const SERVER_KEXINIT_PAYLOAD: &[u8] = b"\x14\x11\x58\xa5\x0f\xa6\x66\x70\x27\x00\x75\x6b\xd9\x62\xe5\xdc\xb2\
 \x00\x00\x01\x14\
 curve25519-sha256,curve25519-sha256@libssh.org,ecdh-sha2-nistp256,ecdh-sha2-nistp384,ecdh-sha2-nistp521,sntrup761x25519-sha512@openssh.com,diffie-hellman-group-exchange-sha256,diffie-hellman-group16-sha512,diffie-hellman-group18-sha512,diffie-hellman-group14-sha256,ext-info-c\
 \x00\x00\x01\xcf\
@@ -204,12 +280,160 @@ umac-64-etm@openssh.com,umac-128-etm@openssh.com,hmac-sha2-256-etm@openssh.com,h
 none,zlib@openssh.com,zlib\
 \x00\x00\x00\x1a\
 none,zlib@openssh.com,zlib\
-\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec());
-    payload.expect(message_code::SSH_MSG_KEXINIT);
-    let kex_algorithms: KexAlgorithms = payload.get();
+\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+
+#[test]
+fn parse_test_key_exchange_init_packet() {
+    let mut payload = Data(SERVER_KEXINIT_PAYLOAD.to_vec());
+    payload.expect(message_code::SSH_MSG_KEXINIT).unwrap();
+    let kex_algorithms: KexAlgorithms = payload.get().unwrap();
     let mut gen_packet = Data::new();
     gen_packet
         .put(&message_code::SSH_MSG_KEXINIT)
         .put(&kex_algorithms);
-    assert!(payload.into_inner() == gen_packet.into_inner());
+    assert!(
+        SERVER_KEXINIT_PAYLOAD[..SERVER_KEXINIT_PAYLOAD.len() - payload.0.len()]
+            == gen_packet.into_inner()
+    );
+}
+
+#[test]
+fn negotiate_with_test_server() {
+    let mut payload = Data(SERVER_KEXINIT_PAYLOAD.to_vec());
+    payload.expect(message_code::SSH_MSG_KEXINIT).unwrap();
+    let server: KexAlgorithms = payload.get().unwrap();
+
+    let mut client = AlgList::default();
+    client.client_encryption = vec![Enc::Aes256Ctr, Enc::Chacha20Poly1305Openssh];
+    client.server_mac = vec![Mac::HmacSha2_512, Mac::HmacSha2_256];
+    let algorithms = client.to_kex_algorithms().negotiate(&server).unwrap();
+    assert_eq!(algorithms.key_exchange, Kex::Sntrup761X25519Sha512);
+    assert_eq!(algorithms.public_key, PubKey::SshEd25519);
+    assert_eq!(algorithms.client_encryption, Enc::Aes256Ctr);
+    assert_eq!(algorithms.server_encryption, Enc::Chacha20Poly1305Openssh);
+    assert_eq!(algorithms.client_mac, Some(Mac::HmacSha2_256Etm));
+    assert_eq!(algorithms.server_mac, None);
+    assert_eq!(algorithms.client_compress, Compress::None);
+
+    client.key_exchange = vec![Kex::DiffieHellmanGroup14Sha1];
+    assert!(matches!(
+        client.to_kex_algorithms().negotiate(&server),
+        Err(SshError::NoKexAlgMatch)
+    ));
+}
+
+#[test]
+fn strict_kex_pseudo_algorithms() {
+    let mut payload = Data(SERVER_KEXINIT_PAYLOAD.to_vec());
+    payload.expect(message_code::SSH_MSG_KEXINIT).unwrap();
+    let mut server: KexAlgorithms = payload.get().unwrap();
+    assert!(!server.strict_kex());
+
+    // the markers are announced but never negotiated
+    let mut client = AlgList::default().to_kex_algorithms();
+    client.key_exchange.insert(0, KEX_STRICT_CLIENT.to_string());
+    server.key_exchange.insert(0, KEX_STRICT_SERVER.to_string());
+    assert!(server.strict_kex());
+    assert!(!client.strict_kex());
+    assert_eq!(
+        client.negotiate(&server).unwrap().key_exchange,
+        Kex::Sntrup761X25519Sha512
+    );
+}
+
+#[test]
+fn strict_kex_through_connection() {
+    use super::data::ByteString;
+    use super::test_server::TestServer;
+
+    let disconnect_reason = |server: &mut TestServer, connection: &mut Connection| {
+        let mut payload = server.read(connection).pop().unwrap();
+        payload.expect(message_code::SSH_MSG_DISCONNECT).unwrap();
+        payload.get::<u32>().unwrap()
+    };
+
+    // sequence numbers restart at zero after every NEWKEYS, in both directions
+    let mut server = TestServer::new();
+    let mut connection = TestServer::client();
+    connection.feed(&server.hello()).unwrap();
+    server.key_exchange(&mut connection).unwrap();
+    assert!(connection.session.strict_kex);
+    // KEXINIT and the reply are read, NEWKEYS waits for the host key
+    assert_eq!(connection.session.server_sequence_number, 2);
+    connection.accept_host_key().unwrap();
+    assert_eq!(connection.session.server_sequence_number, 0);
+    assert_eq!(server.sequence_number, 0);
+    // NEWKEYS was sent as 2, SERVICE_REQUEST as 0
+    assert_eq!(connection.session.client_sequence_number, 1);
+    assert_eq!(
+        server.codes(&mut connection),
+        [
+            message_code::SSH_MSG_NEWKEYS,
+            message_code::SSH_MSG_SERVICE_REQUEST
+        ]
+    );
+    assert_eq!(server.client_sequence_number, 1);
+
+    // and after a re-key as well
+    let mut server = TestServer::new();
+    let mut connection = server.connect();
+    connection.feed(&server.kexinit()).unwrap();
+    server.key_exchange(&mut connection).unwrap();
+    assert_eq!(connection.session.server_sequence_number, 0);
+    assert_eq!(connection.session.client_sequence_number, 0);
+    assert_eq!(
+        server.codes(&mut connection),
+        [message_code::SSH_MSG_NEWKEYS]
+    );
+    assert_eq!(server.client_sequence_number, 0);
+
+    // without strict key exchange they keep counting
+    let mut server = TestServer::new();
+    server
+        .kexinit
+        .key_exchange
+        .retain(|name| name != KEX_STRICT_SERVER);
+    let connection = server.connect();
+    assert!(!connection.session.strict_kex);
+    assert_eq!(connection.session.server_sequence_number, 5);
+    assert_eq!(connection.session.client_sequence_number, 4);
+
+    // nothing but key exchange messages during the initial exchange, not even IGNORE
+    let mut server = TestServer::new();
+    let mut connection = TestServer::client();
+    connection.feed(&server.hello()).unwrap();
+    let ignore = server.packet(
+        Data::new()
+            .put(&message_code::SSH_MSG_IGNORE)
+            .put(&ByteString(vec![])),
+    );
+    assert!(matches!(
+        connection.feed(&ignore),
+        Err(SshError::Protocol(_))
+    ));
+    assert_eq!(
+        disconnect_reason(&mut server, &mut connection),
+        message_code::SSH_DISCONNECT_PROTOCOL_ERROR
+    );
+
+    // and the server's KEXINIT has to be its first packet
+    let mut server = TestServer::new();
+    let mut connection = TestServer::client();
+    let mut stream = TestServer::identification();
+    stream.extend(
+        server.packet(
+            Data::new()
+                .put(&message_code::SSH_MSG_IGNORE)
+                .put(&ByteString(vec![])),
+        ),
+    );
+    stream.extend(server.kexinit());
+    assert!(matches!(
+        connection.feed(&stream),
+        Err(SshError::Protocol(_))
+    ));
+    assert_eq!(
+        disconnect_reason(&mut server, &mut connection),
+        message_code::SSH_DISCONNECT_PROTOCOL_ERROR
+    );
 }
@@ -0,0 +1,486 @@
+use super::client::{Config, SshClient};
+use super::data::ByteString;
+use super::error::{Result, SshError};
+use super::ssh2::message_code;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use ssh_key::{HashAlg, PublicKey};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+const HASHED_HOST_MAGIC: &str = "|1|";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostKeyPolicy {
+    // refuse hosts whose key is not already in known_hosts
+    Strict,
+    // trust unknown hosts on first use and remember them
+    AcceptNew,
+    // ask on the terminal before trusting an unknown host, for interactive programs
+    Ask,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostKeyVerdict {
+    // trust the key and remember it in known_hosts
+    Accept,
+    // trust the key for this connection only, known_hosts is left as it is
+    AcceptOnce,
+    Reject,
+}
+
+// Decides host trust in place of known_hosts, which is then not read. It is
+// called after the server has proven possession of the host key by signing the
+// exchange hash.
+pub trait HostKeyVerifier: Send + Sync {
+    fn verify(
+        &self,
+        address: &SocketAddr,
+        algorithm: &str,
+        key: &[u8],
+        fingerprint: &str,
+    ) -> HostKeyVerdict;
+}
+
+impl<F> HostKeyVerifier for F
+where
+    F: Fn(&SocketAddr, &str, &[u8], &str) -> HostKeyVerdict + Send + Sync,
+{
+    fn verify(
+        &self,
+        address: &SocketAddr,
+        algorithm: &str,
+        key: &[u8],
+        fingerprint: &str,
+    ) -> HostKeyVerdict {
+        self(address, algorithm, key, fingerprint)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Marker {
+    CertAuthority,
+    Revoked,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostKeyStatus {
+    Known,
+    Changed,
+    Unknown,
+    Revoked,
+}
+
+// [@marker] hostnames keytype base64-encoded-key [comment]
+#[derive(Debug, Clone)]
+pub struct KnownHost {
+    pub marker: Option<Marker>,
+    pub hostnames: Vec<String>,
+    pub key: PublicKey,
+}
+
+impl KnownHost {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let mut fields = line.split_whitespace();
+        let mut hostnames = fields.next()?;
+        let marker = match hostnames {
+            "@cert-authority" => Some(Marker::CertAuthority),
+            "@revoked" => Some(Marker::Revoked),
+            _ => None,
+        };
+        if marker.is_some() {
+            hostnames = fields.next()?;
+        }
+        let keytype = fields.next()?;
+        let key = fields.next()?;
+        let key = PublicKey::from_openssh(&format!("{} {}", keytype, key)).ok()?;
+
+        Some(KnownHost {
+            marker,
+            hostnames: hostnames.split(',').map(|s| s.to_string()).collect(),
+            key,
+        })
+    }
+
+    // A negated pattern that matches rejects the line even if another pattern matches.
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        let mut matched = false;
+        for pattern in &self.hostnames {
+            if let Some(hashed) = pattern.strip_prefix(HASHED_HOST_MAGIC) {
+                matched |= match_hashed_host(hashed, &host);
+            } else if let Some(pattern) = pattern.strip_prefix('!') {
+                if match_pattern(&pattern.to_lowercase(), &host) {
+                    return false;
+                }
+            } else {
+                matched |= match_pattern(&pattern.to_lowercase(), &host);
+            }
+        }
+        matched
+    }
+}
+
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: Vec<KnownHost>,
+}
+
+impl KnownHosts {
+    pub fn open(path: &str) -> Result<Self> {
+        let mut content = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut content)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(KnownHosts {
+            path: PathBuf::from(path),
+            hosts: Self::parse(&content),
+        })
+    }
+
+    // Lines that cannot be parsed are skipped, as OpenSSH does.
+    pub fn parse(content: &str) -> Vec<KnownHost> {
+        content.lines().filter_map(KnownHost::parse).collect()
+    }
+
+    pub fn check(&self, host: &str, key: &PublicKey) -> HostKeyStatus {
+        let entries = self.hosts.iter().filter(|entry| entry.matches(host));
+        let mut status = HostKeyStatus::Unknown;
+        for entry in entries {
+            match entry.marker {
+                Some(Marker::Revoked) if entry.key.key_data() == key.key_data() => {
+                    return HostKeyStatus::Revoked;
+                }
+                Some(_) => {}
+                None if entry.key.key_data() == key.key_data() => status = HostKeyStatus::Known,
+                None if entry.key.algorithm() == key.algorithm()
+                    && status == HostKeyStatus::Unknown =>
+                {
+                    status = HostKeyStatus::Changed
+                }
+                None => {}
+            }
+        }
+        status
+    }
+
+    pub fn append(&mut self, host: &str, key: &PublicKey) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut key = key.clone();
+        key.set_comment("");
+        writeln!(
+            file,
+            "{} {}",
+            host,
+            key.to_openssh()
+                .map_err(|e| SshError::KeyInvalid(e.to_string()))?
+                .trim_end()
+        )?;
+        self.hosts.push(KnownHost {
+            marker: None,
+            hostnames: vec![host.to_string()],
+            key,
+        });
+        Ok(())
+    }
+}
+
+// Hosts on the default port are stored bare, others as "[host]:port". The host is
+// the name we were asked to connect to, or the address when there is none.
+pub fn host_name(host: Option<&str>, address: &SocketAddr) -> String {
+    let host = host.map_or_else(|| address.ip().to_string(), |host| host.to_string());
+    match address.port() {
+        22 => host,
+        port => format!("[{}]:{}", host, port),
+    }
+}
+
+// |1|base64(salt)|base64(HMAC-SHA1(salt, host))
+fn match_hashed_host(hashed: &str, host: &str) -> bool {
+    let Some((salt, hash)) = hashed.split_once('|') else {
+        return false;
+    };
+    let (Ok(salt), Ok(hash)) = (STANDARD.decode(salt), STANDARD.decode(hash)) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(&salt) else {
+        return false;
+    };
+    mac.update(host.as_bytes());
+    mac.verify_slice(&hash).is_ok()
+}
+
+// '*' matches any sequence of characters and '?' matches exactly one.
+fn match_pattern(pattern: &str, host: &str) -> bool {
+    fn matches(pattern: &[u8], host: &[u8]) -> bool {
+        match (pattern.first(), host.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => {
+                matches(&pattern[1..], host) || (!host.is_empty() && matches(pattern, &host[1..]))
+            }
+            (Some(b'?'), Some(_)) => matches(&pattern[1..], &host[1..]),
+            (Some(p), Some(h)) if p == h => matches(&pattern[1..], &host[1..]),
+            _ => false,
+        }
+    }
+    matches(pattern.as_bytes(), host.as_bytes())
+}
+
+impl SshClient {
+    pub fn verify_host_key(&mut self, host_key: &ByteString) -> Result<()> {
+        let result = check_host_key(
+            &self.connection.config,
+            self.host_key_verifier.as_deref(),
+            &self.address,
+            &host_key.0,
+        );
+        if let Err(SshError::HostKeyNotVerifiable(description)) = &result {
+            self.disconnect(
+                message_code::SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE,
+                description,
+            )?;
+        }
+        result
+    }
+}
+
+// A verifier decides on its own and known_hosts is only written when it accepts
+// the key for good, otherwise the key is checked against known_hosts under the
+// configured policy.
+pub fn check_host_key(
+    config: &Config,
+    verifier: Option<&dyn HostKeyVerifier>,
+    address: &SocketAddr,
+    host_key: &[u8],
+) -> Result<()> {
+    let key = PublicKey::from_bytes(host_key).map_err(|_| SshError::SignatureInvalid)?;
+    let host = host_name(config.host_name.as_deref(), address);
+    let fingerprint = key.fingerprint(HashAlg::Sha256);
+
+    if let Some(verifier) = verifier {
+        let verdict = verifier.verify(
+            address,
+            key.algorithm().as_str(),
+            host_key,
+            &fingerprint.to_string(),
+        );
+        return match verdict {
+            HostKeyVerdict::Accept if !config.known_hosts_path.is_empty() => {
+                let mut known_hosts = KnownHosts::open(&config.known_hosts_path)?;
+                if known_hosts.check(&host, &key) != HostKeyStatus::Known {
+                    known_hosts.append(&host, &key)?;
+                }
+                Ok(())
+            }
+            HostKeyVerdict::Accept | HostKeyVerdict::AcceptOnce => Ok(()),
+            HostKeyVerdict::Reject => Err(SshError::HostKeyNotVerifiable(format!(
+                "host key for {} was rejected",
+                host
+            ))),
+        };
+    }
+
+    let mut known_hosts = KnownHosts::open(&config.known_hosts_path)?;
+    match known_hosts.check(&host, &key) {
+        HostKeyStatus::Known => Ok(()),
+        HostKeyStatus::Revoked => {
+            tracing::warn!(
+                "The {} host key for {} is marked as revoked. This could mean that a \
+                 stolen key is being used to impersonate this host.",
+                key.algorithm(),
+                host
+            );
+            Err(SshError::HostKeyNotVerifiable(format!(
+                "host key for {} is revoked",
+                host
+            )))
+        }
+        HostKeyStatus::Changed => {
+            tracing::warn!(
+                "REMOTE HOST IDENTIFICATION HAS CHANGED! The fingerprint for the {} key \
+                 sent by the remote host is {}. Offending key for {} in {}.",
+                key.algorithm(),
+                fingerprint,
+                host,
+                config.known_hosts_path
+            );
+            Err(SshError::HostKeyNotVerifiable(format!(
+                "host key for {} has changed",
+                host
+            )))
+        }
+        HostKeyStatus::Unknown => {
+            let accepted = match config.host_key_policy {
+                HostKeyPolicy::Strict => false,
+                HostKeyPolicy::AcceptNew => true,
+                HostKeyPolicy::Ask => {
+                    ask_user(&host, key.algorithm().as_str(), &fingerprint.to_string())?
+                }
+            };
+            if !accepted {
+                return Err(SshError::HostKeyNotVerifiable(format!(
+                    "no host key is known for {}",
+                    host
+                )));
+            }
+            known_hosts.append(&host, &key)?;
+            tracing::warn!(
+                "Permanently added '{}' ({}) to the list of known hosts.",
+                host,
+                key.algorithm()
+            );
+            Ok(())
+        }
+    }
+}
+
+fn ask_user(host: &str, algorithm: &str, fingerprint: &str) -> Result<bool> {
+    print!(
+        "The authenticity of host '{}' can't be established.\n\
+         {} key fingerprint is {}.\n\
+         Are you sure you want to continue connecting (yes/no)? ",
+        host, algorithm, fingerprint
+    );
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("yes"))
+}
+
+#[cfg(test)]
+const TEST_KNOWN_HOSTS: &str = "\
+# comment
+|1|tHBPP9DQ3+ElXR2i9fcIEeuMZ8I=|kpdnyhZpEOCb1PVe+ikTCrCpTVE= ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHhlKpaO0X7/CrK/v0NjvhJ6YUPWDTcRHZ1LVaOpaAuW
+|1|wWN3zcsKgOQ7sgO+jGRp+zho7Ks=|Gmx8wPD/tsCk82hcIR5R/oxroFo= ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHhlKpaO0X7/CrK/v0NjvhJ6YUPWDTcRHZ1LVaOpaAuW
+*.example.org,!bad.example.org ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHhlKpaO0X7/CrK/v0NjvhJ6YUPWDTcRHZ1LVaOpaAuW comment
+@revoked * ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIImq9U0VI5PqjhvMTKhGKccSivgT+Z4odoBV3qDbTopY
+not a valid line
+";
+
+#[test]
+fn check_known_hosts() {
+    let known_hosts = KnownHosts {
+        path: PathBuf::new(),
+        hosts: KnownHosts::parse(TEST_KNOWN_HOSTS),
+    };
+    assert_eq!(known_hosts.hosts.len(), 4);
+
+    let key = PublicKey::from_openssh(
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHhlKpaO0X7/CrK/v0NjvhJ6YUPWDTcRHZ1LVaOpaAuW",
+    )
+    .unwrap();
+    let other = PublicKey::from_openssh(
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKv5gPhN7cKdjO6tkmIpT5uyWTsTLUuGH7vuP3AMThaw",
+    )
+    .unwrap();
+    let revoked = PublicKey::from_openssh(
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIImq9U0VI5PqjhvMTKhGKccSivgT+Z4odoBV3qDbTopY",
+    )
+    .unwrap();
+
+    assert_eq!(
+        known_hosts.check("[example.com]:2222", &key),
+        HostKeyStatus::Known
+    );
+    assert_eq!(known_hosts.check("192.0.2.1", &key), HostKeyStatus::Known);
+    assert_eq!(
+        known_hosts.check("192.0.2.1", &other),
+        HostKeyStatus::Changed
+    );
+    assert_eq!(
+        known_hosts.check("example.com", &key),
+        HostKeyStatus::Unknown
+    );
+    assert_eq!(
+        known_hosts.check("www.EXAMPLE.org", &key),
+        HostKeyStatus::Known
+    );
+    assert_eq!(
+        known_hosts.check("bad.example.org", &key),
+        HostKeyStatus::Unknown
+    );
+    assert_eq!(
+        known_hosts.check("192.0.2.1", &revoked),
+        HostKeyStatus::Revoked
+    );
+
+    let address: SocketAddr = "192.0.2.1:2222".parse().unwrap();
+    assert_eq!(host_name(None, &address), "[192.0.2.1]:2222");
+    assert_eq!(
+        host_name(Some("www.example.org"), &address),
+        "[www.example.org]:2222"
+    );
+    let address: SocketAddr = "192.0.2.1:22".parse().unwrap();
+    assert_eq!(
+        host_name(Some("www.example.org"), &address),
+        "www.example.org"
+    );
+}
+
+#[test]
+fn verifier_replaces_known_hosts() {
+    use super::client::SessionBuilder;
+
+    let key = PublicKey::from_openssh(
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHhlKpaO0X7/CrK/v0NjvhJ6YUPWDTcRHZ1LVaOpaAuW",
+    )
+    .unwrap();
+    let host_key = key.to_bytes().unwrap();
+    let address: SocketAddr = "192.0.2.1:22".parse().unwrap();
+    let path = std::env::temp_dir().join(format!("sseichi_known_hosts_{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let connection = SessionBuilder::create_session()
+        .known_hosts_path(path.to_str().unwrap())
+        .host_key_policy(HostKeyPolicy::Strict)
+        .connection()
+        .unwrap();
+    let config = &connection.config;
+    assert!(check_host_key(config, None, &address, &host_key).is_err());
+
+    let reject = |_: &SocketAddr, _: &str, _: &[u8], _: &str| HostKeyVerdict::Reject;
+    assert!(matches!(
+        check_host_key(config, Some(&reject), &address, &host_key),
+        Err(SshError::HostKeyNotVerifiable(_))
+    ));
+
+    // trusted for this connection, the next one has to ask again
+    let accept_once = |_: &SocketAddr, _: &str, _: &[u8], _: &str| HostKeyVerdict::AcceptOnce;
+    assert!(check_host_key(config, Some(&accept_once), &address, &host_key).is_ok());
+    assert!(!path.exists());
+    assert!(check_host_key(config, None, &address, &host_key).is_err());
+
+    let accept = |_: &SocketAddr, algorithm: &str, _: &[u8], fingerprint: &str| {
+        assert_eq!(algorithm, "ssh-ed25519");
+        assert!(fingerprint.starts_with("SHA256:"));
+        HostKeyVerdict::Accept
+    };
+    assert!(check_host_key(config, Some(&accept), &address, &host_key).is_ok());
+    assert!(check_host_key(config, Some(&accept), &address, &host_key).is_ok());
+    let known_hosts = KnownHosts::open(path.to_str().unwrap()).unwrap();
+    assert_eq!(known_hosts.hosts.len(), 1);
+    assert_eq!(known_hosts.check("192.0.2.1", &key), HostKeyStatus::Known);
+    assert!(check_host_key(config, None, &address, &host_key).is_ok());
+
+    // known_hosts as it is after Accept, AcceptOnce does not touch it
+    let content = std::fs::read(&path).unwrap();
+    assert!(check_host_key(config, Some(&accept_once), &address, &host_key).is_ok());
+    assert_eq!(std::fs::read(&path).unwrap(), content);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,317 @@
+use super::{
+    data::DataType,
+    error::{Result, SshError},
+    session::NewKeys,
+};
+use subtle::ConstantTimeEq;
+
+// Upper bound on packet_length, as enforced by OpenSSH (PACKET_MAX_SIZE).
+// [RFC4253 § 6.1](https://datatracker.ietf.org/doc/html/rfc4253#section-6.1)
+// only requires 35000 bytes, anything larger than this is treated as corrupt.
+const MAX_PACKET_LENGTH: u32 = 256 * 1024;
+
+// Accumulates bytes read from the stream and cuts them into whole packets.
+//
+//   byte[l]   packet length; l = length_size, encrypted unless etm or aead
+//   byte[n]   rest of the packet; n = 4 + packet_length - l
+//   byte[t]   tag; t = tag_size for aead ciphers
+//   byte[m]   mac; m = mac_length
+//
+// A packet is only decrypted once all of its bytes have arrived, so a packet
+// split across reads or several packets coalesced into one read are both fine.
+#[derive(Debug, Default)]
+pub struct PacketReader {
+    buffer: Vec<u8>,
+}
+
+impl PacketReader {
+    pub fn new() -> Self {
+        PacketReader { buffer: Vec::new() }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    // Takes one LF terminated line, used before the binary packet protocol starts.
+    // Lines longer than `max` bytes are an error.
+    pub fn read_line(&mut self, max: usize) -> Result<Option<Vec<u8>>> {
+        match self.buffer.iter().take(max).position(|b| *b == b'\n') {
+            Some(end) => Ok(Some(self.buffer.drain(..=end).collect())),
+            None if self.buffer.len() >= max => Err(SshError::InvalidFormat(format!(
+                "line longer than {} bytes",
+                max
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // Returns the next decrypted packet (packet_length || padding_length || payload || padding),
+    // or None when more bytes have to be read first.
+    pub fn read_packet(
+        &mut self,
+        method: &mut NewKeys,
+        sequence_number: u32,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.buffer.len() < method.enc.length_size() {
+            return Ok(None);
+        }
+        let packet_length = method.enc.decrypt_length(&self.buffer, sequence_number);
+        if !(5..=MAX_PACKET_LENGTH).contains(&packet_length) {
+            return Err(SshError::BadPacketLength(packet_length));
+        }
+        // at least one block, and whole blocks of it
+        let aligned = method.enc.aligned_length(packet_length);
+        let group_size = method.enc.group_size();
+        if aligned < group_size || !aligned.is_multiple_of(group_size) {
+            return Err(SshError::BadPacketLength(packet_length));
+        }
+
+        let packet_size = 4 + packet_length as usize;
+        let mac_length = method.mac.size();
+        let total = packet_size + method.enc.tag_size() + mac_length;
+        if self.buffer.len() < total {
+            return Ok(None);
+        }
+        let mut packet: Vec<u8> = self.buffer.drain(..total).collect();
+        let mac = packet.split_off(total - mac_length);
+
+        // encrypt-then-MAC: the MAC covers the ciphertext and is checked before decrypting
+        let etm = method.mac.is_etm();
+        if etm {
+            verify_mac(method, sequence_number, &packet, &mac)?;
+        }
+
+        let (_, plaintext, _) = method.enc.decrypt(&mut packet, sequence_number)?;
+
+        // encrypt-and-MAC: the MAC covers the plaintext
+        if !etm {
+            verify_mac(method, sequence_number, &plaintext, &mac)?;
+        }
+
+        Ok(Some(plaintext))
+    }
+}
+
+// encrypt-and-MAC: mac = MAC(key, sequence_number || unencrypted_packet)
+// encrypt-then-MAC: mac = MAC(key, sequence_number || packet_length || encrypted_packet)
+pub fn calc_mac(method: &NewKeys, sequence_number: u32, packet: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    sequence_number.encode(&mut data);
+    data.extend(packet);
+    method.mac.sign(&data)
+}
+
+fn verify_mac(method: &NewKeys, sequence_number: u32, packet: &[u8], mac: &[u8]) -> Result<()> {
+    let expected = calc_mac(method, sequence_number, packet);
+    match bool::from(mac.ct_eq(&expected)) {
+        true => Ok(()),
+        false => Err(SshError::MacInvalid),
+    }
+}
+
+#[test]
+fn read_split_and_coalesced_packets() {
+    use crate::crypto::{Enc, Mac};
+
+    let new_keys = || {
+        NewKeys::new(
+            Enc::Aes128Ctr.new_adapter(&[1; 16], &[2; 16], false),
+            Mac::HmacSha2_256.new_adapter(&[3; 32]),
+            Box::new(crate::crypto::compression::none::NoneCompress {}),
+        )
+    };
+    let mut client = new_keys();
+    let mut server = new_keys();
+
+    let mut stream = Vec::new();
+    let mut plaintexts = Vec::new();
+    for (sequence_number, payload_length) in [(0, 3), (1, 1000), (2, 40)] {
+        let packet_length = client.enc.packet_length(payload_length + 1);
+        let padding_length = (packet_length - payload_length - 1) as u8;
+        let mut packet = crate::protocol::data::Data::new();
+        packet
+            .put(&packet_length)
+            .put(&padding_length)
+            .put(&vec![7; packet_length as usize - 1].as_slice());
+        plaintexts.push(packet.clone().into_inner());
+        let mac = calc_mac(&client, sequence_number, &packet.0);
+        client.enc.encrypt(&mut packet, sequence_number);
+        stream.extend(packet.into_inner());
+        stream.extend(mac);
+    }
+
+    // one byte at a time, then everything at once
+    let mut reader = PacketReader::new();
+    let mut packets = Vec::new();
+    for byte in &stream {
+        reader.feed(&[*byte]);
+        if let Some(packet) = reader
+            .read_packet(&mut server, packets.len() as u32)
+            .unwrap()
+        {
+            packets.push(packet);
+        }
+    }
+    assert_eq!(packets, plaintexts);
+    assert!(reader.buffer.is_empty());
+
+    let mut server = new_keys();
+    reader.feed(&stream);
+    for (sequence_number, plaintext) in plaintexts.iter().enumerate() {
+        let packet = reader
+            .read_packet(&mut server, sequence_number as u32)
+            .unwrap();
+        assert_eq!(packet.as_ref(), Some(plaintext));
+    }
+    assert!(reader.read_packet(&mut server, 3).unwrap().is_none());
+}
+
+#[test]
+fn reject_short_and_misaligned_packets() {
+    use crate::crypto::{Enc, Mac};
+
+    let new_keys = |mac: Mac| {
+        NewKeys::new(
+            Enc::Aes128Ctr.new_adapter(&[1; 16], &[2; 16], mac.is_etm()),
+            mac.new_adapter(&[3; 32]),
+            Box::new(crate::crypto::compression::none::NoneCompress {}),
+        )
+    };
+    // packet_length shorter than a block, not a whole number of blocks with the
+    // length field, and not a whole number of blocks without it
+    for (mac, packet_length) in [
+        (Mac::HmacSha2_256, 8),
+        (Mac::HmacSha2_256, 20),
+        (Mac::HmacSha2_256Etm, 12),
+        (Mac::HmacSha2_256Etm, 28),
+    ] {
+        let mut client = new_keys(mac);
+        let mut server = new_keys(mac);
+        let mut packet = crate::protocol::data::Data::new();
+        packet
+            .put(&packet_length)
+            .put(&vec![4; packet_length as usize].as_slice());
+        client.enc.encrypt(&mut packet, 0);
+
+        let mut reader = PacketReader::new();
+        reader.feed(&packet.into_inner());
+        reader.feed(&[0; 64]);
+        assert!(matches!(
+            reader.read_packet(&mut server, 0),
+            Err(SshError::BadPacketLength(length)) if length == packet_length
+        ));
+    }
+
+    // the ciphers check what they are given on their own
+    let mut server = new_keys(Mac::HmacSha2_256);
+    assert!(matches!(
+        server.enc.decrypt(&mut [0; 8], 0),
+        Err(SshError::BadPacketLength(_))
+    ));
+    let mut gcm = Enc::Aes128GcmOpenssh.new_adapter(&[1; 16], &[2; 12], false);
+    let mut packet = crate::protocol::data::Data::new();
+    packet.put(&32u32).put(&[0; 20].as_slice());
+    assert!(matches!(
+        gcm.decrypt(&mut packet.0, 0),
+        Err(SshError::BadPacketLength(32))
+    ));
+    // a length that overflows with the length field added
+    let mut chacha = Enc::Chacha20Poly1305Openssh.new_adapter(&[1; 64], &[], false);
+    let keystream = chacha.decrypt_length(&[0; 4], 0);
+    let mut packet = (keystream ^ u32::MAX).to_be_bytes().to_vec();
+    packet.extend([0; 32]);
+    assert!(matches!(
+        chacha.decrypt(&mut packet, 0),
+        Err(SshError::BadPacketLength(u32::MAX))
+    ));
+}
+
+#[test]
+fn encrypt_then_mac_round_trip() {
+    use super::client::SessionBuilder;
+    use super::data::{ByteString, Data};
+    use super::ssh2::message_code;
+    use crate::crypto::encryption::EncryptionAdapter;
+    use crate::crypto::{Enc, Mac};
+    use hmac::{Hmac, Mac as _};
+    use sha2::Sha256;
+    use std::{cell::Cell, rc::Rc};
+
+    // counts the packets the cipher is asked to decrypt
+    struct Counting(Box<dyn EncryptionAdapter>, Rc<Cell<usize>>);
+    impl EncryptionAdapter for Counting {
+        fn group_size(&self) -> u32 {
+            self.0.group_size()
+        }
+        fn packet_length(&mut self, payload_length: u32) -> u32 {
+            self.0.packet_length(payload_length)
+        }
+        fn decrypt_length(&self, buffer: &[u8], sequence_number: u32) -> u32 {
+            self.0.decrypt_length(buffer, sequence_number)
+        }
+        fn aligned_length(&self, packet_length: u32) -> u32 {
+            self.0.aligned_length(packet_length)
+        }
+        fn encrypt(&mut self, buffer: &mut Data, sequence_number: u32) {
+            self.0.encrypt(buffer, sequence_number)
+        }
+        fn decrypt<'a>(
+            &mut self,
+            buffer: &'a mut [u8],
+            sequence_number: u32,
+        ) -> Result<(&'a mut [u8], Vec<u8>, usize)> {
+            self.1.set(self.1.get() + 1);
+            self.0.decrypt(buffer, sequence_number)
+        }
+    }
+
+    let decrypted = Rc::new(Cell::new(0));
+    let new_keys = |enc: Box<dyn EncryptionAdapter>| {
+        NewKeys::new(
+            enc,
+            Mac::HmacSha2_256Etm.new_adapter(&[3; 32]),
+            Box::new(crate::crypto::compression::none::NoneCompress {}),
+        )
+    };
+    let cipher = || Enc::Aes128Ctr.new_adapter(&[1; 16], &[2; 16], true);
+    let mut connection = SessionBuilder::create_session().connection().unwrap();
+    connection.poll_transmit();
+    connection.session.client_method = new_keys(cipher());
+    connection.session.client_sequence_number = 5;
+    let mut server = new_keys(Box::new(Counting(cipher(), decrypted.clone())));
+    let mut reader = PacketReader::new();
+    let mut ignore = Data::new();
+    ignore
+        .put(&message_code::SSH_MSG_IGNORE)
+        .put(&ByteString(b"encrypt-then-mac".to_vec()));
+
+    // mac = MAC(key, sequence_number || packet_length || encrypted_packet), with
+    // packet_length sent in the clear
+    connection.write_packet(&ignore).unwrap();
+    let packet = connection.poll_transmit().unwrap();
+    let (ciphertext, mac) = packet.split_at(packet.len() - 32);
+    let packet_length = u32::from_be_bytes(ciphertext[..4].try_into().unwrap());
+    assert_eq!(packet_length as usize + 4, ciphertext.len());
+    let mut expected = Hmac::<Sha256>::new_from_slice(&[3; 32]).unwrap();
+    expected.update(&5u32.to_be_bytes());
+    expected.update(ciphertext);
+    assert_eq!(mac, expected.finalize().into_bytes().as_slice());
+
+    reader.feed(&packet);
+    let plaintext = reader.read_packet(&mut server, 5).unwrap().unwrap();
+    assert_eq!(&plaintext[..4], &ciphertext[..4]);
+    assert_eq!(&plaintext[5..5 + ignore.0.len()], ignore.0.as_slice());
+    assert_eq!(decrypted.get(), 1);
+
+    // a flipped ciphertext byte fails the MAC before the cipher sees the packet
+    connection.write_packet(&ignore).unwrap();
+    let mut packet = connection.poll_transmit().unwrap();
+    packet[8] ^= 1;
+    reader.feed(&packet);
+    assert!(matches!(
+        reader.read_packet(&mut server, 6),
+        Err(SshError::MacInvalid)
+    ));
+    assert_eq!(decrypted.get(), 1);
+}
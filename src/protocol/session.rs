@@ -1,10 +1,15 @@
+use super::data::{ByteString, Data};
+use super::error::{Result, SshError};
+use super::ext_info::ExtInfo;
 use super::key_exchange::Kex;
-use super::key_exchange_init::KexAlgorithms;
+use super::key_exchange_init::{KexAlgorithms, NegotiatedAlgorithms};
 use super::version_exchange::Version;
 use crate::crypto::compression::none::NoneCompress;
 use crate::crypto::encryption::none::NoneEncryption;
 use crate::crypto::mac::none::NoneMac;
 use crate::crypto::{compression::CompressAdapter, encryption::EncryptionAdapter, mac::MACAdapter};
+use std::collections::VecDeque;
+use std::time::Instant;
 
 pub struct NewKeys {
     pub enc: Box<dyn EncryptionAdapter>,
@@ -43,11 +48,34 @@ pub struct Session {
 
     pub client_version: Option<Version>,
     pub server_version: Option<Version>,
+    // lines the server sent before its identification string
+    pub server_banner: Vec<String>,
 
     pub client_kex: Option<KexAlgorithms>,
     pub server_kex: Option<KexAlgorithms>,
+    pub algorithms: Option<NegotiatedAlgorithms>,
 
     pub keys: Option<Kex>,
+    pub server_host_key: Option<ByteString>,
+    pub authenticated: bool,
+    // both sides announced strict key exchange in the initial KEXINIT
+    pub strict_kex: bool,
+    pub ext_info: ExtInfo,
+    // the next packet may be SSH_MSG_EXT_INFO, as it follows the first NEWKEYS
+    pub ext_info_expected: bool,
+    // SSH_MSG_EXT_INFO that only counts if SSH_MSG_USERAUTH_SUCCESS comes next
+    pub held_ext_info: Option<Data>,
+
+    // re-keying state
+    pub kex_in_progress: bool,
+    pub bytes_since_kex: u64,
+    pub last_kex: Instant,
+    // the server's wrongly guessed key exchange packet is still to come
+    pub ignore_guess: bool,
+    // packets the application sent while a key exchange was running
+    pub pending: VecDeque<Data>,
+    // connection protocol packets the server sent while a re-key was running
+    pub deferred: VecDeque<Data>,
 }
 
 impl Session {
@@ -59,13 +87,34 @@ impl Session {
             server_sequence_number: 0,
             client_version: None,
             server_version: None,
+            server_banner: Vec::new(),
             client_kex: None,
             server_kex: None,
+            algorithms: None,
             keys: None,
+            server_host_key: None,
+            authenticated: false,
+            strict_kex: false,
+            ext_info: ExtInfo::default(),
+            ext_info_expected: false,
+            held_ext_info: None,
+            kex_in_progress: false,
+            bytes_since_kex: 0,
+            last_kex: Instant::now(),
+            ignore_guess: false,
+            pending: VecDeque::new(),
+            deferred: VecDeque::new(),
         }
     }
 
-    pub fn get_keys(&self) -> Kex {
-        self.keys.clone().unwrap()
+    pub fn get_keys(&self) -> Result<Kex> {
+        self.keys
+            .clone()
+            .ok_or_else(|| SshError::Protocol("no keys negotiated".to_string()))
+    }
+
+    pub fn get_algorithms(&self) -> Result<NegotiatedAlgorithms> {
+        self.algorithms
+            .ok_or_else(|| SshError::Protocol("no algorithms negotiated".to_string()))
     }
 }
@@ -13,6 +13,9 @@ pub mod message_code {
     pub const SSH_MSG_KEXINIT: u8 = 20;
     pub const SSH_MSG_NEWKEYS: u8 = 21;
     // 30 to 49   Key exchange method specific (numbers can be reused for different authentication methods)
+    /* dh */
+    pub const SSH_MSG_KEXDH_INIT: u8 = 30;
+    pub const SSH_MSG_KEXDH_REPLY: u8 = 31;
     /* dh-group-exchange */
     pub const SSH2_MSG_KEX_DH_GEX_REQUEST_OLD: u8 = 30;
     pub const SSH2_MSG_KEX_DH_GEX_GROUP: u8 = 31;
@@ -68,21 +71,21 @@ pub mod message_code {
     pub const SSH2_CERT_TYPE_HOST: u8 = 1;
 
     // Disconnect Code
-    pub const SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT: u8 = 1;
-    pub const SSH_DISCONNECT_PROTOCOL_ERROR: u8 = 2;
-    pub const SSH_DISCONNECT_KEY_EXCHANGE_FAILED: u8 = 3;
-    pub const SSH_DISCONNECT_RESERVED: u8 = 4;
-    pub const SSH_DISCONNECT_MAC_ERROR: u8 = 5;
-    pub const SSH_DISCONNECT_COMPRESSION_ERROR: u8 = 6;
-    pub const SSH_DISCONNECT_SERVICE_NOT_AVAILABLE: u8 = 7;
-    pub const SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED: u8 = 8;
-    pub const SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE: u8 = 9;
-    pub const SSH_DISCONNECT_CONNECTION_LOST: u8 = 10;
-    pub const SSH_DISCONNECT_BY_APPLICATION: u8 = 11;
-    pub const SSH_DISCONNECT_TOO_MANY_CONNECTIONS: u8 = 12;
-    pub const SSH_DISCONNECT_AUTH_CANCELLED_BY_USER: u8 = 13;
-    pub const SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE: u8 = 14;
-    pub const SSH_DISCONNECT_ILLEGAL_USER_NAME: u8 = 15;
+    pub const SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT: u32 = 1;
+    pub const SSH_DISCONNECT_PROTOCOL_ERROR: u32 = 2;
+    pub const SSH_DISCONNECT_KEY_EXCHANGE_FAILED: u32 = 3;
+    pub const SSH_DISCONNECT_RESERVED: u32 = 4;
+    pub const SSH_DISCONNECT_MAC_ERROR: u32 = 5;
+    pub const SSH_DISCONNECT_COMPRESSION_ERROR: u32 = 6;
+    pub const SSH_DISCONNECT_SERVICE_NOT_AVAILABLE: u32 = 7;
+    pub const SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED: u32 = 8;
+    pub const SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE: u32 = 9;
+    pub const SSH_DISCONNECT_CONNECTION_LOST: u32 = 10;
+    pub const SSH_DISCONNECT_BY_APPLICATION: u32 = 11;
+    pub const SSH_DISCONNECT_TOO_MANY_CONNECTIONS: u32 = 12;
+    pub const SSH_DISCONNECT_AUTH_CANCELLED_BY_USER: u32 = 13;
+    pub const SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE: u32 = 14;
+    pub const SSH_DISCONNECT_ILLEGAL_USER_NAME: u32 = 15;
 }
 
 #[allow(dead_code)]
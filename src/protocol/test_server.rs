@@ -0,0 +1,320 @@
+use super::{
+    client::SessionBuilder,
+    connection::{Connection, Event},
+    data::{ByteString, Data},
+    error::Result,
+    key_exchange::Kex,
+    key_exchange_init::{
+        AlgList, KexAlgorithms, NegotiatedAlgorithms, KEX_STRICT_CLIENT, KEX_STRICT_SERVER,
+    },
+    packet_reader::{calc_mac, PacketReader},
+    session::NewKeys,
+    ssh2::message_code,
+};
+use crate::crypto::key_exchange::{curve::Curve25519Sha256, KexMethodAdapter};
+use crate::crypto::mac::none::NoneMac;
+use rsa::signature::Signer;
+
+// The other end of a Connection in tests. It answers every key exchange with a
+// curve25519-sha256 reply signed by its own ed25519 host key and then speaks
+// with the keys both sides derived, so that whole conversations can be run
+// without a socket.
+pub(crate) struct TestServer {
+    pub host_key: ssh_key::PrivateKey,
+    pub kexinit: KexAlgorithms,
+    client_kexinit: Option<KexAlgorithms>,
+    // the next sequence number in each direction, as the server counts them
+    pub sequence_number: u32,
+    pub client_sequence_number: u32,
+    send: NewKeys,
+    recv: NewKeys,
+    reader: PacketReader,
+    // derived, waiting for the NEWKEYS in each direction
+    send_keys: Option<Kex>,
+    recv_keys: Option<Kex>,
+    strict_kex: bool,
+    authenticated: bool,
+}
+
+impl TestServer {
+    pub fn new() -> Self {
+        let mut kexinit = AlgList::default().to_kex_algorithms();
+        kexinit.key_exchange = vec![
+            "curve25519-sha256".to_string(),
+            KEX_STRICT_SERVER.to_string(),
+        ];
+        kexinit.server_host_key = vec!["ssh-ed25519".to_string()];
+        TestServer {
+            host_key: ssh_key::PrivateKey::random(
+                &mut rand_core::OsRng,
+                ssh_key::Algorithm::Ed25519,
+            )
+            .unwrap(),
+            kexinit,
+            client_kexinit: None,
+            sequence_number: 0,
+            client_sequence_number: 0,
+            send: NewKeys::init_state(),
+            recv: NewKeys::init_state(),
+            reader: PacketReader::new(),
+            send_keys: None,
+            recv_keys: None,
+            strict_kex: false,
+            authenticated: false,
+        }
+    }
+
+    // A client that has sent its identification string and nothing else.
+    pub fn client() -> Connection {
+        Self::client_with(SessionBuilder::create_session())
+    }
+
+    pub fn client_with(builder: SessionBuilder) -> Connection {
+        let mut connection = builder.connection().unwrap();
+        assert!(connection.poll_transmit().unwrap().starts_with(b"SSH-2.0-"));
+        connection
+    }
+
+    // The server's identification string and KEXINIT.
+    pub fn hello(&mut self) -> Vec<u8> {
+        let mut stream = Self::identification();
+        stream.extend(self.kexinit());
+        stream
+    }
+
+    pub fn identification() -> Vec<u8> {
+        b"SSH-2.0-OpenSSH_9.6\r\n".to_vec()
+    }
+
+    pub fn kexinit(&mut self) -> Vec<u8> {
+        let mut payload = Data::new();
+        payload
+            .put(&message_code::SSH_MSG_KEXINIT)
+            .put(&self.kexinit);
+        self.packet(&payload)
+    }
+
+    // Runs the initial key exchange and user authentication, leaving a client
+    // that has nothing left to send.
+    pub fn connect(&mut self) -> Connection {
+        let mut connection = Self::client();
+        self.handshake(&mut connection);
+        self.authenticate(&mut connection);
+        connection
+    }
+
+    // The initial key exchange, up to the client being asked to authenticate.
+    pub fn handshake(&mut self, connection: &mut Connection) {
+        connection.feed(&self.hello()).unwrap();
+        self.key_exchange(connection).unwrap();
+        assert!(matches!(connection.poll_event(), Some(Event::HostKey(_))));
+        connection.accept_host_key().unwrap();
+        assert_eq!(
+            self.codes(connection),
+            [
+                message_code::SSH_MSG_NEWKEYS,
+                message_code::SSH_MSG_SERVICE_REQUEST
+            ]
+        );
+        let accept = self.packet(
+            Data::new()
+                .put(&message_code::SSH_MSG_SERVICE_ACCEPT)
+                .put(&"ssh-userauth".to_string()),
+        );
+        connection.feed(&accept).unwrap();
+        assert!(matches!(connection.poll_event(), Some(Event::AuthPrompt)));
+    }
+
+    // Accepts whatever the client authenticated with.
+    pub fn authenticate(&mut self, connection: &mut Connection) {
+        let success = self.packet(Data::new().put(&message_code::SSH_MSG_USERAUTH_SUCCESS));
+        connection.feed(&success).unwrap();
+        assert!(matches!(
+            connection.poll_event(),
+            Some(Event::Authenticated)
+        ));
+    }
+
+    // Answers the client's KEXINIT and key exchange init, which have to be
+    // sent already, with the reply and NEWKEYS. The client's NEWKEYS is read
+    // with the packets that follow it.
+    pub fn key_exchange(&mut self, connection: &mut Connection) -> Result<()> {
+        let client_public_key = self
+            .read(connection)
+            .into_iter()
+            .find_map(|mut payload| {
+                payload.expect(message_code::SSH2_MSG_KEX_ECDH_INIT).ok()?;
+                payload.get::<ByteString>().ok()
+            })
+            .expect("no SSH_MSG_KEX_ECDH_INIT")
+            .0;
+
+        let mut method = Curve25519Sha256::new();
+        let server_public_key = method.public_key();
+        let shared_secret = method.shared_secret(&client_public_key).unwrap();
+        let host_key = ByteString(self.host_key.public_key().to_bytes().unwrap());
+        let exchange_hash = Kex::exchange_hash(
+            &method,
+            &mut connection.session,
+            &host_key,
+            &client_public_key,
+            &server_public_key,
+            &shared_secret,
+        );
+        let signature: ssh_key::Signature = self.host_key.key_data().sign(&exchange_hash);
+        let signature: Vec<u8> = signature.try_into().unwrap();
+        let session_id = match &connection.session.keys {
+            Some(keys) => keys.session_id.clone(),
+            None => exchange_hash.clone(),
+        };
+        let kex = Kex::new(&method, exchange_hash, session_id, &shared_secret);
+        self.send_keys = Some(kex.clone());
+        self.recv_keys = Some(kex);
+
+        let mut stream = self.packet(
+            Data::new()
+                .put(&message_code::SSH2_MSG_KEX_ECDH_REPLY)
+                .put(&host_key)
+                .put(&ByteString(server_public_key))
+                .put(&ByteString(signature)),
+        );
+        stream.extend(self.packet(Data::new().put(&message_code::SSH_MSG_NEWKEYS)));
+        connection.feed(&stream)
+    }
+
+    // One packet to the client, compressed, encrypted and MACed as the server
+    // would.
+    pub fn packet(&mut self, payload: &Data) -> Vec<u8> {
+        let code = payload.0.first().copied().unwrap_or_default();
+        let payload = match !self.send.comp.is_delayed() || self.authenticated {
+            true => self.send.comp.compress(payload.0.clone()).unwrap(),
+            false => payload.0.clone(),
+        };
+        let packet_length = self.send.enc.packet_length(payload.len() as u32 + 1);
+        let padding_length = packet_length as usize - payload.len() - 1;
+        let mut data = Data::new();
+        data.put(&packet_length).put(&(padding_length as u8));
+        data.0.extend(&payload);
+        data.0.extend(vec![0; padding_length]);
+
+        let plaintext = data.0.clone();
+        self.send.enc.encrypt(&mut data, self.sequence_number);
+        let mac = calc_mac(
+            &self.send,
+            self.sequence_number,
+            match self.send.mac.is_etm() {
+                true => &data.0,
+                false => &plaintext,
+            },
+        );
+        data.0.extend(mac);
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+
+        match code {
+            message_code::SSH_MSG_NEWKEYS => {
+                let kex = self.send_keys.take().unwrap();
+                let algorithms = self.negotiated();
+                self.send = NewKeys::new(
+                    algorithms.server_encryption.new_adapter(
+                        &kex.server_encryption_key,
+                        &kex.server_initial_iv,
+                        algorithms.server_mac.is_some_and(|mac| mac.is_etm()),
+                    ),
+                    match algorithms.server_mac {
+                        Some(mac) => mac.new_adapter(&kex.server_integrity_key),
+                        None => Box::new(NoneMac {}),
+                    },
+                    algorithms.server_compress.new_adapter(),
+                );
+                if self.strict_kex {
+                    self.sequence_number = 0;
+                }
+            }
+            // delayed compression starts with the packets after this one
+            message_code::SSH_MSG_USERAUTH_SUCCESS => self.authenticated = true,
+            _ => (),
+        }
+        data.into_inner()
+    }
+
+    // The payloads of everything the client has transmitted so far.
+    pub fn read(&mut self, connection: &mut Connection) -> Vec<Data> {
+        let mut stream = Vec::new();
+        while let Some(bytes) = connection.poll_transmit() {
+            stream.extend(bytes);
+        }
+        self.receive(&stream)
+    }
+
+    // The payloads of the whole packets in `stream` and what was left of the
+    // ones before.
+    pub fn receive(&mut self, stream: &[u8]) -> Vec<Data> {
+        self.reader.feed(stream);
+        let mut payloads = Vec::new();
+        while let Some(packet) = self
+            .reader
+            .read_packet(&mut self.recv, self.client_sequence_number)
+            .unwrap()
+        {
+            let mut packet = Data(packet);
+            let packet_length: u32 = packet.get().unwrap();
+            let padding_length: u8 = packet.get().unwrap();
+            let payload = packet.0[..packet_length as usize - padding_length as usize - 1].to_vec();
+            let payload = match !self.recv.comp.is_delayed() || self.authenticated {
+                true => self.recv.comp.decompress(payload).unwrap(),
+                false => payload,
+            };
+            self.client_sequence_number = self.client_sequence_number.wrapping_add(1);
+
+            if payload.first() == Some(&message_code::SSH_MSG_KEXINIT) {
+                let client_kexinit: KexAlgorithms = Data(payload[1..].to_vec()).get().unwrap();
+                if self.client_kexinit.is_none() {
+                    self.strict_kex = self
+                        .kexinit
+                        .key_exchange
+                        .iter()
+                        .any(|name| name == KEX_STRICT_SERVER)
+                        && client_kexinit
+                            .key_exchange
+                            .iter()
+                            .any(|name| name == KEX_STRICT_CLIENT);
+                }
+                self.client_kexinit = Some(client_kexinit);
+            }
+            if payload.first() == Some(&message_code::SSH_MSG_NEWKEYS) {
+                let kex = self.recv_keys.take().unwrap();
+                let algorithms = self.negotiated();
+                self.recv = NewKeys::new(
+                    algorithms.client_encryption.new_adapter(
+                        &kex.client_encryption_key,
+                        &kex.client_initial_iv,
+                        algorithms.client_mac.is_some_and(|mac| mac.is_etm()),
+                    ),
+                    match algorithms.client_mac {
+                        Some(mac) => mac.new_adapter(&kex.client_integrity_key),
+                        None => Box::new(NoneMac {}),
+                    },
+                    algorithms.client_compress.new_adapter(),
+                );
+                if self.strict_kex {
+                    self.client_sequence_number = 0;
+                }
+            }
+            payloads.push(Data(payload));
+        }
+        payloads
+    }
+
+    // The message codes of everything the client has transmitted so far.
+    pub fn codes(&mut self, connection: &mut Connection) -> Vec<u8> {
+        self.read(connection)
+            .iter()
+            .map(|payload| payload.0[0])
+            .collect()
+    }
+
+    fn negotiated(&self) -> NegotiatedAlgorithms {
+        let client = self.client_kexinit.as_ref().unwrap();
+        client.negotiate(&self.kexinit).unwrap()
+    }
+}
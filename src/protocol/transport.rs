@@ -0,0 +1,246 @@
+use super::{
+    connection::{Connection, Event},
+    data::Data,
+    error::Result,
+    ssh2::message_code,
+};
+
+// Transport layer generic messages 1 to 4, which either side may send at any time.
+// [RFC4253 § 11](https://datatracker.ietf.org/doc/html/rfc4253#section-11)
+#[derive(Debug, PartialEq)]
+pub enum TransportMessage {
+    // uint32    reason code
+    // string    description in ISO-10646 UTF-8 encoding [RFC3629]
+    // string    language tag [RFC3066]
+    Disconnect {
+        reason_code: u32,
+        description: String,
+    },
+    // string    data
+    Ignore,
+    // uint32    packet sequence number of rejected message
+    Unimplemented {
+        sequence_number: u32,
+    },
+    // boolean   always_display
+    // string    message in ISO-10646 UTF-8 encoding [RFC3629]
+    // string    language tag [RFC3066]
+    Debug {
+        always_display: bool,
+        message: String,
+    },
+}
+
+impl TransportMessage {
+    // None when `payload` is not one of the transport layer generic messages.
+    pub fn parse(payload: &Data) -> Result<Option<TransportMessage>> {
+        let mut payload = payload.clone();
+        let message = match payload.get::<u8>()? {
+            message_code::SSH_MSG_DISCONNECT => TransportMessage::Disconnect {
+                reason_code: payload.get()?,
+                description: payload.get()?,
+            },
+            message_code::SSH_MSG_IGNORE => TransportMessage::Ignore,
+            message_code::SSH_MSG_UNIMPLEMENTED => TransportMessage::Unimplemented {
+                sequence_number: payload.get()?,
+            },
+            message_code::SSH_MSG_DEBUG => TransportMessage::Debug {
+                always_display: payload.get()?,
+                message: payload.get()?,
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(message))
+    }
+}
+
+// Message numbers this client knows; anything else is answered with SSH_MSG_UNIMPLEMENTED.
+fn is_implemented(code: u8) -> bool {
+    matches!(
+        code,
+        message_code::SSH_MSG_DISCONNECT..=message_code::SSH_MSG_EXT_INFO
+            | message_code::SSH_MSG_KEXINIT
+            | message_code::SSH_MSG_NEWKEYS
+            | message_code::SSH_MSG_KEXDH_INIT..=message_code::SSH2_MSG_KEX_DH_GEX_REQUEST
+            | message_code::SSH_MSG_USERAUTH_REQUEST..=message_code::SSH_MSG_USERAUTH_BANNER
+            | message_code::SSH2_MSG_USERAUTH_PK_OK..=message_code::SSH2_MSG_USERAUTH_INFO_RESPONSE
+            | message_code::SSH_MSG_GLOBAL_REQUEST..=message_code::SSH_MSG_REQUEST_FAILURE
+            | message_code::SSH_MSG_CHANNEL_OPEN..=message_code::SSH_MSG_CHANNEL_FAILURE
+    )
+}
+
+impl Connection {
+    // Handles the transport layer generic messages so that the application never
+    // sees them. Returns the payload when it is meant for the caller, None when it
+    // was consumed here. SSH_MSG_DISCONNECT closes the connection with
+    // `Event::Disconnected`.
+    pub fn dispatch_transport(&mut self, payload: Data) -> Result<Option<Data>> {
+        let code = payload.0.first().copied().unwrap_or_default();
+        if !is_implemented(code) {
+            // [RFC4253 § 11.4](https://datatracker.ietf.org/doc/html/rfc4253#section-11.4)
+            let sequence_number = self.session.server_sequence_number.wrapping_sub(1);
+            tracing::warn!(
+                "unimplemented message {} (packet {})",
+                code,
+                sequence_number
+            );
+            self.write_packet(
+                Data::new()
+                    .put(&message_code::SSH_MSG_UNIMPLEMENTED)
+                    .put(&sequence_number),
+            )?;
+            return Ok(None);
+        }
+
+        match TransportMessage::parse(&payload)? {
+            None => Ok(Some(payload)),
+            Some(TransportMessage::Disconnect {
+                reason_code,
+                description,
+            }) => {
+                self.push_event(Event::Disconnected {
+                    reason_code,
+                    description,
+                });
+                self.close();
+                Ok(None)
+            }
+            Some(TransportMessage::Ignore) => Ok(None),
+            Some(TransportMessage::Unimplemented { sequence_number }) => {
+                tracing::warn!("server did not implement packet {}", sequence_number);
+                Ok(None)
+            }
+            Some(TransportMessage::Debug {
+                always_display,
+                message,
+            }) => {
+                match always_display {
+                    true => tracing::info!("server debug message: {}", message),
+                    false => tracing::debug!("server debug message: {}", message),
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[test]
+fn parse_transport_messages() {
+    let mut payload = Data::new();
+    payload
+        .put(&message_code::SSH_MSG_DISCONNECT)
+        .put(&message_code::SSH_DISCONNECT_BY_APPLICATION)
+        .put(&"bye".to_string())
+        .put(&"".to_string());
+    assert_eq!(
+        TransportMessage::parse(&payload).unwrap(),
+        Some(TransportMessage::Disconnect {
+            reason_code: message_code::SSH_DISCONNECT_BY_APPLICATION,
+            description: "bye".to_string(),
+        })
+    );
+
+    let mut payload = Data::new();
+    payload
+        .put(&message_code::SSH_MSG_DEBUG)
+        .put(&true)
+        .put(&"hello".to_string())
+        .put(&"".to_string());
+    assert_eq!(
+        TransportMessage::parse(&payload).unwrap(),
+        Some(TransportMessage::Debug {
+            always_display: true,
+            message: "hello".to_string(),
+        })
+    );
+
+    let mut payload = Data::new();
+    payload.put(&message_code::SSH_MSG_UNIMPLEMENTED).put(&7u32);
+    assert_eq!(
+        TransportMessage::parse(&payload).unwrap(),
+        Some(TransportMessage::Unimplemented { sequence_number: 7 })
+    );
+
+    let mut payload = Data::new();
+    payload.put(&message_code::SSH_MSG_CHANNEL_DATA).put(&0u32);
+    assert_eq!(TransportMessage::parse(&payload).unwrap(), None);
+
+    // a truncated message is an error rather than a panic
+    let mut payload = Data::new();
+    payload.put(&message_code::SSH_MSG_DISCONNECT).put(&0u8);
+    assert!(TransportMessage::parse(&payload).is_err());
+
+    assert!(is_implemented(message_code::SSH_MSG_CHANNEL_DATA));
+    assert!(!is_implemented(8));
+    assert!(!is_implemented(192));
+}
+
+#[test]
+fn transport_messages_through_connection() {
+    use super::data::ByteString;
+    use super::test_server::TestServer;
+
+    let mut server = TestServer::new();
+    let mut connection = server.connect();
+
+    // an unknown message is answered with the sequence number of its packet
+    let sequence_number = server.sequence_number;
+    connection
+        .feed(&server.packet(Data::new().put(&192u8).put(&1u32)))
+        .unwrap();
+    assert!(connection.poll_event().is_none());
+    let mut unimplemented = server.read(&mut connection).pop().unwrap();
+    unimplemented
+        .expect(message_code::SSH_MSG_UNIMPLEMENTED)
+        .unwrap();
+    assert_eq!(unimplemented.get::<u32>().unwrap(), sequence_number);
+    assert_eq!(
+        sequence_number,
+        connection.session.server_sequence_number - 1
+    );
+
+    // IGNORE and DEBUG are swallowed
+    let mut stream = server.packet(
+        Data::new()
+            .put(&message_code::SSH_MSG_IGNORE)
+            .put(&ByteString(b"padding".to_vec())),
+    );
+    stream.extend(
+        server.packet(
+            Data::new()
+                .put(&message_code::SSH_MSG_DEBUG)
+                .put(&true)
+                .put(&"debug".to_string())
+                .put(&"".to_string()),
+        ),
+    );
+    connection.feed(&stream).unwrap();
+    assert!(connection.poll_event().is_none());
+    assert!(server.read(&mut connection).is_empty());
+
+    // DISCONNECT closes the connection, nothing after it is read
+    let mut stream = server.packet(
+        Data::new()
+            .put(&message_code::SSH_MSG_DISCONNECT)
+            .put(&message_code::SSH_DISCONNECT_BY_APPLICATION)
+            .put(&"bye".to_string())
+            .put(&"".to_string()),
+    );
+    stream.extend(
+        server.packet(
+            Data::new()
+                .put(&message_code::SSH_MSG_CHANNEL_DATA)
+                .put(&0u32)
+                .put(&ByteString(b"late".to_vec())),
+        ),
+    );
+    connection.feed(&stream).unwrap();
+    assert!(matches!(
+        connection.poll_event(),
+        Some(Event::Disconnected { reason_code, description })
+            if reason_code == message_code::SSH_DISCONNECT_BY_APPLICATION && description == "bye"
+    ));
+    assert!(connection.is_closed());
+    assert!(connection.poll_event().is_none());
+    assert!(server.read(&mut connection).is_empty());
+}
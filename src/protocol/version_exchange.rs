@@ -1,38 +1,121 @@
-use crate::protocol::data::Data;
-
-use super::client::SshClient;
+use super::connection::{Connection, Event};
 use super::data::DataType;
+use super::error::{Result, SshError};
+use super::packet_reader::PacketReader;
 use nom::bytes::complete::{tag, take_until};
 
-// SSH_protoversion_softwareversion SP comments CR LF
+// [RFC4253 § 4.2](https://datatracker.ietf.org/doc/html/rfc4253#section-4.2)
+// The identification string, CR LF included, is at most 255 characters.
+const MAX_VERSION_LENGTH: usize = 255;
+// The server may send other lines first; limits as in OpenSSH.
+const MAX_PRE_BANNER_LINES: usize = 1024;
+const MAX_PRE_BANNER_LINE_LENGTH: usize = 8192;
+
+// SSH-protoversion-softwareversion SP comments CR LF
 #[derive(Debug, Clone, PartialEq)]
 pub struct Version {
+    // the whole line without CR LF, as hashed in the key exchange
     pub version: String,
+    pub proto_version: String,
+    pub software_version: String,
+    pub comments: Option<String>,
     pub crnl: bool,
 }
 
 impl Version {
+    // Parses an identification string without its CR LF. Protocol 1.99 is
+    // accepted since such servers also speak 2.0.
+    pub fn parse(line: &str) -> Result<Version, SshError> {
+        let invalid = || SshError::InvalidFormat(format!("identification string {:?}", line));
+        if line.len() + 2 > MAX_VERSION_LENGTH {
+            return Err(invalid());
+        }
+        let identification = line.strip_prefix("SSH-").ok_or_else(invalid)?;
+        let (identification, comments) = match identification.split_once(' ') {
+            Some((identification, comments)) => (identification, Some(comments.to_string())),
+            None => (identification, None),
+        };
+        let (proto_version, software_version) =
+            identification.split_once('-').ok_or_else(invalid)?;
+        if !matches!(proto_version, "2.0" | "1.99") {
+            return Err(SshError::ProtocolMismatch(proto_version.to_string()));
+        }
+        // softwareversion is printable US-ASCII without spaces and minus signs,
+        // comments may contain spaces as well
+        if software_version.is_empty()
+            || !software_version
+                .bytes()
+                .all(|b| b.is_ascii_graphic() && b != b'-')
+        {
+            return Err(invalid());
+        }
+        if comments
+            .as_deref()
+            .is_some_and(|comments| !comments.bytes().all(|b| b == b' ' || b.is_ascii_graphic()))
+        {
+            return Err(invalid());
+        }
+
+        Ok(Version {
+            version: line.to_string(),
+            proto_version: proto_version.to_string(),
+            software_version: software_version.to_string(),
+            comments,
+            crnl: true,
+        })
+    }
+
     pub fn set_crnl(&mut self, crnl: bool) -> &Self {
         self.crnl = crnl;
         self
     }
 }
 
-impl SshClient {
-    pub fn version_exchange(&mut self) -> anyhow::Result<()> {
-        let mut payload = Vec::new();
-        self.config.version.encode(&mut payload);
-        self.client.send(&payload)?;
+// Takes lines from `reader` until the server's identification string. Lines sent
+// before it are collected in `banner`. Returns None when more bytes have to be read
+// first; anything after the identification string stays in the reader.
+pub fn read_version(
+    reader: &mut PacketReader,
+    banner: &mut Vec<String>,
+) -> Result<Option<Version>> {
+    while let Some(line) = reader.read_line(MAX_PRE_BANNER_LINE_LENGTH)? {
+        // lines end with CR LF, a bare LF is accepted as well
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches('\n').trim_end_matches('\r');
+        if line.starts_with("SSH-") {
+            return Ok(Some(Version::parse(line)?));
+        }
+        if banner.len() == MAX_PRE_BANNER_LINES {
+            return Err(SshError::InvalidFormat(
+                "too many lines before the identification".to_string(),
+            ));
+        }
+        banner.push(line.to_string());
+    }
+    Ok(None)
+}
 
-        let payload = self.client.recv()?;
-        let server_version: Version = Data(payload).get();
+impl Connection {
+    // Our identification string goes out first, with Connection::new. The server
+    // may send other lines before its own and its first packet along with it.
+    // Returns whether the server's identification string has arrived, after
+    // which the initial key exchange starts.
+    pub(crate) fn version_exchange(&mut self) -> Result<bool> {
+        let Some(server_version) = read_version(&mut self.reader, &mut self.session.server_banner)?
+        else {
+            return Ok(false);
+        };
+        if !self.session.server_banner.is_empty() {
+            let lines = self.session.server_banner.clone();
+            self.push_event(Event::ServerBanner(lines));
+        }
 
-        println!("client_version: {:?}", self.config.version);
-        println!("server_version: {:?}", server_version);
-        self.session.client_version = Some(self.config.version.clone());
+        tracing::debug!("client version: {:?}", self.session.client_version);
+        tracing::debug!("server version: {:?}", server_version);
         self.session.server_version = Some(server_version);
 
-        Ok(())
+        self.start_key_exchange()?;
+        Ok(true)
     }
 }
 
@@ -41,15 +124,12 @@ impl DataType for Version {
     where
         Self: Sized,
     {
-        let (input, version) = take_until("\r\n")(input)?;
-        let (input, _) = tag("\r\n")(input)?;
-        Ok((
-            input,
-            Version {
-                version: String::from_utf8(version.to_vec()).unwrap(),
-                crnl: true,
-            },
-        ))
+        let (rest, version) = take_until("\r\n")(input)?;
+        let (rest, _) = tag("\r\n")(rest)?;
+        let version = Version::parse(&String::from_utf8_lossy(version)).map_err(|_| {
+            nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Verify))
+        })?;
+        Ok((rest, version))
     }
 
     fn encode(&self, buf: &mut Vec<u8>) {
@@ -60,17 +140,117 @@ impl DataType for Version {
     }
 }
 
-// #[test]
-// fn parse_ssh_version() {
-//     let packet = b"SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.1\r\n";
-//     let version_from_packet = Version::unpack(packet).unwrap();
-//     let version = Version::client_version();
-//     assert_eq!(version, version_from_packet);
-//     println!("{:?}", version_from_packet);
-//     let packet = b"SSH-2.0-babeld-dc5ec9be\r\n";
-//     let version = Version::unpack(packet);
-//     println!("{:?}", version);
-// }
-// 00000000  53 53 48 2d 32 2e 30 2d  4f 70 65 6e 53 53 48 5f   SSH-2.0- OpenSSH_
-// 00000010  38 2e 39 70 31 20 55 62  75 6e 74 75 2d 33 75 62   8.9p1 Ub untu-3ub
-// 00000020  75 6e 74 75 30 2e 31 0d  0a                        untu0.1. .
+#[test]
+fn parse_ssh_version() {
+    let version = Version::parse("SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.1").unwrap();
+    assert_eq!(version.proto_version, "2.0");
+    assert_eq!(version.software_version, "OpenSSH_8.9p1");
+    assert_eq!(version.comments.as_deref(), Some("Ubuntu-3ubuntu0.1"));
+
+    let version = Version::parse("SSH-1.99-babeld_dc5ec9be").unwrap();
+    assert_eq!(version.software_version, "babeld_dc5ec9be");
+    assert_eq!(version.comments, None);
+
+    assert!(matches!(
+        Version::parse("SSH-1.5-OpenSSH_3.0"),
+        Err(SshError::ProtocolMismatch(_))
+    ));
+    assert!(Version::parse("SSH-2.0-").is_err());
+    for line in [
+        "SSH-2.0-babeld-dc5ec9be",
+        "SSH-2.0-OpenSSH\t9.6",
+        "SSH-2.0-OpenSSH_9.6\r\nSSH-2.0-other",
+        "SSH-2.0-OpenSSH_9.6 Debian\n",
+        "SSH-2.0-OpenSSH_9.6 \x1b[2J",
+        "SSH-2.0-OpenSSH_9.6 Débian",
+    ] {
+        assert!(
+            matches!(Version::parse(line), Err(SshError::InvalidFormat(_))),
+            "{:?}",
+            line
+        );
+    }
+    assert!(Version::parse(&format!("SSH-2.0-{}", "x".repeat(250))).is_err());
+}
+
+#[test]
+fn read_version_after_banner() {
+    let mut reader = PacketReader::new();
+    let mut banner = Vec::new();
+    let stream: &[u8] = b"Welcome\r\nnot ssh\n\r\nSSH-2.0-OpenSSH_9.6\r\n\x00\x00\x01\x0c\x0a\x14";
+
+    // split reads: nothing is returned before the whole line is there
+    let mut chunks = stream.chunks(7);
+    let version = loop {
+        reader.feed(chunks.next().unwrap());
+        if let Some(version) = read_version(&mut reader, &mut banner).unwrap() {
+            break version;
+        }
+    };
+    assert_eq!(version.version, "SSH-2.0-OpenSSH_9.6");
+    assert_eq!(banner, ["Welcome", "not ssh", ""]);
+
+    // coalesced: the start of the first binary packet stays buffered
+    let mut reader = PacketReader::new();
+    reader.feed(stream);
+    let version = read_version(&mut reader, &mut Vec::new()).unwrap().unwrap();
+    assert_eq!(version.software_version, "OpenSSH_9.6");
+    assert_eq!(
+        reader
+            .read_line(MAX_PRE_BANNER_LINE_LENGTH)
+            .unwrap()
+            .unwrap(),
+        b"\x00\x00\x01\x0c\x0a"
+    );
+
+    // an identification string that never ends is refused
+    let mut reader = PacketReader::new();
+    reader.feed(&[b'x'; MAX_PRE_BANNER_LINE_LENGTH]);
+    assert!(read_version(&mut reader, &mut Vec::new()).is_err());
+}
+
+#[test]
+fn banner_lines_become_an_event() {
+    let mut connection = super::client::SessionBuilder::create_session()
+        .connection()
+        .unwrap();
+    connection
+        .feed(b"Welcome\r\nto the server\r\nSSH-2.0-OpenSSH_9.6\r\n")
+        .unwrap();
+    assert!(matches!(
+        connection.poll_event(),
+        Some(Event::ServerBanner(lines)) if lines == ["Welcome", "to the server"]
+    ));
+    assert_eq!(
+        connection.session.server_banner,
+        ["Welcome", "to the server"]
+    );
+    assert!(connection.poll_event().is_none());
+}
+
+#[test]
+fn identification_is_checked() {
+    use super::client::SessionBuilder;
+
+    let connection = SessionBuilder::create_session()
+        .identification("sseichi_1.0 with comments")
+        .connection()
+        .unwrap();
+    assert_eq!(
+        connection
+            .session
+            .client_version
+            .unwrap()
+            .comments
+            .as_deref(),
+        Some("with comments")
+    );
+    for identification in ["sseichi\r\nSSH-2.0-other", "sse-ichi", "sseichi \x00"] {
+        assert!(matches!(
+            SessionBuilder::create_session()
+                .identification(identification)
+                .connection(),
+            Err(SshError::InvalidFormat(_))
+        ));
+    }
+}
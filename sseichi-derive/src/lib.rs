@@ -0,0 +1,169 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, Path};
+
+// #[derive(DataType)] for structs with named fields, implementing
+// sseichi's protocol::data::DataType. Fields go on the wire in declaration
+// order, each with its own DataType impl.
+//
+//   #[data(crate = path::to::sseichi)]
+//     on the struct: where sseichi is found, ::sseichi unless given.
+//
+//   #[data(message = message_code::SSH_MSG_KEXINIT)]
+//     on the struct: a leading byte with the message number. decode fails
+//     when the payload starts with any other number.
+//
+//   #[data(when = want_reply)]
+//     on an Option<T> field: the field is only on the wire when the
+//     expression holds. The expression sees the fields before it by value.
+//     encode writes the field when it is Some, so Some must go with the
+//     expression being true for a value to survive a round trip.
+#[proc_macro_derive(DataType, attributes(data))]
+pub fn derive_data_type(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct Field {
+    name: syn::Ident,
+    ty: syn::Type,
+    when: Option<Expr>,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut message: Option<Path> = None;
+    let mut krate: Path = syn::parse_quote!(::sseichi);
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("data"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("message") {
+                message = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("crate") {
+                krate = meta.value()?.parse()?;
+                Ok(())
+            } else {
+                Err(meta
+                    .error("expected `message = <message number>` or `crate = <path to sseichi>`"))
+            }
+        })?;
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "DataType can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "DataType can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut wire_fields = Vec::new();
+    for field in fields {
+        let mut when = None;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("data"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("when") {
+                    when = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `when = <condition>`"))
+                }
+            })?;
+        }
+        wire_fields.push(Field {
+            name: field.ident.clone().unwrap(),
+            ty: field.ty.clone(),
+            when,
+        });
+    }
+
+    let data_type = quote!(#krate::protocol::data::DataType);
+    let nom = quote!(#krate::protocol::data::nom);
+
+    let encode_message = message.iter().map(|message| {
+        quote! {
+            let message: u8 = #message;
+            #data_type::encode(&message, buf);
+        }
+    });
+    let decode_message = message.iter().map(|message| {
+        quote! {
+            let (input, message) = <u8 as #data_type>::decode(input)?;
+            if message != #message {
+                return Err(#nom::Err::Error(#nom::error::ParseError::from_error_kind(
+                    input,
+                    #nom::error::ErrorKind::Tag,
+                )));
+            }
+        }
+    });
+
+    let encode_fields = wire_fields.iter().map(|field| {
+        let name = &field.name;
+        match &field.when {
+            None => quote! {
+                #data_type::encode(&self.#name, buf);
+            },
+            Some(_) => quote! {
+                if let Some(value) = &self.#name {
+                    #data_type::encode(value, buf);
+                }
+            },
+        }
+    });
+    let decode_fields = wire_fields.iter().map(|field| {
+        let (name, ty) = (&field.name, &field.ty);
+        match &field.when {
+            None => quote! {
+                let (input, #name) = <#ty as #data_type>::decode(input)?;
+            },
+            Some(when) => quote! {
+                let (input, #name) = if #when {
+                    let (input, value) = #data_type::decode(input)?;
+                    (input, Some(value))
+                } else {
+                    (input, None)
+                };
+            },
+        }
+    });
+    let names = wire_fields.iter().map(|field| &field.name);
+
+    Ok(quote! {
+        impl #impl_generics #data_type for #name #ty_generics #where_clause {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                #(#encode_message)*
+                #(#encode_fields)*
+            }
+
+            fn decode(input: &[u8]) -> #nom::IResult<&[u8], Self> {
+                #(#decode_message)*
+                #(#decode_fields)*
+                Ok((input, Self { #(#names),* }))
+            }
+        }
+    })
+}